@@ -0,0 +1,26 @@
+-- Add up migration script here
+-- Applies a transaction in a single round trip: updates the wallet balance
+-- (the wallet CHECK constraint enforces the limit) and records the transaction.
+-- Returns NULLs when the wallet does not exist.
+CREATE FUNCTION add_transaction(
+    p_wallet_id INTEGER,
+    p_amount INTEGER,
+    p_type transaction_type,
+    p_description VARCHAR(10),
+    OUT new_total INTEGER,
+    OUT new_limit INTEGER
+) AS $$
+BEGIN
+    UPDATE wallet
+    SET total = total + CASE WHEN p_type = 'c' THEN p_amount ELSE - p_amount END
+    WHERE id = p_wallet_id
+    RETURNING total, "limit" INTO new_total, new_limit;
+
+    IF NOT FOUND THEN
+        RETURN;
+    END IF;
+
+    INSERT INTO transaction (wallet_id, amount, "type", description)
+    VALUES (p_wallet_id, p_amount, p_type, p_description);
+END;
+$$ LANGUAGE plpgsql;
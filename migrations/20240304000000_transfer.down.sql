@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP FUNCTION transfer;
+
+ALTER TABLE transaction DROP COLUMN transfer_id;
+
+DROP TABLE transfer;
@@ -0,0 +1,60 @@
+-- Add up migration script here
+CREATE TABLE transfer (
+    id SERIAL PRIMARY KEY,
+    from_wallet_id INTEGER NOT NULL,
+    to_wallet_id INTEGER NOT NULL,
+    amount INTEGER NOT NULL,
+    "description" VARCHAR(10) NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    FOREIGN KEY (from_wallet_id) REFERENCES wallet(id),
+    FOREIGN KEY (to_wallet_id) REFERENCES wallet(id)
+);
+
+-- debit and credit rows created by a transfer point to it
+ALTER TABLE transaction ADD COLUMN transfer_id INTEGER REFERENCES transfer(id);
+
+-- Moves amount between two wallets in a single transaction. Both wallets are
+-- locked in id order so concurrent transfers in opposite directions cannot
+-- deadlock. Returns the payer balance, or the id of a wallet that does not exist.
+CREATE FUNCTION transfer(
+    p_from_wallet_id INTEGER,
+    p_to_wallet_id INTEGER,
+    p_amount INTEGER,
+    p_description VARCHAR(10),
+    OUT new_total INTEGER,
+    OUT new_limit INTEGER,
+    OUT missing_wallet_id INTEGER
+) AS $$
+DECLARE
+    v_transfer_id INTEGER;
+BEGIN
+    PERFORM id FROM wallet
+    WHERE id IN (p_from_wallet_id, p_to_wallet_id)
+    ORDER BY id
+    FOR UPDATE;
+
+    IF NOT EXISTS (SELECT 1 FROM wallet WHERE id = p_from_wallet_id) THEN
+        missing_wallet_id := p_from_wallet_id;
+        RETURN;
+    END IF;
+    IF NOT EXISTS (SELECT 1 FROM wallet WHERE id = p_to_wallet_id) THEN
+        missing_wallet_id := p_to_wallet_id;
+        RETURN;
+    END IF;
+
+    UPDATE wallet SET total = total - p_amount
+    WHERE id = p_from_wallet_id
+    RETURNING total, "limit" INTO new_total, new_limit;
+
+    UPDATE wallet SET total = total + p_amount WHERE id = p_to_wallet_id;
+
+    INSERT INTO transfer (from_wallet_id, to_wallet_id, amount, description)
+    VALUES (p_from_wallet_id, p_to_wallet_id, p_amount, p_description)
+    RETURNING id INTO v_transfer_id;
+
+    INSERT INTO transaction (wallet_id, amount, "type", description, transfer_id)
+    VALUES
+        (p_from_wallet_id, p_amount, 'd', p_description, v_transfer_id),
+        (p_to_wallet_id, p_amount, 'c', p_description, v_transfer_id);
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP TRIGGER wallet_closed ON wallet;
+
+DROP FUNCTION reject_closed_wallet;
+
+ALTER TABLE wallet DROP COLUMN closed_at;
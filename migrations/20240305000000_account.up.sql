@@ -0,0 +1,17 @@
+-- Add up migration script here
+ALTER TABLE wallet ADD COLUMN closed_at TIMESTAMP;
+
+-- Closed wallets keep their history but their balance and limit are frozen,
+-- which covers add_transaction, transfer and limit changes at once.
+-- RB001 is reported to the client as a closed account.
+CREATE FUNCTION reject_closed_wallet() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'wallet % is closed', OLD.id USING ERRCODE = 'RB001';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER wallet_closed
+BEFORE UPDATE OF total, "limit" ON wallet
+FOR EACH ROW
+WHEN (OLD.closed_at IS NOT NULL)
+EXECUTE FUNCTION reject_closed_wallet();
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP FUNCTION reverse_transaction;
+
+ALTER TABLE transaction DROP COLUMN reversal_of;
@@ -0,0 +1,49 @@
+-- Add up migration script here
+-- a transaction can only be reversed once
+ALTER TABLE transaction ADD COLUMN reversal_of INTEGER UNIQUE REFERENCES transaction(id);
+
+-- Reverses a transaction in a single round trip, recording an entry of the
+-- opposite type that references it. The wallet CHECK constraint rejects a
+-- reversal that would exceed the limit and the UNIQUE constraint a second one.
+-- RB002 is raised when the wallet does not exist, RB003 when the transaction
+-- does not and RB004 when it is a reversal or a transfer leg.
+CREATE FUNCTION reverse_transaction(
+    p_wallet_id INTEGER,
+    p_transaction_id INTEGER,
+    OUT new_total INTEGER,
+    OUT new_limit INTEGER,
+    OUT reversal_id INTEGER,
+    OUT reversal_type transaction_type,
+    OUT reversal_amount INTEGER,
+    OUT reversal_description VARCHAR(10),
+    OUT reversal_created_at TIMESTAMP
+) AS $$
+DECLARE
+    v_original transaction%ROWTYPE;
+BEGIN
+    PERFORM id FROM wallet WHERE id = p_wallet_id;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'wallet % not found', p_wallet_id USING ERRCODE = 'RB002';
+    END IF;
+
+    SELECT * INTO v_original FROM transaction
+    WHERE id = p_transaction_id AND wallet_id = p_wallet_id;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'transaction % not found', p_transaction_id USING ERRCODE = 'RB003';
+    END IF;
+    IF v_original.reversal_of IS NOT NULL OR v_original.transfer_id IS NOT NULL THEN
+        RAISE EXCEPTION 'transaction % is not reversible', p_transaction_id USING ERRCODE = 'RB004';
+    END IF;
+
+    reversal_type := CASE WHEN v_original."type" = 'c' THEN 'd' ELSE 'c' END;
+    UPDATE wallet
+    SET total = total + CASE WHEN reversal_type = 'c' THEN v_original.amount ELSE - v_original.amount END
+    WHERE id = p_wallet_id
+    RETURNING total, "limit" INTO new_total, new_limit;
+
+    INSERT INTO transaction (wallet_id, amount, "type", description, reversal_of)
+    VALUES (p_wallet_id, v_original.amount, reversal_type, v_original.description, p_transaction_id)
+    RETURNING id, amount, description, created_at
+    INTO reversal_id, reversal_amount, reversal_description, reversal_created_at;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,32 @@
+-- Add up migration script here
+-- add_transaction also returns the recorded transaction, so it can be referenced
+DROP FUNCTION add_transaction;
+
+CREATE FUNCTION add_transaction(
+    p_wallet_id INTEGER,
+    p_amount INTEGER,
+    p_type transaction_type,
+    p_description VARCHAR(10),
+    OUT new_total INTEGER,
+    OUT new_limit INTEGER,
+    OUT transaction_id INTEGER,
+    OUT transaction_created_at TIMESTAMP
+) AS $$
+BEGIN
+    UPDATE wallet
+    SET total = total + CASE WHEN p_type = 'c' THEN p_amount ELSE - p_amount END
+    WHERE id = p_wallet_id
+    RETURNING total, "limit" INTO new_total, new_limit;
+
+    IF NOT FOUND THEN
+        RETURN;
+    END IF;
+
+    INSERT INTO transaction (wallet_id, amount, "type", description)
+    VALUES (p_wallet_id, p_amount, p_type, p_description)
+    RETURNING id, created_at INTO transaction_id, transaction_created_at;
+END;
+$$ LANGUAGE plpgsql;
+
+-- a repeated request returns the transaction created by the first one
+ALTER TABLE idempotency_key ADD COLUMN transaction_id INTEGER REFERENCES transaction(id);
@@ -0,0 +1,11 @@
+-- Add down migration script here
+DROP FUNCTION release_hold;
+DROP FUNCTION capture_hold;
+DROP FUNCTION place_hold;
+
+DROP TABLE hold;
+
+ALTER TABLE wallet
+    DROP CONSTRAINT wallet_check,
+    ADD CONSTRAINT wallet_check CHECK (- total <= "limit");
+ALTER TABLE wallet DROP COLUMN held;
@@ -0,0 +1,129 @@
+-- Add up migration script here
+-- part of the limit reserved by pending holds, debits can only use the rest
+ALTER TABLE wallet ADD COLUMN held INTEGER DEFAULT 0 NOT NULL;
+ALTER TABLE wallet
+    DROP CONSTRAINT wallet_check,
+    ADD CONSTRAINT wallet_check CHECK (held - total <= "limit");
+
+-- pending holds, deleted once captured, released or expired
+CREATE TABLE hold (
+    id SERIAL PRIMARY KEY,
+    wallet_id INTEGER NOT NULL,
+    amount INTEGER NOT NULL,
+    "description" VARCHAR(10) NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    FOREIGN KEY (wallet_id) REFERENCES wallet(id)
+);
+
+CREATE INDEX hold_expires_at_index ON hold(expires_at);
+
+-- Reserves part of the limit, the wallet CHECK constraint rejecting a hold that
+-- does not fit. RB001 is raised when the wallet is closed and RB002 when it does
+-- not exist.
+CREATE FUNCTION place_hold(
+    p_wallet_id INTEGER,
+    p_amount INTEGER,
+    p_description VARCHAR(10),
+    p_created_at TIMESTAMP,
+    p_expires_at TIMESTAMP,
+    OUT new_total INTEGER,
+    OUT new_limit INTEGER,
+    OUT new_held INTEGER,
+    OUT hold_id INTEGER
+) AS $$
+BEGIN
+    UPDATE wallet SET held = held + p_amount
+    WHERE id = p_wallet_id AND closed_at IS NULL
+    RETURNING total, "limit", held INTO new_total, new_limit, new_held;
+
+    IF NOT FOUND THEN
+        PERFORM id FROM wallet WHERE id = p_wallet_id;
+        IF FOUND THEN
+            RAISE EXCEPTION 'wallet % is closed', p_wallet_id USING ERRCODE = 'RB001';
+        END IF;
+        RAISE EXCEPTION 'wallet % not found', p_wallet_id USING ERRCODE = 'RB002';
+    END IF;
+
+    INSERT INTO hold (wallet_id, amount, "description", created_at, expires_at)
+    VALUES (p_wallet_id, p_amount, p_description, p_created_at, p_expires_at)
+    RETURNING id INTO hold_id;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Posts a debit of p_amount, the whole hold when NULL, and releases the hold.
+-- RB005 is raised when the hold does not exist or expired and RB006 when the
+-- amount is above the held one.
+CREATE FUNCTION capture_hold(
+    p_wallet_id INTEGER,
+    p_hold_id INTEGER,
+    p_amount INTEGER,
+    OUT new_total INTEGER,
+    OUT new_limit INTEGER,
+    OUT new_held INTEGER,
+    OUT transaction_id INTEGER,
+    OUT transaction_amount INTEGER,
+    OUT transaction_description VARCHAR(10),
+    OUT transaction_created_at TIMESTAMP
+) AS $$
+DECLARE
+    v_hold hold%ROWTYPE;
+BEGIN
+    PERFORM id FROM wallet WHERE id = p_wallet_id;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'wallet % not found', p_wallet_id USING ERRCODE = 'RB002';
+    END IF;
+
+    DELETE FROM hold
+    WHERE id = p_hold_id AND wallet_id = p_wallet_id AND expires_at > now() AT TIME ZONE 'UTC'
+    RETURNING * INTO v_hold;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'hold % not found', p_hold_id USING ERRCODE = 'RB005';
+    END IF;
+    transaction_amount := COALESCE(p_amount, v_hold.amount);
+    IF transaction_amount > v_hold.amount THEN
+        RAISE EXCEPTION 'capture exceeds hold %', p_hold_id USING ERRCODE = 'RB006';
+    END IF;
+
+    UPDATE wallet
+    SET total = total - transaction_amount, held = held - v_hold.amount
+    WHERE id = p_wallet_id
+    RETURNING total, "limit", held INTO new_total, new_limit, new_held;
+
+    INSERT INTO transaction (wallet_id, amount, "type", description)
+    VALUES (p_wallet_id, transaction_amount, 'd', v_hold.description)
+    RETURNING id, description, created_at
+    INTO transaction_id, transaction_description, transaction_created_at;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Releases a hold without posting anything, with the same errors as capture_hold.
+CREATE FUNCTION release_hold(
+    p_wallet_id INTEGER,
+    p_hold_id INTEGER,
+    OUT new_total INTEGER,
+    OUT new_limit INTEGER,
+    OUT new_held INTEGER,
+    OUT hold_amount INTEGER,
+    OUT hold_description VARCHAR(10),
+    OUT hold_created_at TIMESTAMP,
+    OUT hold_expires_at TIMESTAMP
+) AS $$
+BEGIN
+    PERFORM id FROM wallet WHERE id = p_wallet_id;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'wallet % not found', p_wallet_id USING ERRCODE = 'RB002';
+    END IF;
+
+    DELETE FROM hold WHERE id = p_hold_id AND wallet_id = p_wallet_id
+    RETURNING amount, description, created_at, expires_at
+    INTO hold_amount, hold_description, hold_created_at, hold_expires_at;
+    IF NOT FOUND THEN
+        RAISE EXCEPTION 'hold % not found', p_hold_id USING ERRCODE = 'RB005';
+    END IF;
+
+    UPDATE wallet SET held = held - hold_amount
+    WHERE id = p_wallet_id
+    RETURNING total, "limit", held INTO new_total, new_limit, new_held;
+END;
+$$ LANGUAGE plpgsql;
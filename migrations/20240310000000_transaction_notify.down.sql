@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TRIGGER transaction_notify ON transaction;
+
+DROP FUNCTION notify_transaction;
@@ -0,0 +1,18 @@
+-- Add up migration script here
+-- Every new transaction is announced on the account_activity channel along with
+-- the balance it left, so each app instance can stream it to its subscribers.
+-- Notifications are only delivered once the transaction commits.
+CREATE FUNCTION notify_transaction() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('account_activity', json_build_object(
+        'transaction', row_to_json(NEW),
+        'wallet', (SELECT row_to_json(wallet) FROM wallet WHERE id = NEW.wallet_id)
+    )::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER transaction_notify
+AFTER INSERT ON transaction
+FOR EACH ROW
+EXECUTE FUNCTION notify_transaction();
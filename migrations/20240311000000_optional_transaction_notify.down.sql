@@ -0,0 +1,10 @@
+-- Add down migration script here
+CREATE OR REPLACE FUNCTION notify_transaction() RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM pg_notify('account_activity', json_build_object(
+        'transaction', row_to_json(NEW),
+        'wallet', (SELECT row_to_json(wallet) FROM wallet WHERE id = NEW.wallet_id)
+    )::text);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
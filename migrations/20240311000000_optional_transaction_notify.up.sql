@@ -0,0 +1,15 @@
+-- Add up migration script here
+-- pg_notify takes a database-wide lock when the transaction commits, serializing
+-- every write. Only connections opened with rinha.account_events = on announce
+-- their transactions, which the instances do when account events are enabled.
+CREATE OR REPLACE FUNCTION notify_transaction() RETURNS TRIGGER AS $$
+BEGIN
+    IF current_setting('rinha.account_events', true) = 'on' THEN
+        PERFORM pg_notify('account_activity', json_build_object(
+            'transaction', row_to_json(NEW),
+            'wallet', (SELECT row_to_json(wallet) FROM wallet WHERE id = NEW.wallet_id)
+        )::text);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
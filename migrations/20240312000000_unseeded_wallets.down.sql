@@ -0,0 +1,5 @@
+-- Add down migration script here
+INSERT INTO wallet (id, "limit")
+VALUES (1, 100000), (2, 80000), (3, 1000000), (4, 10000000), (5, 500000)
+ON CONFLICT (id) DO NOTHING;
+SELECT setval(pg_get_serial_sequence('wallet', 'id'), MAX(id)) FROM wallet;
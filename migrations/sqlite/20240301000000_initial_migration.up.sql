@@ -0,0 +1,25 @@
+-- Add up migration script here
+CREATE TABLE wallet (
+    id INTEGER PRIMARY KEY,
+    "limit" INTEGER NOT NULL,
+    total INTEGER DEFAULT 0 NOT NULL,
+    CHECK (- total <= "limit")
+);
+
+CREATE TABLE "transaction" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    wallet_id INTEGER NOT NULL,
+    amount INTEGER NOT NULL,
+    "type" TEXT NOT NULL CHECK ("type" IN ('c', 'd')),
+    "description" VARCHAR(10) NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    FOREIGN KEY (wallet_id) REFERENCES wallet(id)
+);
+
+CREATE INDEX wallet_id_created_at_index ON "transaction"(wallet_id, created_at);
+
+INSERT INTO wallet ("limit", total) VALUES (100000, 0);
+INSERT INTO wallet ("limit", total) VALUES (80000, 0);
+INSERT INTO wallet ("limit", total) VALUES (1000000, 0);
+INSERT INTO wallet ("limit", total) VALUES (10000000, 0);
+INSERT INTO wallet ("limit", total) VALUES (500000, 0);
@@ -0,0 +1,10 @@
+-- Add up migration script here
+CREATE TABLE idempotency_key (
+    "key" VARCHAR(255) PRIMARY KEY,
+    wallet_id INTEGER NOT NULL,
+    fingerprint TEXT NOT NULL,
+    total INTEGER NOT NULL,
+    "limit" INTEGER NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    FOREIGN KEY (wallet_id) REFERENCES wallet(id)
+);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE "transaction" DROP COLUMN transfer_id;
+
+DROP TABLE transfer;
@@ -0,0 +1,14 @@
+-- Add up migration script here
+CREATE TABLE transfer (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    from_wallet_id INTEGER NOT NULL,
+    to_wallet_id INTEGER NOT NULL,
+    amount INTEGER NOT NULL,
+    "description" VARCHAR(10) NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    FOREIGN KEY (from_wallet_id) REFERENCES wallet(id),
+    FOREIGN KEY (to_wallet_id) REFERENCES wallet(id)
+);
+
+-- debit and credit rows created by a transfer point to it
+ALTER TABLE "transaction" ADD COLUMN transfer_id INTEGER REFERENCES transfer(id);
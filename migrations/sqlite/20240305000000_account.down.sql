@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TRIGGER wallet_closed;
+
+ALTER TABLE wallet DROP COLUMN closed_at;
@@ -0,0 +1,10 @@
+-- Add up migration script here
+ALTER TABLE wallet ADD COLUMN closed_at DATETIME;
+
+-- Closed wallets keep their history but their balance and limit are frozen
+CREATE TRIGGER wallet_closed
+BEFORE UPDATE OF total, "limit" ON wallet
+WHEN OLD.closed_at IS NOT NULL
+BEGIN
+    SELECT RAISE(ABORT, 'wallet is closed');
+END;
@@ -0,0 +1,2 @@
+-- Add down migration script here
+DROP INDEX transaction_wallet_id_created_at_id_index;
@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- keyset pagination of the wallet history walks (created_at, id) backwards
+CREATE INDEX transaction_wallet_id_created_at_id_index
+ON "transaction"(wallet_id, created_at DESC, id DESC);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX transaction_reversal_of_index;
+
+ALTER TABLE "transaction" DROP COLUMN reversal_of;
@@ -0,0 +1,5 @@
+-- Add up migration script here
+ALTER TABLE "transaction" ADD COLUMN reversal_of INTEGER REFERENCES "transaction"(id);
+
+-- a transaction can only be reversed once
+CREATE UNIQUE INDEX transaction_reversal_of_index ON "transaction"(reversal_of);
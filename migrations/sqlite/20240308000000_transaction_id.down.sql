@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE idempotency_key DROP COLUMN transaction_id;
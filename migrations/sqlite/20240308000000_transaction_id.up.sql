@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- a repeated request returns the transaction created by the first one
+ALTER TABLE idempotency_key ADD COLUMN transaction_id INTEGER REFERENCES "transaction"(id);
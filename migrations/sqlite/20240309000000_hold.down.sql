@@ -0,0 +1,8 @@
+-- Add down migration script here
+DROP INDEX hold_expires_at_index;
+
+DROP TABLE hold;
+
+DROP TRIGGER wallet_held_limit;
+
+ALTER TABLE wallet DROP COLUMN held;
@@ -0,0 +1,24 @@
+-- Add up migration script here
+-- part of the limit reserved by pending holds, debits can only use the rest
+ALTER TABLE wallet ADD COLUMN held INTEGER DEFAULT 0 NOT NULL;
+
+-- the limit CHECK constraint cannot be altered, so the held part is checked here
+CREATE TRIGGER wallet_held_limit
+BEFORE UPDATE OF total, held, "limit" ON wallet
+WHEN NEW.held - NEW.total > NEW."limit"
+BEGIN
+    SELECT RAISE(ABORT, 'wallet limit exceeded');
+END;
+
+-- pending holds, deleted once captured, released or expired
+CREATE TABLE hold (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    wallet_id INTEGER NOT NULL,
+    amount INTEGER NOT NULL,
+    "description" VARCHAR(10) NOT NULL,
+    created_at DATETIME NOT NULL,
+    expires_at DATETIME NOT NULL,
+    FOREIGN KEY (wallet_id) REFERENCES wallet(id)
+);
+
+CREATE INDEX hold_expires_at_index ON hold(expires_at);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+INSERT OR IGNORE INTO wallet (id, "limit")
+VALUES (1, 100000), (2, 80000), (3, 1000000), (4, 10000000), (5, 500000);
@@ -0,0 +1,13 @@
+-- Add up migration script here
+-- The initial migration opened the five wallets of the challenge, accounts are now
+-- opened by `server seed`. The ones nothing happened to yet are removed, seeding
+-- them again opens them as they were.
+DELETE FROM wallet
+WHERE (id, "limit") IN ((1, 100000), (2, 80000), (3, 1000000), (4, 10000000), (5, 500000))
+    AND total = 0
+    AND held = 0
+    AND closed_at IS NULL
+    AND NOT EXISTS (SELECT 1 FROM "transaction" WHERE wallet_id = wallet.id)
+    AND NOT EXISTS (SELECT 1 FROM idempotency_key WHERE wallet_id = wallet.id)
+    AND NOT EXISTS (SELECT 1 FROM transfer WHERE wallet.id IN (from_wallet_id, to_wallet_id))
+    AND NOT EXISTS (SELECT 1 FROM hold WHERE wallet_id = wallet.id);
@@ -0,0 +1,2 @@
+-- Add down migration script here
+DROP TRIGGER wallet_closed_hold;
@@ -0,0 +1,8 @@
+-- Add up migration script here
+-- Closed wallets cannot reserve their limit either, releasing what was held is allowed
+CREATE TRIGGER wallet_closed_hold
+BEFORE UPDATE OF held ON wallet
+WHEN OLD.closed_at IS NOT NULL AND NEW.held > OLD.held
+BEGIN
+    SELECT RAISE(ABORT, 'wallet is closed');
+END;
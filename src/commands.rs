@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::Path;
+
+use redis::Client;
+use serde::Deserialize;
+use sqlx::migrate::{Migrate, MigrateError, Migrator};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions, Postgres};
+use sqlx::sqlite::{Sqlite, SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::Pool;
+use validator::Validate;
+
+use crate::config::{Config, DatabaseConfig, PoolConfig};
+use crate::database::{
+    Backend, CachedDatabase, MemoryDatabase, MongoDatabase, PostgresDatabase, RedisDatabase,
+    SqliteDatabase, TransactionRepository,
+};
+use crate::{metrics, Database};
+
+static POSTGRES_MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+pub(crate) static SQLITE_MIGRATOR: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+async fn postgres_pool(
+    url: &str,
+    pool: &PoolConfig,
+    account_events: bool,
+) -> Result<Pool<Postgres>, sqlx::Error> {
+    let mut options = url.parse::<PgConnectOptions>()?;
+    if account_events {
+        // the transaction_notify trigger skips pg_notify without it
+        options = options.options([("rinha.account_events", "on")]);
+    }
+    PgPoolOptions::new()
+        .max_connections(pool.max_connections)
+        .acquire_timeout(pool.acquire_timeout)
+        .idle_timeout(pool.idle_timeout)
+        .connect_with(options)
+        .await
+}
+
+async fn sqlite_pool(url: &str, pool: &PoolConfig) -> Result<Pool<Sqlite>, sqlx::Error> {
+    let options = url.parse::<SqliteConnectOptions>()?.create_if_missing(true);
+    SqlitePoolOptions::new()
+        .max_connections(pool.max_connections)
+        .acquire_timeout(pool.acquire_timeout)
+        .idle_timeout(pool.idle_timeout)
+        .connect_with(options)
+        .await
+}
+
+/// Opens the configured database, behind the statement cache when there is one.
+pub async fn connect(config: &Config) -> Result<Database, Box<dyn Error>> {
+    let backend = match &config.database {
+        DatabaseConfig::Postgres { url } => {
+            let pool = postgres_pool(url, &config.pool, config.account_events).await?;
+            metrics::register_pool(pool.clone())?;
+            Backend::Postgres(PostgresDatabase::new(
+                pool,
+                config.statement_size,
+                config.account_events,
+            ))
+        }
+        DatabaseConfig::Sqlite { url } => {
+            let pool = sqlite_pool(url, &config.pool).await?;
+            metrics::register_pool(pool.clone())?;
+            Backend::Sqlite(SqliteDatabase::new(pool, config.statement_size))
+        }
+        DatabaseConfig::Mongo { url, schema } => {
+            let client = mongodb::Client::with_uri_str(url).await?;
+            let mongo = MongoDatabase::new(
+                client,
+                *schema,
+                config.statement_size,
+                config.account_events,
+            )
+            .await?;
+            Backend::Mongo(mongo)
+        }
+        DatabaseConfig::Memory => Backend::Memory(MemoryDatabase::new(config.statement_size)),
+        DatabaseConfig::Redis { url } => {
+            let client = Client::open(url.as_str())?;
+            Backend::Redis(Box::new(RedisDatabase::new(
+                client,
+                config.statement_size,
+                config.account_events,
+            )))
+        }
+    };
+
+    Ok(match &config.cache_url {
+        Some(cache_url) => {
+            let redis = Client::open(cache_url.as_str())?;
+            Database::Cached(CachedDatabase::new(
+                redis,
+                backend,
+                config.statement_size,
+                config.statement_cache_ttl_seconds,
+            ))
+        }
+        None => Database::Direct(backend),
+    })
+}
+
+/// Applies the pending migrations, the databases without a schema have none.
+pub async fn migrate(config: &Config) -> Result<(), Box<dyn Error>> {
+    match &config.database {
+        DatabaseConfig::Postgres { url } => {
+            let pool = postgres_pool(url, &config.pool, false).await?;
+            POSTGRES_MIGRATOR.run(&pool).await?;
+            pool.close().await;
+        }
+        DatabaseConfig::Sqlite { url } => {
+            let pool = sqlite_pool(url, &config.pool).await?;
+            SQLITE_MIGRATOR.run(&pool).await?;
+            pool.close().await;
+        }
+        DatabaseConfig::Mongo { url, schema } => {
+            let client = mongodb::Client::with_uri_str(url).await?;
+            MongoDatabase::migrate(&client, *schema, config.statement_size).await?;
+            client.shutdown().await;
+        }
+        DatabaseConfig::Memory | DatabaseConfig::Redis { .. } => {}
+    }
+    tracing::info!(
+        database_type = %config.database.database_type(),
+        "Database migrated"
+    );
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Validate)]
+#[serde(deny_unknown_fields)]
+struct SeedAccount {
+    #[validate(range(min = 1, max = 2147483647))]
+    id: u32,
+    #[serde(rename = "limite")]
+    #[validate(range(max = 2147483647))]
+    limit: u32,
+}
+
+/// Opens the accounts of the file that do not exist yet, existing ones are left as
+/// they are.
+pub async fn seed(config: &Config, file: &Path) -> Result<(), Box<dyn Error>> {
+    if config.database == DatabaseConfig::Memory {
+        return Err(
+            "the memory database only lives inside the serve process, see SEED_FILE".into(),
+        );
+    }
+    let database = connect(config).await?;
+    let result = seed_database(&database, file).await;
+    database.close().await;
+    result
+}
+
+/// Same as `seed`, on an open database.
+pub async fn seed_database(database: &Database, file: &Path) -> Result<(), Box<dyn Error>> {
+    let content = std::fs::read_to_string(file)
+        .map_err(|e| format!("failed to read {}: {e}", file.display()))?;
+    let accounts: Vec<SeedAccount> = serde_json::from_str(&content)
+        .map_err(|e| format!("invalid accounts file {}: {e}", file.display()))?;
+    for account in &accounts {
+        account
+            .validate()
+            .map_err(|e| format!("invalid account {}: {e}", account.id))?;
+    }
+
+    let mut seeded = 0;
+    for account in &accounts {
+        if database.seed_account(account.id, account.limit).await? {
+            seeded += 1;
+        }
+    }
+    tracing::info!(
+        seeded,
+        existing = accounts.len() - seeded,
+        "Accounts seeded"
+    );
+    Ok(())
+}
+
+/// Fails when the database is unreachable or its schema is not the one this build
+/// expects.
+pub async fn check(config: &Config) -> Result<(), Box<dyn Error>> {
+    let database = connect(config).await?;
+    database.ping().await?;
+    database.close().await;
+    tracing::info!(
+        database_type = %config.database.database_type(),
+        "Database reachable"
+    );
+
+    match &config.database {
+        DatabaseConfig::Postgres { url } => {
+            let pool = postgres_pool(url, &config.pool, false).await?;
+            check_migrations(&POSTGRES_MIGRATOR, &pool).await?;
+            pool.close().await;
+        }
+        DatabaseConfig::Sqlite { url } => {
+            let pool = sqlite_pool(url, &config.pool).await?;
+            check_migrations(&SQLITE_MIGRATOR, &pool).await?;
+            pool.close().await;
+        }
+        DatabaseConfig::Mongo { url, schema } => {
+            let client = mongodb::Client::with_uri_str(url).await?;
+            let pending = MongoDatabase::pending_migrations(&client, *schema).await?;
+            client.shutdown().await;
+            if !pending.is_empty() {
+                return Err(format!("pending migrations {pending:?}, run migrate").into());
+            }
+            tracing::info!("Schema up to date");
+        }
+        DatabaseConfig::Memory | DatabaseConfig::Redis { .. } => {}
+    }
+    Ok(())
+}
+
+/// Fails unless the migrations applied are exactly the ones embedded in the binary.
+async fn check_migrations<DB>(migrator: &Migrator, pool: &Pool<DB>) -> Result<(), Box<dyn Error>>
+where
+    DB: sqlx::Database,
+    DB::Connection: Migrate,
+{
+    let mut connection = pool.acquire().await?;
+    if let Some(version) = connection.dirty_version().await? {
+        return Err(MigrateError::Dirty(version).into());
+    }
+    let mut applied: HashMap<_, _> = connection
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|migration| (migration.version, migration.checksum))
+        .collect();
+
+    let mut pending = Vec::new();
+    for migration in migrator
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+    {
+        match applied.remove(&migration.version) {
+            Some(checksum) if checksum != migration.checksum => {
+                return Err(MigrateError::VersionMismatch(migration.version).into());
+            }
+            Some(_) => {}
+            None => pending.push(migration.version),
+        }
+    }
+    // applied by a newer build
+    if let Some(&version) = applied.keys().min() {
+        return Err(MigrateError::VersionMissing(version).into());
+    }
+    if !pending.is_empty() {
+        return Err(format!("pending migrations {pending:?}, run migrate").into());
+    }
+    let version = migrator.iter().map(|migration| migration.version).max();
+    tracing::info!(version, "Schema up to date");
+    Ok(())
+}
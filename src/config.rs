@@ -0,0 +1,452 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+
+use crate::database::MongoSchema;
+
+const DEFAULT_PORT: u16 = 9999;
+const DEFAULT_DATABASE_CONNECTIONS: u32 = 10;
+/// Same as the sqlx defaults.
+const DEFAULT_ACQUIRE_TIMEOUT: u64 = 30;
+const DEFAULT_IDLE_TIMEOUT: u64 = 10 * 60;
+const DEFAULT_STATEMENT_SIZE: u32 = 10;
+const MAX_STATEMENT_SIZE: u32 = 1000;
+const DEFAULT_STATEMENT_CACHE_TTL: u64 = 60;
+/// Seconds a hold reserves the limit.
+const DEFAULT_HOLD_TTL: u64 = 7 * 24 * 60 * 60;
+/// Seconds in-flight requests have to finish on shutdown, below the 10 seconds
+/// docker waits before killing the container.
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DatabaseType {
+    Postgres,
+    Sqlite,
+    Mongo,
+    Memory,
+    Redis,
+}
+
+impl fmt::Display for DatabaseType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.to_possible_value() {
+            Some(value) => f.write_str(value.get_name()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Rinha de Backend API server
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// TOML file with the settings not given as flags or environment variables
+    #[arg(long, global = true, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+    #[command(flatten)]
+    pub settings: Settings,
+}
+
+#[derive(Clone, Debug, PartialEq, Subcommand)]
+pub enum Command {
+    /// Serve the HTTP API, the default
+    Serve,
+    /// Apply the pending migrations of the database
+    Migrate,
+    /// Open the accounts of a JSON file that do not exist yet
+    Seed {
+        /// Array of accounts such as [{"id": 1, "limite": 100000}]
+        #[arg(long)]
+        file: PathBuf,
+    },
+    /// Check the database is reachable and has every migration applied
+    Check,
+}
+
+/// Every setting can be given as a flag, an environment variable or in the
+/// configuration file, in that order of precedence.
+#[derive(Debug, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// [default: postgres]
+    #[arg(long, global = true, env = "DATABASE_TYPE")]
+    database_type: Option<DatabaseType>,
+    /// Required by postgres and sqlite
+    #[arg(long, global = true, env = "DATABASE_URL")]
+    database_url: Option<String>,
+    /// Maximum connections of the postgres and sqlite pools [default: 10]
+    #[arg(long, global = true, env = "DATABASE_CONNECTIONS")]
+    database_connections: Option<u32>,
+    /// Seconds to wait for a free pool connection [default: 30]
+    #[arg(long, global = true, env = "DATABASE_ACQUIRE_TIMEOUT_SECONDS")]
+    database_acquire_timeout_seconds: Option<u64>,
+    /// Seconds an idle pool connection is kept open [default: 600]
+    #[arg(long, global = true, env = "DATABASE_IDLE_TIMEOUT_SECONDS")]
+    database_idle_timeout_seconds: Option<u64>,
+    /// Required by mongo
+    #[arg(long, global = true, env = "MONGO_URL")]
+    mongo_url: Option<String>,
+    /// Defaults to collection when the server supports transactions
+    #[arg(long, global = true, env = "MONGO_SCHEMA")]
+    mongo_schema: Option<MongoSchema>,
+    /// Required by redis, caches the statements of the other databases
+    #[arg(long, global = true, env = "REDIS_URL")]
+    redis_url: Option<String>,
+    /// Address to listen on [default: 0.0.0.0]
+    #[arg(long, global = true, env = "HOST")]
+    host: Option<IpAddr>,
+    /// [default: 9999]
+    #[arg(long, global = true, env = "PORT")]
+    port: Option<u16>,
+    /// Transactions shown in the statement [default: 10]
+    #[arg(long, global = true, env = "STATEMENT_SIZE")]
+    statement_size: Option<u32>,
+    /// Seconds a cached statement is trusted before being reloaded [default: 60]
+    #[arg(long, global = true, env = "STATEMENT_CACHE_TTL_SECONDS")]
+    statement_cache_ttl_seconds: Option<u64>,
+    /// Seconds a hold reserves the limit [default: 604800]
+    #[arg(long, global = true, env = "HOLD_TTL_SECONDS")]
+    hold_ttl_seconds: Option<u64>,
+    /// Seconds in-flight requests have to finish on shutdown [default: 8]
+    #[arg(long, global = true, env = "SHUTDOWN_TIMEOUT_SECONDS")]
+    shutdown_timeout_seconds: Option<u64>,
+    /// Stream the account activity, postgres then notifies every write and mongo needs
+    /// the collection schema [default: false]
+    #[arg(long, global = true, env = "ACCOUNT_EVENTS")]
+    account_events: Option<bool>,
+    /// Accounts opened by serve as seed does, the only way to fill the memory database
+    #[arg(long, global = true, env = "SEED_FILE")]
+    seed_file: Option<PathBuf>,
+}
+
+impl Settings {
+    fn read(path: &Path) -> Result<Self, ConfigError> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+        toml::from_str(&content).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+    }
+
+    /// Takes from `other` what is not set.
+    fn or(self, other: Settings) -> Settings {
+        Settings {
+            database_type: self.database_type.or(other.database_type),
+            database_url: self.database_url.or(other.database_url),
+            database_connections: self.database_connections.or(other.database_connections),
+            database_acquire_timeout_seconds: self
+                .database_acquire_timeout_seconds
+                .or(other.database_acquire_timeout_seconds),
+            database_idle_timeout_seconds: self
+                .database_idle_timeout_seconds
+                .or(other.database_idle_timeout_seconds),
+            mongo_url: self.mongo_url.or(other.mongo_url),
+            mongo_schema: self.mongo_schema.or(other.mongo_schema),
+            redis_url: self.redis_url.or(other.redis_url),
+            host: self.host.or(other.host),
+            port: self.port.or(other.port),
+            statement_size: self.statement_size.or(other.statement_size),
+            statement_cache_ttl_seconds: self
+                .statement_cache_ttl_seconds
+                .or(other.statement_cache_ttl_seconds),
+            hold_ttl_seconds: self.hold_ttl_seconds.or(other.hold_ttl_seconds),
+            shutdown_timeout_seconds: self
+                .shutdown_timeout_seconds
+                .or(other.shutdown_timeout_seconds),
+            account_events: self.account_events.or(other.account_events),
+            seed_file: self.seed_file.or(other.seed_file),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("failed to read {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+
+    #[error("invalid configuration file {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+
+    #[error("{0} must be set when the database type is {1}")]
+    Missing(&'static str, DatabaseType),
+
+    #[error("{0} must be {1}")]
+    Invalid(&'static str, &'static str),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DatabaseConfig {
+    Postgres {
+        url: String,
+    },
+    Sqlite {
+        url: String,
+    },
+    Mongo {
+        url: String,
+        schema: Option<MongoSchema>,
+    },
+    Memory,
+    Redis {
+        url: String,
+    },
+}
+
+impl DatabaseConfig {
+    pub fn database_type(&self) -> DatabaseType {
+        match self {
+            DatabaseConfig::Postgres { .. } => DatabaseType::Postgres,
+            DatabaseConfig::Sqlite { .. } => DatabaseType::Sqlite,
+            DatabaseConfig::Mongo { .. } => DatabaseType::Mongo,
+            DatabaseConfig::Memory => DatabaseType::Memory,
+            DatabaseConfig::Redis { .. } => DatabaseType::Redis,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolConfig {
+    pub max_connections: u32,
+    pub acquire_timeout: Duration,
+    pub idle_timeout: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    pub pool: PoolConfig,
+    /// Redis caching the statements, never set when redis is the database.
+    pub cache_url: Option<String>,
+    pub bind: SocketAddr,
+    pub statement_size: u32,
+    pub statement_cache_ttl_seconds: u64,
+    pub hold_ttl: chrono::Duration,
+    pub shutdown_timeout: Duration,
+    pub account_events: bool,
+    pub seed_file: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn load(cli: Cli) -> Result<Self, ConfigError> {
+        let file = match &cli.config {
+            Some(path) => Settings::read(path)?,
+            None => Settings::default(),
+        };
+        cli.settings.or(file).try_into()
+    }
+}
+
+impl TryFrom<Settings> for Config {
+    type Error = ConfigError;
+
+    fn try_from(settings: Settings) -> Result<Self, Self::Error> {
+        let database_type = settings.database_type.unwrap_or(DatabaseType::Postgres);
+        let required =
+            |value: Option<String>, name| value.ok_or(ConfigError::Missing(name, database_type));
+        let database = match database_type {
+            DatabaseType::Postgres => DatabaseConfig::Postgres {
+                url: required(settings.database_url, "DATABASE_URL")?,
+            },
+            DatabaseType::Sqlite => DatabaseConfig::Sqlite {
+                url: required(settings.database_url, "DATABASE_URL")?,
+            },
+            DatabaseType::Mongo => DatabaseConfig::Mongo {
+                url: required(settings.mongo_url, "MONGO_URL")?,
+                schema: settings.mongo_schema,
+            },
+            DatabaseType::Memory => DatabaseConfig::Memory,
+            DatabaseType::Redis => DatabaseConfig::Redis {
+                url: required(settings.redis_url.clone(), "REDIS_URL")?,
+            },
+        };
+        // redis is already the source of truth, there is nothing to cache
+        let cache_url = settings
+            .redis_url
+            .filter(|_| database_type != DatabaseType::Redis);
+
+        let max_connections = settings
+            .database_connections
+            .unwrap_or(DEFAULT_DATABASE_CONNECTIONS);
+        if max_connections == 0 {
+            return Err(ConfigError::Invalid("DATABASE_CONNECTIONS", "at least 1"));
+        }
+        let acquire_timeout = settings
+            .database_acquire_timeout_seconds
+            .unwrap_or(DEFAULT_ACQUIRE_TIMEOUT);
+        if acquire_timeout == 0 {
+            return Err(ConfigError::Invalid(
+                "DATABASE_ACQUIRE_TIMEOUT_SECONDS",
+                "at least 1",
+            ));
+        }
+        let statement_size = settings.statement_size.unwrap_or(DEFAULT_STATEMENT_SIZE);
+        if !(1..=MAX_STATEMENT_SIZE).contains(&statement_size) {
+            return Err(ConfigError::Invalid("STATEMENT_SIZE", "between 1 and 1000"));
+        }
+        let statement_cache_ttl_seconds = settings
+            .statement_cache_ttl_seconds
+            .unwrap_or(DEFAULT_STATEMENT_CACHE_TTL);
+        if statement_cache_ttl_seconds == 0 {
+            return Err(ConfigError::Invalid(
+                "STATEMENT_CACHE_TTL_SECONDS",
+                "at least 1",
+            ));
+        }
+        let hold_ttl = settings.hold_ttl_seconds.unwrap_or(DEFAULT_HOLD_TTL);
+        let hold_ttl = i64::try_from(hold_ttl)
+            .ok()
+            .filter(|&ttl| ttl > 0)
+            .and_then(chrono::Duration::try_seconds)
+            .ok_or(ConfigError::Invalid(
+                "HOLD_TTL_SECONDS",
+                "at least 1 and at most a few centuries",
+            ))?;
+
+        Ok(Config {
+            database,
+            pool: PoolConfig {
+                max_connections,
+                acquire_timeout: Duration::from_secs(acquire_timeout),
+                idle_timeout: Duration::from_secs(
+                    settings
+                        .database_idle_timeout_seconds
+                        .unwrap_or(DEFAULT_IDLE_TIMEOUT),
+                ),
+            },
+            cache_url,
+            bind: SocketAddr::new(
+                settings.host.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+                settings.port.unwrap_or(DEFAULT_PORT),
+            ),
+            statement_size,
+            statement_cache_ttl_seconds,
+            hold_ttl,
+            shutdown_timeout: Duration::from_secs(
+                settings
+                    .shutdown_timeout_seconds
+                    .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            ),
+            account_events: settings.account_events.unwrap_or_default(),
+            seed_file: settings.seed_file,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+
+    use super::*;
+
+    /// Parses the flags alone, the environment of the machine running the tests is
+    /// not read.
+    fn cli(args: &[&str]) -> Cli {
+        let command = Cli::command().mut_args(|arg| arg.env(None));
+        let matches = command
+            .try_get_matches_from(["rinha-de-backend"].iter().chain(args))
+            .unwrap();
+        Cli::from_arg_matches(&matches).unwrap()
+    }
+
+    fn parse(args: &[&str]) -> Settings {
+        cli(args).settings
+    }
+
+    #[test]
+    fn should_prefer_flags_over_the_file() {
+        let file: Settings = toml::from_str(
+            r#"
+            database_type = "sqlite"
+            database_url = "sqlite://file.db"
+            port = 3000
+            "#,
+        )
+        .unwrap();
+        let flags = parse(&["--database-url", "sqlite://flag.db"]);
+
+        let config = Config::try_from(flags.or(file)).unwrap();
+        assert_eq!(
+            config.database,
+            DatabaseConfig::Sqlite {
+                url: "sqlite://flag.db".to_string()
+            }
+        );
+        assert_eq!(config.bind.port(), 3000);
+        assert_eq!(config.statement_size, DEFAULT_STATEMENT_SIZE);
+    }
+
+    #[test]
+    fn should_accept_settings_after_the_command() {
+        let cli = cli(&[
+            "seed",
+            "--file",
+            "accounts.json",
+            "--database-type",
+            "memory",
+        ]);
+        assert_eq!(
+            cli.command,
+            Some(Command::Seed {
+                file: PathBuf::from("accounts.json")
+            })
+        );
+        let config = Config::load(cli).unwrap();
+        assert_eq!(config.database, DatabaseConfig::Memory);
+    }
+
+    #[test]
+    fn should_cache_only_other_databases() {
+        let config = Config::try_from(parse(&[
+            "--database-type",
+            "memory",
+            "--redis-url",
+            "redis://cache",
+        ]))
+        .unwrap();
+        assert_eq!(config.cache_url.as_deref(), Some("redis://cache"));
+
+        let config = Config::try_from(parse(&[
+            "--database-type",
+            "redis",
+            "--redis-url",
+            "redis://database",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.database,
+            DatabaseConfig::Redis {
+                url: "redis://database".to_string()
+            }
+        );
+        assert_eq!(config.cache_url, None);
+    }
+
+    #[test]
+    fn should_reject_invalid_settings() {
+        let error = Config::try_from(Settings::default()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "DATABASE_URL must be set when the database type is postgres"
+        );
+
+        let error = Config::try_from(parse(&[
+            "--database-type",
+            "memory",
+            "--statement-size",
+            "0",
+        ]))
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "STATEMENT_SIZE must be between 1 and 1000"
+        );
+
+        let error = toml::from_str::<Settings>("database_typo = \"memory\"").unwrap_err();
+        assert!(error.to_string().contains("unknown field `database_typo`"));
+    }
+}
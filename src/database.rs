@@ -1,20 +1,89 @@
 pub use cache::CachedDatabase;
+pub use memory::MemoryDatabase;
 pub use models::*;
-pub use mongo::MongoDatabase;
+pub use mongo::{MongoDatabase, MongoSchema};
 pub use postgres::PostgresDatabase;
+pub use self::redis::RedisDatabase;
+pub use sqlite::SqliteDatabase;
+
+use std::future::Future;
+use std::time::Instant;
+
+use tokio::sync::broadcast;
+use tracing::{field::Empty, Instrument};
 
 use crate::error::ServerError;
 
 mod cache;
+mod memory;
 mod models;
 mod postgres;
 mod mongo;
+mod redis;
+mod sqlite;
 
 #[derive(Clone)]
 pub enum Database {
+    Direct(Backend),
+    Cached(CachedDatabase<Backend>),
+}
+
+#[derive(Clone)]
+pub enum Backend {
     Postgres(PostgresDatabase),
+    Sqlite(SqliteDatabase),
     Mongo(MongoDatabase),
-    Cached(CachedDatabase<PostgresDatabase>),
+    Memory(MemoryDatabase),
+    Redis(Box<RedisDatabase>),
+}
+
+/// Events kept for each subscriber, a slower one misses the oldest.
+const EVENTS_CAPACITY: usize = 1024;
+/// How long to wait before listening again to the events of the other instances.
+const LISTEN_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+fn events_channel() -> broadcast::Sender<AccountEvent> {
+    broadcast::channel(EVENTS_CAPACITY).0
+}
+
+impl Backend {
+    fn name(&self) -> &'static str {
+        match self {
+            Backend::Postgres(_) => "postgres",
+            Backend::Sqlite(_) => "sqlite",
+            Backend::Mongo(_) => "mongo",
+            Backend::Memory(_) => "memory",
+            Backend::Redis(_) => "redis",
+        }
+    }
+
+    /// Runs a call to the backend inside a span recording its outcome and duration.
+    async fn traced<T>(
+        &self,
+        operation: &'static str,
+        account_id: Option<u32>,
+        call: impl Future<Output = Result<T, ServerError>>,
+    ) -> Result<T, ServerError> {
+        let span = tracing::info_span!(
+            "repository",
+            operation,
+            backend = self.name(),
+            account_id,
+            outcome = Empty,
+            duration_ms = Empty,
+        );
+        let start = Instant::now();
+        let result = call.instrument(span.clone()).await;
+        span.record("duration_ms", start.elapsed().as_secs_f64() * 1000.0);
+        span.record(
+            "outcome",
+            match &result {
+                Ok(_) => "ok",
+                Err(e) => e.code(),
+            },
+        );
+        result
+    }
 }
 
 impl TransactionRepository for Database {
@@ -22,31 +91,552 @@ impl TransactionRepository for Database {
         &self,
         id: u32,
         transaction: Transaction,
-    ) -> Result<Balance, ServerError> {
+    ) -> Result<(Balance, Transaction), ServerError> {
         match self {
-            Database::Postgres(database) => database.add_transaction(id, transaction).await,
+            Database::Direct(database) => database.add_transaction(id, transaction).await,
             Database::Cached(database) => database.add_transaction(id, transaction).await,
-            Database::Mongo(database) => database.add_transaction(id, transaction).await,
         }
     }
 
+    async fn add_idempotent_transaction(
+        &self,
+        id: u32,
+        transaction: Transaction,
+        key: &str,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        match self {
+            Database::Direct(database) => {
+                database.add_idempotent_transaction(id, transaction, key).await
+            }
+            Database::Cached(database) => {
+                database.add_idempotent_transaction(id, transaction, key).await
+            }
+        }
+    }
+
+    async fn add_transactions(
+        &self,
+        id: u32,
+        transactions: Vec<Transaction>,
+        mode: BatchMode,
+    ) -> Result<BatchOutcome, ServerError> {
+        // refused before any is applied, whatever the mode
+        for (index, transaction) in transactions.iter().enumerate() {
+            transaction.value().map_err(|e| ServerError::BatchRejected(index, Box::new(e)))?;
+        }
+        match self {
+            Database::Direct(database) => database.add_transactions(id, transactions, mode).await,
+            Database::Cached(database) => database.add_transactions(id, transactions, mode).await,
+        }
+    }
+
+    async fn transfer(&self, id: u32, transfer: Transfer) -> Result<Balance, ServerError> {
+        match self {
+            Database::Direct(database) => database.transfer(id, transfer).await,
+            Database::Cached(database) => database.transfer(id, transfer).await,
+        }
+    }
+
+    async fn reverse_transaction(
+        &self,
+        id: u32,
+        transaction_id: &str,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        match self {
+            Database::Direct(database) => database.reverse_transaction(id, transaction_id).await,
+            Database::Cached(database) => database.reverse_transaction(id, transaction_id).await,
+        }
+    }
+
+    async fn get_transaction(
+        &self,
+        id: u32,
+        transaction_id: &str,
+    ) -> Result<Transaction, ServerError> {
+        match self {
+            Database::Direct(database) => database.get_transaction(id, transaction_id).await,
+            Database::Cached(database) => database.get_transaction(id, transaction_id).await,
+        }
+    }
+
+    async fn place_hold(&self, id: u32, hold: Hold) -> Result<(Balance, Hold), ServerError> {
+        match self {
+            Database::Direct(database) => database.place_hold(id, hold).await,
+            Database::Cached(database) => database.place_hold(id, hold).await,
+        }
+    }
+
+    async fn capture_hold(
+        &self,
+        id: u32,
+        hold_id: &str,
+        value: Option<u32>,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        match self {
+            Database::Direct(database) => database.capture_hold(id, hold_id, value).await,
+            Database::Cached(database) => database.capture_hold(id, hold_id, value).await,
+        }
+    }
+
+    async fn release_hold(&self, id: u32, hold_id: &str) -> Result<(Balance, Hold), ServerError> {
+        match self {
+            Database::Direct(database) => database.release_hold(id, hold_id).await,
+            Database::Cached(database) => database.release_hold(id, hold_id).await,
+        }
+    }
+
+    async fn expire_holds(&self) -> Result<Vec<u32>, ServerError> {
+        match self {
+            Database::Direct(database) => database.expire_holds().await,
+            Database::Cached(database) => database.expire_holds().await,
+        }
+    }
 
     async fn get_statement(&self, id: &u32) -> Result<Statement, ServerError> {
         match self {
-            Database::Postgres(database) => database.get_statement(id).await,
+            Database::Direct(database) => database.get_statement(id).await,
             Database::Cached(database) => database.get_statement(id).await,
-            Database::Mongo(database) => database.get_statement(id).await,
+        }
+    }
+
+    async fn get_history(&self, id: u32, query: HistoryQuery) -> Result<HistoryPage, ServerError> {
+        match self {
+            Database::Direct(database) => database.get_history(id, query).await,
+            Database::Cached(database) => database.get_history(id, query).await,
+        }
+    }
+
+    async fn create_account(&self, limit: u32) -> Result<Account, ServerError> {
+        match self {
+            Database::Direct(database) => database.create_account(limit).await,
+            Database::Cached(database) => database.create_account(limit).await,
+        }
+    }
+
+    async fn seed_account(&self, id: u32, limit: u32) -> Result<bool, ServerError> {
+        match self {
+            Database::Direct(database) => database.seed_account(id, limit).await,
+            Database::Cached(database) => database.seed_account(id, limit).await,
+        }
+    }
+
+    async fn get_account(&self, id: u32) -> Result<Account, ServerError> {
+        match self {
+            Database::Direct(database) => database.get_account(id).await,
+            Database::Cached(database) => database.get_account(id).await,
+        }
+    }
+
+    async fn update_limit(&self, id: u32, limit: u32) -> Result<Account, ServerError> {
+        match self {
+            Database::Direct(database) => database.update_limit(id, limit).await,
+            Database::Cached(database) => database.update_limit(id, limit).await,
+        }
+    }
+
+    async fn close_account(&self, id: u32) -> Result<Account, ServerError> {
+        match self {
+            Database::Direct(database) => database.close_account(id).await,
+            Database::Cached(database) => database.close_account(id).await,
+        }
+    }
+
+    async fn ping(&self) -> Result<(), ServerError> {
+        match self {
+            Database::Direct(database) => database.ping().await,
+            Database::Cached(database) => database.ping().await,
+        }
+    }
+
+    async fn close(&self) {
+        match self {
+            Database::Direct(database) => database.close().await,
+            Database::Cached(database) => database.close().await,
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<AccountEvent> {
+        match self {
+            Database::Direct(database) => database.subscribe(),
+            Database::Cached(database) => database.subscribe(),
+        }
+    }
+}
+
+impl TransactionRepository for Backend {
+    async fn add_transaction(
+        &self,
+        id: u32,
+        transaction: Transaction,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        self.traced("add_transaction", Some(id), async {
+            match self {
+                Backend::Postgres(database) => database.add_transaction(id, transaction).await,
+                Backend::Sqlite(database) => database.add_transaction(id, transaction).await,
+                Backend::Mongo(database) => database.add_transaction(id, transaction).await,
+                Backend::Memory(database) => database.add_transaction(id, transaction).await,
+                Backend::Redis(database) => database.add_transaction(id, transaction).await,
+            }
+        })
+        .await
+    }
+
+    async fn add_idempotent_transaction(
+        &self,
+        id: u32,
+        transaction: Transaction,
+        key: &str,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        self.traced("add_idempotent_transaction", Some(id), async {
+            match self {
+                Backend::Postgres(database) => {
+                    database.add_idempotent_transaction(id, transaction, key).await
+                }
+                Backend::Sqlite(database) => {
+                    database.add_idempotent_transaction(id, transaction, key).await
+                }
+                Backend::Mongo(database) => {
+                    database.add_idempotent_transaction(id, transaction, key).await
+                }
+                Backend::Memory(database) => {
+                    database.add_idempotent_transaction(id, transaction, key).await
+                }
+                Backend::Redis(database) => {
+                    database.add_idempotent_transaction(id, transaction, key).await
+                }
+            }
+        })
+        .await
+    }
+
+    async fn add_transactions(
+        &self,
+        id: u32,
+        transactions: Vec<Transaction>,
+        mode: BatchMode,
+    ) -> Result<BatchOutcome, ServerError> {
+        self.traced("add_transactions", Some(id), async {
+            match self {
+                Backend::Postgres(database) => {
+                    database.add_transactions(id, transactions, mode).await
+                }
+                Backend::Sqlite(database) => {
+                    database.add_transactions(id, transactions, mode).await
+                }
+                Backend::Mongo(database) => database.add_transactions(id, transactions, mode).await,
+                Backend::Memory(database) => {
+                    database.add_transactions(id, transactions, mode).await
+                }
+                Backend::Redis(database) => database.add_transactions(id, transactions, mode).await,
+            }
+        })
+        .await
+    }
+
+    async fn transfer(&self, id: u32, transfer: Transfer) -> Result<Balance, ServerError> {
+        self.traced("transfer", Some(id), async {
+            match self {
+                Backend::Postgres(database) => database.transfer(id, transfer).await,
+                Backend::Sqlite(database) => database.transfer(id, transfer).await,
+                Backend::Mongo(database) => database.transfer(id, transfer).await,
+                Backend::Memory(database) => database.transfer(id, transfer).await,
+                Backend::Redis(database) => database.transfer(id, transfer).await,
+            }
+        })
+        .await
+    }
+
+    async fn reverse_transaction(
+        &self,
+        id: u32,
+        transaction_id: &str,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        self.traced("reverse_transaction", Some(id), async {
+            match self {
+                Backend::Postgres(database) => {
+                    database.reverse_transaction(id, transaction_id).await
+                }
+                Backend::Sqlite(database) => database.reverse_transaction(id, transaction_id).await,
+                Backend::Mongo(database) => database.reverse_transaction(id, transaction_id).await,
+                Backend::Memory(database) => database.reverse_transaction(id, transaction_id).await,
+                Backend::Redis(database) => database.reverse_transaction(id, transaction_id).await,
+            }
+        })
+        .await
+    }
+
+    async fn get_transaction(
+        &self,
+        id: u32,
+        transaction_id: &str,
+    ) -> Result<Transaction, ServerError> {
+        self.traced("get_transaction", Some(id), async {
+            match self {
+                Backend::Postgres(database) => database.get_transaction(id, transaction_id).await,
+                Backend::Sqlite(database) => database.get_transaction(id, transaction_id).await,
+                Backend::Mongo(database) => database.get_transaction(id, transaction_id).await,
+                Backend::Memory(database) => database.get_transaction(id, transaction_id).await,
+                Backend::Redis(database) => database.get_transaction(id, transaction_id).await,
+            }
+        })
+        .await
+    }
+
+    async fn place_hold(&self, id: u32, hold: Hold) -> Result<(Balance, Hold), ServerError> {
+        self.traced("place_hold", Some(id), async {
+            match self {
+                Backend::Postgres(database) => database.place_hold(id, hold).await,
+                Backend::Sqlite(database) => database.place_hold(id, hold).await,
+                Backend::Mongo(database) => database.place_hold(id, hold).await,
+                Backend::Memory(database) => database.place_hold(id, hold).await,
+                Backend::Redis(database) => database.place_hold(id, hold).await,
+            }
+        })
+        .await
+    }
+
+    async fn capture_hold(
+        &self,
+        id: u32,
+        hold_id: &str,
+        value: Option<u32>,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        self.traced("capture_hold", Some(id), async {
+            match self {
+                Backend::Postgres(database) => database.capture_hold(id, hold_id, value).await,
+                Backend::Sqlite(database) => database.capture_hold(id, hold_id, value).await,
+                Backend::Mongo(database) => database.capture_hold(id, hold_id, value).await,
+                Backend::Memory(database) => database.capture_hold(id, hold_id, value).await,
+                Backend::Redis(database) => database.capture_hold(id, hold_id, value).await,
+            }
+        })
+        .await
+    }
+
+    async fn release_hold(&self, id: u32, hold_id: &str) -> Result<(Balance, Hold), ServerError> {
+        self.traced("release_hold", Some(id), async {
+            match self {
+                Backend::Postgres(database) => database.release_hold(id, hold_id).await,
+                Backend::Sqlite(database) => database.release_hold(id, hold_id).await,
+                Backend::Mongo(database) => database.release_hold(id, hold_id).await,
+                Backend::Memory(database) => database.release_hold(id, hold_id).await,
+                Backend::Redis(database) => database.release_hold(id, hold_id).await,
+            }
+        })
+        .await
+    }
+
+    async fn expire_holds(&self) -> Result<Vec<u32>, ServerError> {
+        self.traced("expire_holds", None, async {
+            match self {
+                Backend::Postgres(database) => database.expire_holds().await,
+                Backend::Sqlite(database) => database.expire_holds().await,
+                Backend::Mongo(database) => database.expire_holds().await,
+                Backend::Memory(database) => database.expire_holds().await,
+                Backend::Redis(database) => database.expire_holds().await,
+            }
+        })
+        .await
+    }
+
+    async fn get_statement(&self, id: &u32) -> Result<Statement, ServerError> {
+        self.traced("get_statement", Some(*id), async {
+            match self {
+                Backend::Postgres(database) => database.get_statement(id).await,
+                Backend::Sqlite(database) => database.get_statement(id).await,
+                Backend::Mongo(database) => database.get_statement(id).await,
+                Backend::Memory(database) => database.get_statement(id).await,
+                Backend::Redis(database) => database.get_statement(id).await,
+            }
+        })
+        .await
+    }
+
+    async fn get_history(&self, id: u32, query: HistoryQuery) -> Result<HistoryPage, ServerError> {
+        self.traced("get_history", Some(id), async {
+            match self {
+                Backend::Postgres(database) => database.get_history(id, query).await,
+                Backend::Sqlite(database) => database.get_history(id, query).await,
+                Backend::Mongo(database) => database.get_history(id, query).await,
+                Backend::Memory(database) => database.get_history(id, query).await,
+                Backend::Redis(database) => database.get_history(id, query).await,
+            }
+        })
+        .await
+    }
+
+    async fn create_account(&self, limit: u32) -> Result<Account, ServerError> {
+        self.traced("create_account", None, async {
+            match self {
+                Backend::Postgres(database) => database.create_account(limit).await,
+                Backend::Sqlite(database) => database.create_account(limit).await,
+                Backend::Mongo(database) => database.create_account(limit).await,
+                Backend::Memory(database) => database.create_account(limit).await,
+                Backend::Redis(database) => database.create_account(limit).await,
+            }
+        })
+        .await
+    }
+
+    async fn seed_account(&self, id: u32, limit: u32) -> Result<bool, ServerError> {
+        self.traced("seed_account", Some(id), async {
+            match self {
+                Backend::Postgres(database) => database.seed_account(id, limit).await,
+                Backend::Sqlite(database) => database.seed_account(id, limit).await,
+                Backend::Mongo(database) => database.seed_account(id, limit).await,
+                Backend::Memory(database) => database.seed_account(id, limit).await,
+                Backend::Redis(database) => database.seed_account(id, limit).await,
+            }
+        })
+        .await
+    }
+
+    async fn get_account(&self, id: u32) -> Result<Account, ServerError> {
+        self.traced("get_account", Some(id), async {
+            match self {
+                Backend::Postgres(database) => database.get_account(id).await,
+                Backend::Sqlite(database) => database.get_account(id).await,
+                Backend::Mongo(database) => database.get_account(id).await,
+                Backend::Memory(database) => database.get_account(id).await,
+                Backend::Redis(database) => database.get_account(id).await,
+            }
+        })
+        .await
+    }
+
+    async fn update_limit(&self, id: u32, limit: u32) -> Result<Account, ServerError> {
+        self.traced("update_limit", Some(id), async {
+            match self {
+                Backend::Postgres(database) => database.update_limit(id, limit).await,
+                Backend::Sqlite(database) => database.update_limit(id, limit).await,
+                Backend::Mongo(database) => database.update_limit(id, limit).await,
+                Backend::Memory(database) => database.update_limit(id, limit).await,
+                Backend::Redis(database) => database.update_limit(id, limit).await,
+            }
+        })
+        .await
+    }
+
+    async fn close_account(&self, id: u32) -> Result<Account, ServerError> {
+        self.traced("close_account", Some(id), async {
+            match self {
+                Backend::Postgres(database) => database.close_account(id).await,
+                Backend::Sqlite(database) => database.close_account(id).await,
+                Backend::Mongo(database) => database.close_account(id).await,
+                Backend::Memory(database) => database.close_account(id).await,
+                Backend::Redis(database) => database.close_account(id).await,
+            }
+        })
+        .await
+    }
+
+    // not traced, readiness probes would flood the logs
+    async fn ping(&self) -> Result<(), ServerError> {
+        match self {
+            Backend::Postgres(database) => database.ping().await,
+            Backend::Sqlite(database) => database.ping().await,
+            Backend::Mongo(database) => database.ping().await,
+            Backend::Memory(database) => database.ping().await,
+            Backend::Redis(database) => database.ping().await,
+        }
+    }
+
+    async fn close(&self) {
+        match self {
+            Backend::Postgres(database) => database.close().await,
+            Backend::Sqlite(database) => database.close().await,
+            Backend::Mongo(database) => database.close().await,
+            Backend::Memory(database) => database.close().await,
+            Backend::Redis(database) => database.close().await,
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<AccountEvent> {
+        match self {
+            Backend::Postgres(database) => database.subscribe(),
+            Backend::Sqlite(database) => database.subscribe(),
+            Backend::Mongo(database) => database.subscribe(),
+            Backend::Memory(database) => database.subscribe(),
+            Backend::Redis(database) => database.subscribe(),
         }
     }
 }
 
 pub trait TransactionRepository {
+    /// Returns the new balance and the stored transaction, which carries its id.
     async fn add_transaction(
         &self,
         id: u32,
         transaction: Transaction,
-    ) -> Result<Balance, ServerError>;
+    ) -> Result<(Balance, Transaction), ServerError>;
+    /// Applies the transaction once per idempotency key. Repeating a key returns the
+    /// balance and transaction stored with it, and reusing it for a different request
+    /// is a conflict.
+    async fn add_idempotent_transaction(
+        &self,
+        id: u32,
+        transaction: Transaction,
+        key: &str,
+    ) -> Result<(Balance, Transaction), ServerError>;
+    /// Applies the transactions in order, in a single database transaction where the
+    /// backend has them. Only transactions that would exceed the limit are handled as
+    /// `mode` says, any other failure fails the whole batch.
+    async fn add_transactions(
+        &self,
+        id: u32,
+        transactions: Vec<Transaction>,
+        mode: BatchMode,
+    ) -> Result<BatchOutcome, ServerError>;
+    /// Moves money from wallet `id` to `transfer.to`, returning the payer balance.
+    async fn transfer(&self, id: u32, transfer: Transfer) -> Result<Balance, ServerError>;
+    /// Compensates a transaction with an entry of the opposite type that references it,
+    /// returning that entry. Backends that only keep the last transactions can only
+    /// reverse those.
+    async fn reverse_transaction(
+        &self,
+        id: u32,
+        transaction_id: &str,
+    ) -> Result<(Balance, Transaction), ServerError>;
+    /// Backends that only keep the last transactions can only find those.
+    async fn get_transaction(
+        &self,
+        id: u32,
+        transaction_id: &str,
+    ) -> Result<Transaction, ServerError>;
+    /// Reserves `hold.value` of the limit without posting a debit. Debits can only
+    /// use what is left.
+    async fn place_hold(&self, id: u32, hold: Hold) -> Result<(Balance, Hold), ServerError>;
+    /// Posts a debit of `value`, the whole hold when `None`, and releases the rest.
+    async fn capture_hold(
+        &self,
+        id: u32,
+        hold_id: &str,
+        value: Option<u32>,
+    ) -> Result<(Balance, Transaction), ServerError>;
+    async fn release_hold(&self, id: u32, hold_id: &str) -> Result<(Balance, Hold), ServerError>;
+    /// Releases the holds past their expiration date, returning the wallets they held.
+    async fn expire_holds(&self) -> Result<Vec<u32>, ServerError>;
     async fn get_statement(&self, id: &u32) -> Result<Statement, ServerError>;
+    /// Walks the wallet history from the newest transaction. Backends that only keep
+    /// the last transactions page through those.
+    async fn get_history(&self, id: u32, query: HistoryQuery) -> Result<HistoryPage, ServerError>;
+    /// Opens a new wallet with a zero balance.
+    async fn create_account(&self, limit: u32) -> Result<Account, ServerError>;
+    /// Opens the wallet with the given id unless it already exists, returning whether
+    /// it was opened. Wallets created afterwards get ids above the seeded ones.
+    async fn seed_account(&self, id: u32, limit: u32) -> Result<bool, ServerError>;
+    async fn get_account(&self, id: u32) -> Result<Account, ServerError>;
+    /// Changes the wallet limit, rejecting a limit below the current negative balance.
+    async fn update_limit(&self, id: u32, limit: u32) -> Result<Account, ServerError>;
+    /// Soft-closes the wallet: its history stays readable but new transactions fail.
+    async fn close_account(&self, id: u32) -> Result<Account, ServerError>;
+    /// Checks that the backend, and the cache in front of it, can serve requests.
+    async fn ping(&self) -> Result<(), ServerError>;
+    /// Closes the connections, once the requests using them are done.
+    async fn close(&self);
+    /// Receives the transactions applied to any wallet from now on. Backends shared
+    /// by several instances also deliver the ones applied by the others.
+    fn subscribe(&self) -> broadcast::Receiver<AccountEvent>;
 }
 
 #[cfg(test)]
@@ -68,4 +658,21 @@ mod tests {
         // let result = stmt.add_transaction(transaction.into());
         // assert!(result.is_ok());
     }
+
+    #[tokio::test]
+    async fn should_refuse_batches_with_values_out_of_range() {
+        let database = Database::Direct(Backend::Memory(MemoryDatabase::new(10)));
+        database.seed_account(1, 1000).await.unwrap();
+        let transactions = vec![
+            Transaction::new(100, TransactionType::Deposit, "credit".to_string()),
+            Transaction::new(4294967295, TransactionType::Withdraw, "debit".to_string()),
+        ];
+        let result = database
+            .add_transactions(1, transactions, BatchMode::BestEffort)
+            .await;
+        let error = result.err().unwrap();
+        assert_eq!(error.code(), "value_out_of_range");
+        assert!(matches!(error, ServerError::BatchRejected(1, _)));
+        assert_eq!(database.get_statement(&1).await.unwrap().balance.total, 0);
+    }
 }
@@ -1,8 +1,14 @@
-use redis::{AsyncCommands, Client as RedisClient};
+use chrono::Utc;
+use redis::{AsyncCommands, Client as RedisClient, Script};
+use tokio::sync::broadcast;
 
 use crate::{
-    database::{Balance, PostgresDatabase, Statement, Transaction, TransactionRepository},
+    database::{
+        Account, AccountEvent, Balance, BatchMode, BatchOutcome, HistoryPage, HistoryQuery, Hold,
+        Statement, Transaction, TransactionRepository, Transfer,
+    },
     error::ServerError,
+    metrics,
 };
 
 #[derive(Clone)]
@@ -12,23 +18,148 @@ where
 {
     cache: RedisClient,
     database: T,
+    apply_transaction: Script,
+    cache_statement: Script,
+    /// Number of transactions kept in the cached statement.
+    statement_size: isize,
+    /// Seconds a cached statement is trusted before being reloaded from the database.
+    statement_ttl: u64,
+}
+
+// Every write bumps the version (KEYS[1]) so a statement loaded from the database
+// before it is not cached afterwards. The total (KEYS[2]) is only moved when it
+// lands on the committed total (ARGV[2]), otherwise a concurrent write got there
+// first and the cached entries are dropped, returning 1. The transaction is only
+// pushed into statements already loaded, or the list would miss older ones.
+const APPLY_TRANSACTION: &str = r#"
+redis.call("INCR", KEYS[1])
+if redis.call("EXISTS", KEYS[2]) == 0 then
+    return 0
+end
+if redis.call("INCRBY", KEYS[2], ARGV[1]) ~= tonumber(ARGV[2]) then
+    redis.call("DEL", KEYS[2], KEYS[3], KEYS[4], KEYS[5], KEYS[6])
+    return 1
+end
+if redis.call("EXISTS", KEYS[3]) == 1 then
+    redis.call("LPUSH", KEYS[4], ARGV[3])
+    redis.call("LTRIM", KEYS[4], 0, ARGV[4] - 1)
+end
+return 0
+"#;
+
+// caches a statement read from the database, unless a write bumped the version
+// (KEYS[1]) since it was read at ARGV[1]
+const CACHE_STATEMENT: &str = r#"
+if tostring(redis.call("GET", KEYS[1]) or 0) ~= ARGV[1] then
+    return 0
+end
+redis.call("SET", KEYS[2], ARGV[3])
+redis.call("SET", KEYS[5], ARGV[4])
+if ARGV[5] == "" then
+    redis.call("DEL", KEYS[6])
+else
+    redis.call("SET", KEYS[6], ARGV[5])
+end
+redis.call("DEL", KEYS[4])
+for i = 6, #ARGV do
+    redis.call("RPUSH", KEYS[4], ARGV[i])
+end
+redis.call("SET", KEYS[3], 1, "EX", ARGV[2])
+return 1
+"#;
+
+/// Version, total, limit, held part, whether the statement is cached and its
+/// transactions.
+type CachedStatement = (
+    Option<i64>,
+    Option<i32>,
+    Option<u32>,
+    Option<u32>,
+    bool,
+    Vec<String>,
+);
+
+/// Keys of the cached entries of a wallet, in the order the scripts expect them.
+fn cache_keys(id: u32) -> [String; 6] {
+    [
+        format!("statement:{id}:version"),
+        format!("balance:{id}:total"),
+        format!("statement:{id}:cached"),
+        format!("statement:{id}:transactions"),
+        format!("balance:{id}:limit"),
+        format!("balance:{id}:held"),
+    ]
 }
 
 impl<T> CachedDatabase<T>
 where
     T: TransactionRepository,
 {
-    pub fn new(cache: RedisClient, database: T) -> Self {
-        Self { cache, database }
+    pub fn new(cache: RedisClient, database: T, statement_size: u32, statement_ttl: u64) -> Self {
+        Self {
+            cache,
+            database,
+            apply_transaction: Script::new(APPLY_TRANSACTION),
+            cache_statement: Script::new(CACHE_STATEMENT),
+            statement_size: statement_size as isize,
+            statement_ttl,
+        }
+    }
+
+    /// Drops the cached entries of wallets the database just wrote to. The write is
+    /// committed by then, so a failure only leaves them to expire with the statement TTL.
+    async fn invalidate(&self, ids: &[u32]) {
+        if let Err(e) = self.drop_cached(ids).await {
+            tracing::error!(?ids, "Failed to drop the cached statements: {e}");
+            metrics::CACHE_OUT_OF_SYNC.inc();
+        }
+    }
+
+    /// Drops the cached balance, limit, held part and statement of the given wallets,
+    /// bumping their version so statements being loaded are not cached.
+    async fn drop_cached(&self, ids: &[u32]) -> Result<(), ServerError> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for id in ids {
+            let [version, keys @ ..] = cache_keys(*id);
+            pipe.incr(version, 1).ignore().del(&keys).ignore();
+        }
+        let mut connection = self.cache.get_async_connection().await?;
+        pipe.query_async::<_, ()>(&mut connection).await?;
+        Ok(())
+    }
+
+    /// Applies a committed transaction to the cached entries, returning whether they
+    /// were out of sync and dropped instead.
+    async fn cache_transaction(
+        &self,
+        connection: &mut redis::aio::Connection,
+        id: u32,
+        balance: &Balance,
+        transaction: &Transaction,
+    ) -> Result<bool, ServerError> {
+        let out_of_sync = self
+            .apply_transaction
+            .key(&cache_keys(id))
+            .arg(transaction.value()?)
+            .arg(balance.total)
+            .arg(serde_json::to_string(transaction)?)
+            .arg(self.statement_size)
+            .invoke_async(connection)
+            .await?;
+        Ok(out_of_sync)
     }
 }
 
-impl TransactionRepository for CachedDatabase<PostgresDatabase> {
+impl<T> TransactionRepository for CachedDatabase<T>
+where
+    T: TransactionRepository,
+{
     async fn add_transaction(
         &self,
         id: u32,
         transaction: Transaction,
-    ) -> Result<Balance, ServerError> {
+    ) -> Result<(Balance, Transaction), ServerError> {
         let mut connection = self.cache.get_async_connection().await?;
         let (total, limit): (Option<i32>, Option<i32>) = connection
             .mget(vec![
@@ -38,42 +169,294 @@ impl TransactionRepository for CachedDatabase<PostgresDatabase> {
             .await?;
 
         // early check if the transaction would exceed the limit
-        let tx_value = transaction.value();
+        let tx_value = transaction.value()?;
         if let (Some(total), Some(limit)) = (total, limit) {
-            if total + tx_value < -limit {
+            metrics::CACHE_LOOKUPS
+                .with_label_values(&["balance", "hit"])
+                .inc();
+            if (total as i64) + (tx_value as i64) < -(limit as i64) {
                 return Err(ServerError::TransactionWouldExceedLimit);
             }
+        } else {
+            metrics::CACHE_LOOKUPS
+                .with_label_values(&["balance", "miss"])
+                .inc();
         }
-        let balance = self.database.add_transaction(id, transaction).await?;
-
-        // update the cache
-        // set limit
-        if let None = limit {
-            connection
-                .set(format!("balance:{id}:limit"), balance.limit)
-                .await?;
-        }
+        let (balance, transaction) = self.database.add_transaction(id, transaction).await?;
 
-        match total {
-            // something is wrong, update the cache
-            Some(total) if total + tx_value != balance.total => {
-                println!("Cache is out of sync, updating");
-                connection
-                    .set(format!("balance:{id}:total"), balance.total)
-                    .await?
+        // the transaction is committed, the cache can no longer change the response
+        let cached = self.cache_transaction(&mut connection, id, &balance, &transaction).await;
+        match cached {
+            Ok(false) => {}
+            Ok(true) => {
+                tracing::warn!(id, "Cache is out of sync, dropped it");
+                metrics::CACHE_OUT_OF_SYNC.inc();
             }
-            _ => {
-                connection
-                    .incr(format!("balance:{id}:total"), tx_value)
-                    .await?
+            Err(e) => {
+                tracing::error!(id, "Failed to update the cached statement: {e}");
+                metrics::CACHE_OUT_OF_SYNC.inc();
+                let _ = self.drop_cached(&[id]).await;
             }
         }
 
+        Ok((balance, transaction))
+    }
+
+    async fn add_idempotent_transaction(
+        &self,
+        id: u32,
+        transaction: Transaction,
+        key: &str,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        // a retried request must reach the database even if the cached balance says
+        // it would exceed the limit, since the original may already be applied
+        let applied = self
+            .database
+            .add_idempotent_transaction(id, transaction, key)
+            .await?;
+
+        // we cannot tell a replay from a new transaction, drop the cached entries
+        self.invalidate(&[id]).await;
+
+        Ok(applied)
+    }
+
+    async fn add_transactions(
+        &self,
+        id: u32,
+        transactions: Vec<Transaction>,
+        mode: BatchMode,
+    ) -> Result<BatchOutcome, ServerError> {
+        let outcome = self.database.add_transactions(id, transactions, mode).await?;
+
+        self.invalidate(&[id]).await;
+
+        Ok(outcome)
+    }
+
+    async fn transfer(&self, id: u32, transfer: Transfer) -> Result<Balance, ServerError> {
+        let to = transfer.to;
+        let balance = self.database.transfer(id, transfer).await?;
+
+        self.invalidate(&[id, to]).await;
+
         Ok(balance)
     }
 
+    async fn reverse_transaction(
+        &self,
+        id: u32,
+        transaction_id: &str,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        let reversal = self.database.reverse_transaction(id, transaction_id).await?;
+
+        self.invalidate(&[id]).await;
+
+        Ok(reversal)
+    }
+
+    async fn get_transaction(
+        &self,
+        id: u32,
+        transaction_id: &str,
+    ) -> Result<Transaction, ServerError> {
+        self.database.get_transaction(id, transaction_id).await
+    }
+
+    async fn place_hold(&self, id: u32, hold: Hold) -> Result<(Balance, Hold), ServerError> {
+        let placed = self.database.place_hold(id, hold).await?;
+
+        self.invalidate(&[id]).await;
+
+        Ok(placed)
+    }
+
+    async fn capture_hold(
+        &self,
+        id: u32,
+        hold_id: &str,
+        value: Option<u32>,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        let captured = self.database.capture_hold(id, hold_id, value).await?;
+
+        self.invalidate(&[id]).await;
+
+        Ok(captured)
+    }
+
+    async fn release_hold(&self, id: u32, hold_id: &str) -> Result<(Balance, Hold), ServerError> {
+        let released = self.database.release_hold(id, hold_id).await?;
+
+        self.invalidate(&[id]).await;
+
+        Ok(released)
+    }
+
+    async fn expire_holds(&self) -> Result<Vec<u32>, ServerError> {
+        let expired = self.database.expire_holds().await?;
+
+        if !expired.is_empty() {
+            self.invalidate(&expired).await;
+        }
+
+        Ok(expired)
+    }
+
     async fn get_statement(&self, id: &u32) -> Result<Statement, ServerError> {
+        let mut connection = self.cache.get_async_connection().await?;
+        let (version, total, limit, held, cached, transactions): CachedStatement = redis::pipe()
+            .atomic()
+            .get(format!("statement:{id}:version"))
+            .get(format!("balance:{id}:total"))
+            .get(format!("balance:{id}:limit"))
+            .get(format!("balance:{id}:held"))
+            .exists(format!("statement:{id}:cached"))
+            .lrange(format!("statement:{id}:transactions"), 0, self.statement_size - 1)
+            .query_async(&mut connection)
+            .await?;
+
+        if let (Some(total), Some(limit), true) = (total, limit, cached) {
+            metrics::CACHE_LOOKUPS
+                .with_label_values(&["statement", "hit"])
+                .inc();
+            return Ok(Statement {
+                balance: Balance {
+                    total,
+                    limit,
+                    statement_date: Some(Utc::now()),
+                    held,
+                },
+                last_transactions: transactions
+                    .iter()
+                    .map(|transaction| serde_json::from_str(transaction))
+                    .collect::<Result<_, _>>()?,
+            });
+        }
+
+        // cache miss, load the statement from the database and cache it
+        metrics::CACHE_LOOKUPS
+            .with_label_values(&["statement", "miss"])
+            .inc();
         let stmt = self.database.get_statement(id).await?;
+        let transactions = stmt
+            .last_transactions
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+        // no held part is cached as a missing key
+        let held = stmt.balance.held.map(|held| held.to_string());
+        // skipped when a write happened since the version was read
+        self.cache_statement
+            .key(&cache_keys(*id))
+            .arg(version.unwrap_or(0))
+            .arg(self.statement_ttl)
+            .arg(stmt.balance.total)
+            .arg(stmt.balance.limit)
+            .arg(held.unwrap_or_default())
+            .arg(transactions)
+            .invoke_async::<_, ()>(&mut connection)
+            .await?;
+
         Ok(stmt)
     }
+
+    async fn get_history(&self, id: u32, query: HistoryQuery) -> Result<HistoryPage, ServerError> {
+        self.database.get_history(id, query).await
+    }
+
+    async fn create_account(&self, limit: u32) -> Result<Account, ServerError> {
+        self.database.create_account(limit).await
+    }
+
+    async fn seed_account(&self, id: u32, limit: u32) -> Result<bool, ServerError> {
+        self.database.seed_account(id, limit).await
+    }
+
+    async fn get_account(&self, id: u32) -> Result<Account, ServerError> {
+        self.database.get_account(id).await
+    }
+
+    async fn update_limit(&self, id: u32, limit: u32) -> Result<Account, ServerError> {
+        let account = self.database.update_limit(id, limit).await?;
+
+        // the cached limit is used to reject transactions early
+        self.invalidate(&[id]).await;
+
+        Ok(account)
+    }
+
+    async fn close_account(&self, id: u32) -> Result<Account, ServerError> {
+        self.database.close_account(id).await
+    }
+
+    async fn ping(&self) -> Result<(), ServerError> {
+        let mut connection = self.cache.get_async_connection().await?;
+        redis::cmd("PING").query_async::<_, ()>(&mut connection).await?;
+        self.database.ping().await
+    }
+
+    async fn close(&self) {
+        self.database.close().await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<AccountEvent> {
+        self.database.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::{MemoryDatabase, TransactionType};
+
+    use super::*;
+
+    const STATEMENT_SIZE: u32 = 10;
+
+    fn ids(statement: &Statement) -> Vec<Option<String>> {
+        statement
+            .last_transactions
+            .iter()
+            .map(|transaction| transaction.id.clone())
+            .collect()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis server at REDIS_URL"]
+    async fn should_match_the_database_after_writes() {
+        let redis = RedisClient::open(std::env::var("REDIS_URL").unwrap()).unwrap();
+        let memory = MemoryDatabase::new(STATEMENT_SIZE);
+        memory.seed_account(1, 100000).await.unwrap();
+        let cached = CachedDatabase::new(redis, memory.clone(), STATEMENT_SIZE, 60);
+        cached.drop_cached(&[1]).await.unwrap();
+
+        // loaded into the cache, then updated by the writes
+        cached.get_statement(&1).await.unwrap();
+        let writes = (1..=20).map(|value| {
+            let transaction = Transaction::new(value, TransactionType::Deposit, "c".to_string());
+            cached.add_transaction(1, transaction)
+        });
+        for result in futures_util::future::join_all(writes).await {
+            result.unwrap();
+        }
+
+        let expected = memory.get_statement(&1).await.unwrap();
+        let statement = cached.get_statement(&1).await.unwrap();
+        assert_eq!(statement.balance.total, expected.balance.total);
+        assert_eq!(statement.balance.limit, expected.balance.limit);
+        // writes reaching the cache out of order drop it instead of reordering it
+        assert_eq!(ids(&statement), ids(&expected));
+    }
+
+    #[test]
+    fn should_roundtrip_cached_transaction() {
+        let transaction = Transaction::new(1000, TransactionType::Withdraw, "debit".to_string());
+        let cached = serde_json::to_string(&transaction).unwrap();
+        let restored: Transaction = serde_json::from_str(&cached).unwrap();
+        assert_eq!(restored.value, transaction.value);
+        assert_eq!(restored.description, transaction.description);
+        assert_eq!(
+            restored.date.timestamp_millis(),
+            transaction.date.timestamp_millis()
+        );
+    }
 }
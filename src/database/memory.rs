@@ -0,0 +1,833 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use tokio::sync::broadcast;
+
+use crate::database::{
+    events_channel, Account, AccountEvent, Balance, BatchMode, BatchOutcome, HistoryPage,
+    HistoryQuery, Hold, Statement, Transaction, TransactionRepository, Transfer,
+};
+use crate::error::ServerError;
+
+#[derive(Clone)]
+pub struct MemoryDatabase(Arc<Mutex<MemoryStore>>);
+
+struct MemoryStore {
+    wallets: HashMap<u32, MemoryWallet>,
+    // idempotency key -> (request fingerprint, resulting balance and transaction)
+    idempotency_keys: HashMap<String, (String, (Balance, Transaction))>,
+    // shared by transactions and transfers
+    last_id: u64,
+    events: broadcast::Sender<AccountEvent>,
+    statement_size: usize,
+}
+
+struct MemoryWallet {
+    balance: Balance,
+    // most recent transaction first
+    transactions: VecDeque<Transaction>,
+    // pending holds, their sum being the balance held part
+    holds: Vec<Hold>,
+    closed_at: Option<DateTime<Utc>>,
+}
+
+impl MemoryWallet {
+    fn new(limit: u32, statement_size: usize) -> Self {
+        Self {
+            balance: Balance::new(limit),
+            transactions: VecDeque::with_capacity(statement_size),
+            holds: Vec::new(),
+            closed_at: None,
+        }
+    }
+
+    fn held(&self) -> i64 {
+        self.balance.held.unwrap_or_default() as i64
+    }
+
+    /// Same invariant as the wallet CHECK constraint: held - total <= limit
+    fn fits(&self, total: i32, held: i64) -> bool {
+        held - total as i64 <= self.balance.limit as i64
+    }
+
+    fn take_hold(&mut self, hold_id: &str) -> Result<Hold, ServerError> {
+        let position = self
+            .holds
+            .iter()
+            .position(|hold| hold.id.as_deref() == Some(hold_id))
+            .ok_or_else(|| ServerError::HoldNotFound(hold_id.to_string()))?;
+        let hold = self.holds.remove(position);
+        self.balance.held = Some((self.held() - hold.value as i64) as u32);
+        Ok(hold)
+    }
+
+    fn account(&self, id: u32) -> Account {
+        Account {
+            id,
+            limit: self.balance.limit,
+            total: self.balance.total,
+            closed_at: self.closed_at,
+        }
+    }
+}
+
+impl MemoryDatabase {
+    /// Starts without accounts, see `SEED_FILE`.
+    pub fn new(statement_size: u32) -> Self {
+        Self(Arc::new(Mutex::new(MemoryStore {
+            wallets: HashMap::new(),
+            idempotency_keys: HashMap::new(),
+            last_id: 0,
+            events: events_channel(),
+            statement_size: statement_size as usize,
+        })))
+    }
+}
+
+impl MemoryStore {
+    fn next_id(&mut self) -> String {
+        self.last_id += 1;
+        self.last_id.to_string()
+    }
+
+    fn add_transaction(
+        &mut self,
+        id: u32,
+        mut transaction: Transaction,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        let transaction_id = self.next_id();
+        let wallet = self
+            .wallets
+            .get_mut(&id)
+            .ok_or(ServerError::UserNotFound(id))?;
+        if wallet.closed_at.is_some() {
+            return Err(ServerError::AccountClosed);
+        }
+
+        let total = wallet
+            .balance
+            .total
+            .checked_add(transaction.value()?)
+            .filter(|&total| wallet.fits(total, wallet.held()))
+            .ok_or(ServerError::TransactionWouldExceedLimit)?;
+        wallet.balance.total = total;
+
+        if wallet.transactions.len() == self.statement_size {
+            wallet.transactions.pop_back();
+        }
+        transaction.id = Some(transaction_id);
+        wallet.transactions.push_front(transaction.clone());
+
+        let balance = Balance {
+            statement_date: Some(Utc::now()),
+            ..wallet.balance.clone()
+        };
+        // no subscribers is not an error
+        let _ = self
+            .events
+            .send(AccountEvent::new(id, &balance, &transaction));
+        Ok((balance, transaction))
+    }
+}
+
+impl TransactionRepository for MemoryDatabase {
+    async fn add_transaction(
+        &self,
+        id: u32,
+        transaction: Transaction,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        self.0.lock().unwrap().add_transaction(id, transaction)
+    }
+
+    async fn add_idempotent_transaction(
+        &self,
+        id: u32,
+        transaction: Transaction,
+        key: &str,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        let mut store = self.0.lock().unwrap();
+        let fingerprint = transaction.fingerprint(id);
+        if let Some((stored, applied)) = store.idempotency_keys.get(key) {
+            return match *stored == fingerprint {
+                true => Ok(applied.clone()),
+                false => Err(ServerError::IdempotencyKeyConflict),
+            };
+        }
+        let applied = store.add_transaction(id, transaction)?;
+        store
+            .idempotency_keys
+            .insert(key.to_string(), (fingerprint, applied.clone()));
+        Ok(applied)
+    }
+
+    async fn add_transactions(
+        &self,
+        id: u32,
+        transactions: Vec<Transaction>,
+        mode: BatchMode,
+    ) -> Result<BatchOutcome, ServerError> {
+        let mut store = self.0.lock().unwrap();
+        let wallet = store
+            .wallets
+            .get(&id)
+            .ok_or(ServerError::UserNotFound(id))?;
+        if mode == BatchMode::AllOrNothing {
+            // check the whole batch first, there is nothing to roll back
+            let mut total = wallet.balance.total;
+            for (index, transaction) in transactions.iter().enumerate() {
+                match total.checked_add(transaction.value()?) {
+                    Some(next) if wallet.fits(next, wallet.held()) => total = next,
+                    _ => {
+                        let e = ServerError::TransactionWouldExceedLimit;
+                        return Err(ServerError::BatchRejected(index, Box::new(e)));
+                    }
+                }
+            }
+        }
+        let mut results = Vec::with_capacity(transactions.len());
+        for (index, transaction) in transactions.into_iter().enumerate() {
+            match store.add_transaction(id, transaction) {
+                Ok((_, transaction)) => results.push(Ok(transaction)),
+                Err(e) => results.push(Err(mode.on_failure(index, e)?)),
+            }
+        }
+        let wallet = &store.wallets[&id];
+        Ok(BatchOutcome {
+            balance: Balance {
+                statement_date: Some(Utc::now()),
+                ..wallet.balance.clone()
+            },
+            results,
+        })
+    }
+
+    async fn transfer(&self, id: u32, transfer: Transfer) -> Result<Balance, ServerError> {
+        let mut store = self.0.lock().unwrap();
+        let credited = transfer.credit().value()?;
+        // everything that could refuse the credit is checked before the debit is applied
+        match store.wallets.get(&transfer.to) {
+            None => return Err(ServerError::UserNotFound(transfer.to)),
+            Some(payee) if payee.closed_at.is_some() => return Err(ServerError::AccountClosed),
+            Some(payee) if payee.balance.total.checked_add(credited).is_none() => {
+                return Err(ServerError::TransactionWouldExceedLimit)
+            }
+            Some(_) => {}
+        }
+        let transfer_id = Some(store.next_id());
+        let debit = Transaction {
+            transfer_id: transfer_id.clone(),
+            ..transfer.debit()
+        };
+        let credit = Transaction {
+            transfer_id,
+            ..transfer.credit()
+        };
+        let (balance, _) = store.add_transaction(id, debit)?;
+        store.add_transaction(transfer.to, credit)?;
+        Ok(balance)
+    }
+
+    async fn reverse_transaction(
+        &self,
+        id: u32,
+        transaction_id: &str,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        let mut store = self.0.lock().unwrap();
+        let wallet = store
+            .wallets
+            .get(&id)
+            .ok_or(ServerError::UserNotFound(id))?;
+        let original = wallet
+            .transactions
+            .iter()
+            .find(|transaction| transaction.id.as_deref() == Some(transaction_id))
+            .ok_or_else(|| ServerError::TransactionNotFound(transaction_id.to_string()))?;
+        if !original.is_reversible() {
+            return Err(ServerError::TransactionNotReversible);
+        }
+        let reversed = wallet
+            .transactions
+            .iter()
+            .any(|transaction| transaction.reverses.as_deref() == Some(transaction_id));
+        if reversed {
+            return Err(ServerError::TransactionAlreadyReversed);
+        }
+        let reversal = original.reversal();
+        store.add_transaction(id, reversal)
+    }
+
+    async fn get_transaction(
+        &self,
+        id: u32,
+        transaction_id: &str,
+    ) -> Result<Transaction, ServerError> {
+        let store = self.0.lock().unwrap();
+        let wallet = store
+            .wallets
+            .get(&id)
+            .ok_or(ServerError::UserNotFound(id))?;
+        wallet
+            .transactions
+            .iter()
+            .find(|transaction| transaction.id.as_deref() == Some(transaction_id))
+            .cloned()
+            .ok_or_else(|| ServerError::TransactionNotFound(transaction_id.to_string()))
+    }
+
+    async fn place_hold(&self, id: u32, mut hold: Hold) -> Result<(Balance, Hold), ServerError> {
+        let mut store = self.0.lock().unwrap();
+        let hold_id = store.next_id();
+        let wallet = store
+            .wallets
+            .get_mut(&id)
+            .ok_or(ServerError::UserNotFound(id))?;
+        if wallet.closed_at.is_some() {
+            return Err(ServerError::AccountClosed);
+        }
+        let held = wallet.held() + hold.value as i64;
+        if !wallet.fits(wallet.balance.total, held) {
+            return Err(ServerError::TransactionWouldExceedLimit);
+        }
+        wallet.balance.held = Some(held as u32);
+        hold.id = Some(hold_id);
+        wallet.holds.push(hold.clone());
+        Ok((
+            Balance {
+                statement_date: Some(Utc::now()),
+                ..wallet.balance.clone()
+            },
+            hold,
+        ))
+    }
+
+    async fn capture_hold(
+        &self,
+        id: u32,
+        hold_id: &str,
+        value: Option<u32>,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        let mut store = self.0.lock().unwrap();
+        let wallet = store
+            .wallets
+            .get_mut(&id)
+            .ok_or(ServerError::UserNotFound(id))?;
+        let hold = wallet
+            .holds
+            .iter()
+            .find(|hold| hold.id.as_deref() == Some(hold_id) && !hold.is_expired())
+            .ok_or_else(|| ServerError::HoldNotFound(hold_id.to_string()))?;
+        let transaction = hold.capture(value)?;
+        if wallet.closed_at.is_some() {
+            return Err(ServerError::AccountClosed);
+        }
+        // the debit always fits in the limit the hold releases
+        wallet.take_hold(hold_id)?;
+        store.add_transaction(id, transaction)
+    }
+
+    async fn release_hold(&self, id: u32, hold_id: &str) -> Result<(Balance, Hold), ServerError> {
+        let mut store = self.0.lock().unwrap();
+        let wallet = store
+            .wallets
+            .get_mut(&id)
+            .ok_or(ServerError::UserNotFound(id))?;
+        let hold = wallet.take_hold(hold_id)?;
+        Ok((
+            Balance {
+                statement_date: Some(Utc::now()),
+                ..wallet.balance.clone()
+            },
+            hold,
+        ))
+    }
+
+    async fn expire_holds(&self) -> Result<Vec<u32>, ServerError> {
+        let mut store = self.0.lock().unwrap();
+        let mut expired = Vec::new();
+        for (id, wallet) in store.wallets.iter_mut() {
+            let holds: Vec<_> = wallet
+                .holds
+                .iter()
+                .filter(|hold| hold.is_expired())
+                .filter_map(|hold| hold.id.clone())
+                .collect();
+            for hold_id in &holds {
+                wallet.take_hold(hold_id)?;
+            }
+            if !holds.is_empty() {
+                expired.push(*id);
+            }
+        }
+        Ok(expired)
+    }
+
+    async fn get_statement(&self, id: &u32) -> Result<Statement, ServerError> {
+        let store = self.0.lock().unwrap();
+        let wallet = store
+            .wallets
+            .get(id)
+            .ok_or(ServerError::UserNotFound(*id))?;
+        Ok(Statement {
+            balance: Balance {
+                statement_date: Some(Utc::now()),
+                ..wallet.balance.clone()
+            },
+            last_transactions: wallet.transactions.iter().cloned().collect(),
+        })
+    }
+
+    async fn get_history(&self, id: u32, query: HistoryQuery) -> Result<HistoryPage, ServerError> {
+        let store = self.0.lock().unwrap();
+        let wallet = store
+            .wallets
+            .get(&id)
+            .ok_or(ServerError::UserNotFound(id))?;
+        HistoryPage::from_window(wallet.transactions.iter().cloned(), &query)
+    }
+
+    async fn create_account(&self, limit: u32) -> Result<Account, ServerError> {
+        let mut store = self.0.lock().unwrap();
+        let id = store.wallets.keys().max().copied().unwrap_or(0) + 1;
+        let wallet = MemoryWallet::new(limit, store.statement_size);
+        let account = wallet.account(id);
+        store.wallets.insert(id, wallet);
+        Ok(account)
+    }
+
+    async fn seed_account(&self, id: u32, limit: u32) -> Result<bool, ServerError> {
+        let mut store = self.0.lock().unwrap();
+        if store.wallets.contains_key(&id) {
+            return Ok(false);
+        }
+        let wallet = MemoryWallet::new(limit, store.statement_size);
+        store.wallets.insert(id, wallet);
+        Ok(true)
+    }
+
+    async fn get_account(&self, id: u32) -> Result<Account, ServerError> {
+        let store = self.0.lock().unwrap();
+        let wallet = store
+            .wallets
+            .get(&id)
+            .ok_or(ServerError::UserNotFound(id))?;
+        Ok(wallet.account(id))
+    }
+
+    async fn update_limit(&self, id: u32, limit: u32) -> Result<Account, ServerError> {
+        let mut store = self.0.lock().unwrap();
+        let wallet = store
+            .wallets
+            .get_mut(&id)
+            .ok_or(ServerError::UserNotFound(id))?;
+        if wallet.closed_at.is_some() {
+            return Err(ServerError::AccountClosed);
+        }
+        if wallet.held() - wallet.balance.total as i64 > limit as i64 {
+            return Err(ServerError::LimitBelowBalance);
+        }
+        wallet.balance.limit = limit;
+        Ok(wallet.account(id))
+    }
+
+    async fn close_account(&self, id: u32) -> Result<Account, ServerError> {
+        let mut store = self.0.lock().unwrap();
+        let wallet = store
+            .wallets
+            .get_mut(&id)
+            .ok_or(ServerError::UserNotFound(id))?;
+        wallet.closed_at.get_or_insert_with(Utc::now);
+        Ok(wallet.account(id))
+    }
+
+    async fn ping(&self) -> Result<(), ServerError> {
+        Ok(())
+    }
+
+    async fn close(&self) {}
+
+    fn subscribe(&self) -> broadcast::Receiver<AccountEvent> {
+        self.0.lock().unwrap().events.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::TransactionType;
+
+    use super::*;
+
+    const STATEMENT_SIZE: u32 = 10;
+
+    /// The accounts of the original challenge.
+    async fn seeded() -> MemoryDatabase {
+        let database = MemoryDatabase::new(STATEMENT_SIZE);
+        for (id, limit) in (1..).zip([100000, 80000, 1000000, 10000000, 500000]) {
+            database.seed_account(id, limit).await.unwrap();
+        }
+        database
+    }
+
+    #[tokio::test]
+    async fn should_not_exceed_limit() {
+        let database = seeded().await;
+        let transaction = Transaction::new(100001, TransactionType::Withdraw, "debit".to_string());
+        let result = database.add_transaction(1, transaction).await;
+        assert!(matches!(
+            result,
+            Err(ServerError::TransactionWouldExceedLimit)
+        ));
+
+        let transaction = Transaction::new(100000, TransactionType::Withdraw, "debit".to_string());
+        let (balance, _) = database.add_transaction(1, transaction).await.unwrap();
+        assert_eq!(balance.total, -100000);
+    }
+
+    #[tokio::test]
+    async fn should_reject_total_overflow() {
+        let database = seeded().await;
+        let deposit = || {
+            Transaction::new(
+                i32::MAX as u32,
+                TransactionType::Deposit,
+                "credit".to_string(),
+            )
+        };
+        database.add_transaction(1, deposit()).await.unwrap();
+        let result = database.add_transaction(1, deposit()).await;
+        assert!(matches!(
+            result,
+            Err(ServerError::TransactionWouldExceedLimit)
+        ));
+        assert_eq!(
+            database.get_statement(&1).await.unwrap().balance.total,
+            i32::MAX
+        );
+
+        // would wrap to a credit of 1, or overflow when negated
+        for value in [4294967295, 2147483648] {
+            let withdraw = Transaction::new(value, TransactionType::Withdraw, "debit".to_string());
+            let result = database.add_transaction(1, withdraw).await;
+            assert!(matches!(result, Err(ServerError::ValueOutOfRange(_))));
+        }
+        assert_eq!(
+            database.get_statement(&1).await.unwrap().balance.total,
+            i32::MAX
+        );
+    }
+
+    #[tokio::test]
+    async fn should_not_debit_when_the_credit_fails() {
+        let database = seeded().await;
+        let deposit = Transaction::new(
+            i32::MAX as u32,
+            TransactionType::Deposit,
+            "credit".to_string(),
+        );
+        database.add_transaction(2, deposit).await.unwrap();
+
+        let result = database
+            .transfer(1, Transfer::new(2, 1, "transfer".to_string()))
+            .await;
+        assert!(matches!(
+            result,
+            Err(ServerError::TransactionWouldExceedLimit)
+        ));
+        let statement = database.get_statement(&1).await.unwrap();
+        assert_eq!(statement.balance.total, 0);
+        assert!(statement.last_transactions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_keep_last_transactions() {
+        let database = seeded().await;
+        for value in 1..=12 {
+            let transaction =
+                Transaction::new(value, TransactionType::Deposit, "credit".to_string());
+            database.add_transaction(2, transaction).await.unwrap();
+        }
+
+        let stmt = database.get_statement(&2).await.unwrap();
+        assert_eq!(stmt.balance.total, 78);
+        assert_eq!(stmt.last_transactions.len(), STATEMENT_SIZE as usize);
+        assert_eq!(stmt.last_transactions[0].value, 12);
+        assert_eq!(stmt.last_transactions[9].value, 3);
+    }
+
+    #[tokio::test]
+    async fn should_apply_idempotency_key_once() {
+        let database = seeded().await;
+        let transaction = || Transaction::new(1000, TransactionType::Withdraw, "debit".to_string());
+        let (first, stored) = database
+            .add_idempotent_transaction(1, transaction(), "key")
+            .await
+            .unwrap();
+        let (retry, replayed) = database
+            .add_idempotent_transaction(1, transaction(), "key")
+            .await
+            .unwrap();
+        assert_eq!(first.total, -1000);
+        assert_eq!(retry.total, -1000);
+        assert_eq!(replayed.id, stored.id);
+
+        let other = Transaction::new(2000, TransactionType::Withdraw, "debit".to_string());
+        let result = database.add_idempotent_transaction(1, other, "key").await;
+        assert!(matches!(result, Err(ServerError::IdempotencyKeyConflict)));
+    }
+
+    #[tokio::test]
+    async fn should_apply_batches() {
+        let database = seeded().await;
+        let batch = || {
+            vec![
+                Transaction::new(50000, TransactionType::Withdraw, "first".to_string()),
+                Transaction::new(40000, TransactionType::Withdraw, "second".to_string()),
+            ]
+        };
+        let result = database
+            .add_transactions(2, batch(), BatchMode::AllOrNothing)
+            .await;
+        assert!(matches!(result, Err(ServerError::BatchRejected(1, _))));
+        let stmt = database.get_statement(&2).await.unwrap();
+        assert_eq!(stmt.balance.total, 0);
+        assert!(stmt.last_transactions.is_empty());
+
+        let outcome = database
+            .add_transactions(2, batch(), BatchMode::BestEffort)
+            .await
+            .unwrap();
+        assert_eq!(outcome.balance.total, -50000);
+        assert!(outcome.results[0].is_ok());
+        assert!(matches!(
+            outcome.results[1],
+            Err(ServerError::TransactionWouldExceedLimit)
+        ));
+    }
+
+    #[tokio::test]
+    async fn should_publish_transactions() {
+        let database = seeded().await;
+        let mut events = database.subscribe();
+        let transfer = Transfer::new(2, 1000, "transfer".to_string());
+        database.transfer(1, transfer).await.unwrap();
+        let batch = vec![Transaction::new(
+            90000,
+            TransactionType::Withdraw,
+            "rejected".to_string(),
+        )];
+        let result = database
+            .add_transactions(2, batch, BatchMode::AllOrNothing)
+            .await;
+        assert!(result.is_err());
+
+        let debit = events.try_recv().unwrap();
+        assert_eq!((debit.wallet_id, debit.balance.total), (1, -1000));
+        let credit = events.try_recv().unwrap();
+        assert_eq!((credit.wallet_id, credit.balance.total), (2, 1000));
+        assert_eq!(
+            debit.transaction.transfer_id,
+            credit.transaction.transfer_id
+        );
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn should_transfer_between_wallets() {
+        let database = seeded().await;
+        let transfer = Transfer::new(3, 100001, "transfer".to_string());
+        let result = database.transfer(1, transfer).await;
+        assert!(matches!(
+            result,
+            Err(ServerError::TransactionWouldExceedLimit)
+        ));
+
+        let transfer = Transfer::new(3, 500, "transfer".to_string());
+        let balance = database.transfer(1, transfer).await.unwrap();
+        assert_eq!(balance.total, -500);
+        let stmt = database.get_statement(&3).await.unwrap();
+        assert_eq!(stmt.balance.total, 500);
+        assert_eq!(stmt.last_transactions.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn should_manage_accounts() {
+        let database = seeded().await;
+        let account = database.create_account(1000).await.unwrap();
+        assert_eq!(account.id, 6);
+        assert_eq!(account.total, 0);
+
+        let transaction = Transaction::new(800, TransactionType::Withdraw, "debit".to_string());
+        database.add_transaction(6, transaction).await.unwrap();
+        let result = database.update_limit(6, 500).await;
+        assert!(matches!(result, Err(ServerError::LimitBelowBalance)));
+        let account = database.update_limit(6, 800).await.unwrap();
+        assert_eq!(account.limit, 800);
+
+        let account = database.close_account(6).await.unwrap();
+        assert!(account.closed_at.is_some());
+        let transaction = Transaction::new(100, TransactionType::Deposit, "credit".to_string());
+        let result = database.add_transaction(6, transaction).await;
+        assert!(matches!(result, Err(ServerError::AccountClosed)));
+        let result = database
+            .transfer(1, Transfer::new(6, 100, "transfer".to_string()))
+            .await;
+        assert!(matches!(result, Err(ServerError::AccountClosed)));
+        assert_eq!(
+            database.get_statement(&6).await.unwrap().balance.total,
+            -800
+        );
+    }
+
+    #[tokio::test]
+    async fn should_page_through_history() {
+        let database = seeded().await;
+        let date = Utc::now();
+        for value in 1..=5 {
+            let transaction_type = match value % 2 {
+                0 => TransactionType::Withdraw,
+                _ => TransactionType::Deposit,
+            };
+            // same date for all of them, told apart by their position
+            let transaction = Transaction {
+                date,
+                ..Transaction::new(value, transaction_type, format!("tx {value}"))
+            };
+            database.add_transaction(1, transaction).await.unwrap();
+        }
+
+        let mut query = HistoryQuery {
+            from: None,
+            to: None,
+            transaction_type: None,
+            description: None,
+            cursor: None,
+            limit: 2,
+        };
+        let mut values = Vec::new();
+        loop {
+            let page = database.get_history(1, query.clone()).await.unwrap();
+            values.extend(page.transactions.iter().map(|t| t.value));
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(values, vec![5, 4, 3, 2, 1]);
+
+        query.cursor = None;
+        query.transaction_type = Some(TransactionType::Deposit);
+        query.description = Some("TX".to_string());
+        let page = database.get_history(1, query).await.unwrap();
+        let values: Vec<_> = page.transactions.iter().map(|t| t.value).collect();
+        assert_eq!(values, vec![5, 3]);
+        assert!(page.next_cursor.is_some());
+    }
+
+    #[tokio::test]
+    async fn should_reverse_transaction_once() {
+        let database = seeded().await;
+        let transaction = Transaction::new(1000, TransactionType::Deposit, "credit".to_string());
+        database.add_transaction(1, transaction).await.unwrap();
+        let transaction = Transaction::new(101000, TransactionType::Withdraw, "debit".to_string());
+        database.add_transaction(1, transaction).await.unwrap();
+
+        let stmt = database.get_statement(&1).await.unwrap();
+        let debit = stmt.last_transactions[0].id.clone().unwrap();
+        let credit = stmt.last_transactions[1].id.clone().unwrap();
+        // taking the credit back would exceed the limit
+        let result = database.reverse_transaction(1, &credit).await;
+        assert!(matches!(
+            result,
+            Err(ServerError::TransactionWouldExceedLimit)
+        ));
+
+        let (balance, reversal) = database.reverse_transaction(1, &debit).await.unwrap();
+        assert_eq!(balance.total, 1000);
+        assert_eq!(reversal.reverses, Some(debit.clone()));
+        let result = database.reverse_transaction(1, &debit).await;
+        assert!(matches!(
+            result,
+            Err(ServerError::TransactionAlreadyReversed)
+        ));
+        let result = database
+            .reverse_transaction(1, reversal.id.as_deref().unwrap())
+            .await;
+        assert!(matches!(result, Err(ServerError::TransactionNotReversible)));
+        let result = database.reverse_transaction(1, "unknown").await;
+        assert!(matches!(result, Err(ServerError::TransactionNotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn should_find_transaction_by_id() {
+        let database = seeded().await;
+        let transaction = Transaction::new(1000, TransactionType::Deposit, "credit".to_string());
+        let (_, stored) = database.add_transaction(1, transaction).await.unwrap();
+
+        let id = stored.id.unwrap();
+        let found = database.get_transaction(1, &id).await.unwrap();
+        assert_eq!(found.id, Some(id.clone()));
+        assert_eq!(found.value, 1000);
+        let result = database.get_transaction(2, &id).await;
+        assert!(matches!(result, Err(ServerError::TransactionNotFound(_))));
+        let result = database.get_transaction(6, &id).await;
+        assert!(matches!(result, Err(ServerError::UserNotFound(6))));
+    }
+
+    #[tokio::test]
+    async fn should_capture_and_release_holds() {
+        let database = seeded().await;
+        let hold = Hold::new(60000, "hold".to_string(), chrono::Duration::days(7));
+        let (balance, hold) = database.place_hold(1, hold).await.unwrap();
+        assert_eq!((balance.total, balance.held), (0, Some(60000)));
+        // only what the hold left of the limit can be used
+        let transaction = Transaction::new(40001, TransactionType::Withdraw, "debit".to_string());
+        let result = database.add_transaction(1, transaction).await;
+        assert!(matches!(
+            result,
+            Err(ServerError::TransactionWouldExceedLimit)
+        ));
+
+        let hold_id = hold.id.unwrap();
+        let result = database.capture_hold(1, &hold_id, Some(60001)).await;
+        assert!(matches!(result, Err(ServerError::CaptureExceedsHold)));
+        let (balance, debit) = database
+            .capture_hold(1, &hold_id, Some(50000))
+            .await
+            .unwrap();
+        assert_eq!((balance.total, balance.held), (-50000, Some(0)));
+        assert_eq!(debit.description, "hold");
+        let result = database.release_hold(1, &hold_id).await;
+        assert!(matches!(result, Err(ServerError::HoldNotFound(_))));
+
+        let hold = Hold::new(1000, "hold".to_string(), chrono::Duration::days(7));
+        let (_, hold) = database.place_hold(1, hold).await.unwrap();
+        let (balance, _) = database
+            .release_hold(1, hold.id.as_deref().unwrap())
+            .await
+            .unwrap();
+        assert_eq!((balance.total, balance.held), (-50000, Some(0)));
+    }
+
+    #[tokio::test]
+    async fn should_expire_holds() {
+        let database = seeded().await;
+        let hold = Hold::new(1000, "hold".to_string(), chrono::Duration::zero());
+        let (_, hold) = database.place_hold(2, hold).await.unwrap();
+        let result = database
+            .capture_hold(2, hold.id.as_deref().unwrap(), None)
+            .await;
+        assert!(matches!(result, Err(ServerError::HoldNotFound(_))));
+
+        assert_eq!(database.expire_holds().await.unwrap(), vec![2]);
+        let stmt = database.get_statement(&2).await.unwrap();
+        assert_eq!(stmt.balance.held, Some(0));
+        assert!(database.expire_holds().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn should_return_user_not_found() {
+        let database = seeded().await;
+        let result = database.get_statement(&6).await;
+        assert!(matches!(result, Err(ServerError::UserNotFound(6))));
+    }
+}
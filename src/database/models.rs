@@ -1,6 +1,8 @@
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use crate::error::ServerError;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Statement {
     #[serde(rename = "saldo")]
@@ -9,14 +11,26 @@ pub struct Statement {
     pub last_transactions: Vec<Transaction>,
 }
 
+/// Converts a value to the `i32` totals are kept in, instead of letting it wrap.
+pub fn to_i32(value: u32) -> Result<i32, ServerError> {
+    i32::try_from(value).map_err(|_| ServerError::ValueOutOfRange(value))
+}
+
 impl Balance {
     pub fn new(limit: u32) -> Self {
         Self {
             total: 0,
             statement_date: None,
             limit,
+            held: Some(0),
         }
     }
+
+    /// What is left for debits and new holds: the limit plus the posted balance,
+    /// minus the held part.
+    pub fn available(&self) -> i64 {
+        self.limit as i64 + self.total as i64 - self.held.unwrap_or_default() as i64
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
@@ -26,10 +40,31 @@ pub struct Balance {
     pub statement_date: Option<chrono::DateTime<Utc>>,
     #[serde(rename = "limite")]
     pub limit: u32,
+    /// Part of the limit reserved by pending holds, `total` being the posted balance
+    /// and `total - held` the available one. Only known where the balance is read
+    /// along with the holds.
+    #[serde(rename = "reservado", default, skip_serializing_if = "Option::is_none")]
+    pub held: Option<u32>,
+}
+
+/// A wallet as managed through the account endpoints. Closed accounts keep their
+/// history but reject new transactions.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Account {
+    pub id: u32,
+    #[serde(rename = "limite")]
+    pub limit: u32,
+    #[serde(rename = "saldo")]
+    pub total: i32,
+    #[serde(rename = "encerrada_em")]
+    pub closed_at: Option<chrono::DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Transaction {
+    /// Set once the transaction is stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
     #[serde(rename = "valor")]
     pub value: u32,
     #[serde(rename = "tipo")]
@@ -39,27 +74,328 @@ pub struct Transaction {
     #[serde(rename = "realizada_em")]
     #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
     pub date: chrono::DateTime<Utc>,
+    /// Transaction compensated by this one.
+    #[serde(rename = "estorno_de")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reverses: Option<String>,
+    /// Transfer this transaction is a leg of.
+    #[serde(rename = "transferencia")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transfer_id: Option<String>,
 }
 
 impl Transaction {
     pub fn new(value: u32, transaction_type: TransactionType, description: String) -> Self {
         Self {
+            id: None,
             value,
             transaction_type,
             description,
             date: Utc::now(),
+            reverses: None,
+            transfer_id: None,
+        }
+    }
+
+    /// Compensating entry of this transaction, with the opposite type.
+    pub fn reversal(&self) -> Transaction {
+        let transaction_type = match self.transaction_type {
+            TransactionType::Deposit => TransactionType::Withdraw,
+            TransactionType::Withdraw => TransactionType::Deposit,
+        };
+        Transaction {
+            reverses: self.id.clone(),
+            ..Transaction::new(self.value, transaction_type, self.description.clone())
+        }
+    }
+
+    /// Reversals and transfer legs cannot be reversed, the latter would only
+    /// compensate one side of the transfer.
+    pub fn is_reversible(&self) -> bool {
+        self.reverses.is_none() && self.transfer_id.is_none()
+    }
+
+    /// Signed value applied to the total, refused when it does not fit in it.
+    pub fn value(&self) -> Result<i32, ServerError> {
+        let value = to_i32(self.value)?;
+        Ok(match self.transaction_type {
+            TransactionType::Deposit => value,
+            TransactionType::Withdraw => -value,
+        })
+    }
+
+    /// Identifies the request that created this transaction, used to detect an
+    /// idempotency key being reused with a different payload.
+    pub fn fingerprint(&self, id: u32) -> String {
+        format!(
+            "{id}:{}:{}:{}",
+            self.transaction_type.as_str(),
+            self.value,
+            self.description
+        )
+    }
+}
+
+/// Part of the limit reserved without posting a debit, until it is captured,
+/// released or expires.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Hold {
+    /// Set once the hold is stored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(rename = "valor")]
+    pub value: u32,
+    #[serde(rename = "descricao")]
+    pub description: String,
+    #[serde(rename = "criada_em")]
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "expira_em")]
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Hold {
+    pub fn new(value: u32, description: String, ttl: chrono::Duration) -> Self {
+        let created_at = Utc::now();
+        Self {
+            id: None,
+            value,
+            description,
+            created_at,
+            expires_at: created_at + ttl,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }
+
+    /// Debit posted when `value` of the hold is captured, all of it when `None`.
+    pub fn capture(&self, value: Option<u32>) -> Result<Transaction, ServerError> {
+        let value = value.unwrap_or(self.value);
+        if value > self.value {
+            return Err(ServerError::CaptureExceedsHold);
+        }
+        Ok(Transaction::new(
+            value,
+            TransactionType::Withdraw,
+            self.description.clone(),
+        ))
+    }
+}
+
+/// Money moved from one wallet to another, recorded as a debit in the payer
+/// statement and a credit in the payee statement.
+#[derive(Debug, Clone)]
+pub struct Transfer {
+    pub to: u32,
+    pub value: u32,
+    pub description: String,
+    pub date: chrono::DateTime<Utc>,
+}
+
+impl Transfer {
+    pub fn new(to: u32, value: u32, description: String) -> Self {
+        Self {
+            to,
+            value,
+            description,
+            date: Utc::now(),
+        }
+    }
+
+    pub fn debit(&self) -> Transaction {
+        Transaction {
+            date: self.date,
+            ..Transaction::new(
+                self.value,
+                TransactionType::Withdraw,
+                self.description.clone(),
+            )
+        }
+    }
+
+    pub fn credit(&self) -> Transaction {
+        Transaction {
+            date: self.date,
+            ..Transaction::new(
+                self.value,
+                TransactionType::Deposit,
+                self.description.clone(),
+            )
+        }
+    }
+}
+
+/// A transaction applied to a wallet, along with the balance it left.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AccountEvent {
+    pub wallet_id: u32,
+    pub balance: Balance,
+    pub transaction: Transaction,
+}
+
+impl AccountEvent {
+    pub fn new(wallet_id: u32, balance: &Balance, transaction: &Transaction) -> Self {
+        Self {
+            wallet_id,
+            balance: balance.clone(),
+            transaction: transaction.clone(),
+        }
+    }
+}
+
+/// How a batch of transactions is applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum BatchMode {
+    /// A transaction that would exceed the limit rolls back the whole batch.
+    #[default]
+    #[serde(rename = "tudo_ou_nada")]
+    AllOrNothing,
+    /// Transactions that would exceed the limit are skipped and reported.
+    #[serde(rename = "melhor_esforco")]
+    BestEffort,
+}
+
+impl BatchMode {
+    /// What the failure of the transaction at `index` means for the batch: `Ok` with
+    /// the error to report when the batch goes on, `Err` when it stops.
+    pub fn on_failure(self, index: usize, e: ServerError) -> Result<ServerError, ServerError> {
+        match (self, e) {
+            (BatchMode::BestEffort, e @ ServerError::TransactionWouldExceedLimit) => Ok(e),
+            (BatchMode::AllOrNothing, e @ ServerError::TransactionWouldExceedLimit) => {
+                Err(ServerError::BatchRejected(index, Box::new(e)))
+            }
+            (_, e) => Err(e),
+        }
+    }
+}
+
+/// Balance after a batch, with the result of each transaction in the order they
+/// were submitted.
+#[derive(Debug)]
+pub struct BatchOutcome {
+    pub balance: Balance,
+    pub results: Vec<Result<Transaction, ServerError>>,
+}
+
+/// Filters of a wallet history page. `from` is inclusive and `to` exclusive.
+#[derive(Debug, Clone)]
+pub struct HistoryQuery {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub transaction_type: Option<TransactionType>,
+    /// Case-insensitive substring of the description.
+    pub description: Option<String>,
+    /// Last transaction of the previous page.
+    pub cursor: Option<HistoryCursor>,
+    pub limit: u32,
+}
+
+impl HistoryQuery {
+    fn matches(&self, transaction: &Transaction) -> bool {
+        self.from.is_none_or(|from| transaction.date >= from)
+            && self.to.is_none_or(|to| transaction.date < to)
+            && self
+                .transaction_type
+                .is_none_or(|transaction_type| transaction_type == transaction.transaction_type)
+            && self.description.as_ref().is_none_or(|description| {
+                let description = description.to_lowercase();
+                transaction
+                    .description
+                    .to_lowercase()
+                    .contains(&description)
+            })
+    }
+}
+
+/// Position in a wallet history, transactions are walked from the newest to the oldest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryCursor {
+    pub date: DateTime<Utc>,
+    /// Breaks ties between transactions at the same date, its meaning is up to the backend.
+    pub id: String,
+}
+
+impl HistoryCursor {
+    pub fn new(date: DateTime<Utc>, id: impl ToString) -> Self {
+        Self {
+            date,
+            id: id.to_string(),
         }
     }
 
-    pub fn value(&self) -> i32 {
-        match self.transaction_type {
-            TransactionType::Deposit => self.value as i32,
-            TransactionType::Withdraw => -(self.value as i32),
+    pub fn encode(&self) -> String {
+        format!("{}.{}", self.date.timestamp_micros(), self.id)
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, ServerError> {
+        let (date, id) = cursor.split_once('.').ok_or(ServerError::InvalidCursor)?;
+        let date = date
+            .parse()
+            .ok()
+            .and_then(DateTime::from_timestamp_micros)
+            .ok_or(ServerError::InvalidCursor)?;
+        Ok(Self::new(date, id))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryPage {
+    pub transactions: Vec<Transaction>,
+    /// Set when there may be older transactions.
+    pub next_cursor: Option<HistoryCursor>,
+}
+
+impl HistoryPage {
+    /// Pages through the transactions a backend keeps in memory, newest first.
+    /// Transactions at the same date are told apart by their position among them.
+    pub fn from_window(
+        transactions: impl IntoIterator<Item = Transaction>,
+        query: &HistoryQuery,
+    ) -> Result<Self, ServerError> {
+        let cursor = match &query.cursor {
+            Some(cursor) => {
+                let position: usize = cursor.id.parse().map_err(|_| ServerError::InvalidCursor)?;
+                Some((cursor.date.timestamp_micros(), position))
+            }
+            None => None,
+        };
+        let mut page = Self {
+            transactions: Vec::new(),
+            next_cursor: None,
+        };
+        // (date, position among the transactions at that date) of every transaction
+        let mut key = (i64::MAX, 0);
+        let mut last_position = 0;
+        for transaction in transactions {
+            let date = transaction.date.timestamp_micros();
+            key = match key.0 == date {
+                true => (date, key.1 + 1),
+                false => (date, 0),
+            };
+            let after_cursor = cursor.is_none_or(|(cursor_date, cursor_position)| {
+                date < cursor_date || (date == cursor_date && key.1 > cursor_position)
+            });
+            if !after_cursor || !query.matches(&transaction) {
+                continue;
+            }
+            if page.transactions.len() == query.limit as usize {
+                page.next_cursor = page
+                    .transactions
+                    .last()
+                    .map(|last| HistoryCursor::new(last.date, last_position));
+                break;
+            }
+            page.transactions.push(transaction);
+            last_position = key.1;
         }
+        Ok(page)
     }
 }
 
-#[derive(sqlx::Type, Debug, Serialize, Deserialize, Clone)]
+#[derive(sqlx::Type, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[sqlx(type_name = "transaction_type")]
 pub enum TransactionType {
     #[serde(rename = "c")]
@@ -69,3 +405,12 @@ pub enum TransactionType {
     #[sqlx(rename = "d")]
     Withdraw,
 }
+
+impl TransactionType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "c",
+            TransactionType::Withdraw => "d",
+        }
+    }
+}
@@ -1,107 +1,963 @@
+use std::time::Duration;
+
 use bson::Document;
-use futures_util::StreamExt;
-use mongodb::{Client, Database, IndexModel};
+use clap::ValueEnum;
+use futures_util::{StreamExt, TryStreamExt};
+use mongodb::{Client, ClientSession, Database, IndexModel};
 use mongodb::bson::{Bson, doc};
-use mongodb::options::{CreateCollectionOptions, FindOneAndUpdateOptions, IndexOptions, ReturnDocument, ValidationAction, ValidationLevel};
+use mongodb::error::{ErrorKind, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT, WriteFailure};
+use mongodb::options::{CreateCollectionOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions, IndexOptions, ReturnDocument, ValidationAction, ValidationLevel};
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
 
-use crate::database::{Balance, models, Statement, Transaction, TransactionRepository, TransactionType};
+use crate::database::{events_channel, Account, AccountEvent, Balance, BatchMode, BatchOutcome, HistoryCursor, LISTEN_RETRY_INTERVAL, HistoryPage, HistoryQuery, Hold, models, Statement, Transaction, TransactionRepository, TransactionType, Transfer};
 use crate::error::ServerError;
 
 #[derive(Clone)]
 pub struct MongoDatabase {
     client: Client,
     database: Database,
+    schema: MongoSchema,
+    /// Whether the server has multi-document transactions, which transfers need.
+    transactions: bool,
+    events: broadcast::Sender<AccountEvent>,
+    statement_size: i32,
+}
+
+/// Where the transactions of a wallet are stored.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MongoSchema {
+    /// One document per transaction in the transactions collection, written in
+    /// the same multi-document transaction as the balance. Requires a replica set.
+    Collection,
+    /// The last transactions are kept inside the balance document, so every write
+    /// is a single-document operation. Works on standalone servers.
+    Embedded,
+}
+
+impl std::fmt::Display for MongoSchema {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.to_possible_value() {
+            Some(value) => f.write_str(value.get_name()),
+            None => Ok(()),
+        }
+    }
 }
 
 impl MongoDatabase {
-    pub async fn new(client: Client) -> Self {
-        let database = client.default_database().expect("no default database");
-        // assure that total cannot be less than -limit
-        let validator = doc! {
-            "$expr": doc! {
-                "$gte": [
-                    "$balance.total",
-                    doc! {"$multiply": [ "$balance.limite", -1]},
-                ]
+    /// Expects the collections created by `migrate`. Account events are streamed from
+    /// the transactions collection, so the embedded schema cannot share them between
+    /// instances and refuses them.
+    pub async fn new(client: Client, schema: Option<MongoSchema>, statement_size: u32, account_events: bool) -> Result<Self, ServerError> {
+        let database = default_database(&client)?;
+        let schema = schema_for(&database, schema).await?;
+        let transactions = supports_transactions(&database).await?;
+        let events = events_channel();
+        match schema {
+            MongoSchema::Collection if account_events => _ = tokio::spawn(listen(database.clone(), events.clone())),
+            MongoSchema::Embedded if account_events => {
+                return Err(ServerError::Unsupported("account events need the collection schema"));
             }
-        };
-        let validation_opts = CreateCollectionOptions::builder().validator(validator).validation_action(Some(ValidationAction::Error)).validation_level(Some(ValidationLevel::Strict)).build();
-        database.create_collection(BALANCE, validation_opts).await.expect("failed to create collection");
-        let collection = database.collection::<Statement>(BALANCE);
-        let opts = IndexOptions::builder().unique(true).build();
-        let model = IndexModel::builder().keys(doc! {"id": 1}).options(Some(opts)).build();
-        collection.create_index(model, None).await.expect("failed to create index");
-        migrate(&client).await;
-        Self { client, database }
+            _ => {}
+        }
+        Ok(Self { client, database, schema, transactions, events, statement_size: statement_size as i32 })
     }
-}
 
-const BALANCE: &str = "balances";
-const TRANSACTIONS: &str = "transactions";
+    /// Creates the collections and indexes, then copies the last transactions into the
+    /// balances when the embedded schema is used. Applied migrations are skipped.
+    pub async fn migrate(client: &Client, schema: Option<MongoSchema>, statement_size: u32) -> Result<(), ServerError> {
+        let database = default_database(client)?;
+        let migration = database.collection::<Document>("migration");
+        for name in pending_migrations(&database, schema).await? {
+            match name {
+                SCHEMA_MIGRATION => create_collections(&database).await?,
+                EMBEDDED_MIGRATION => migrate_embedded(&database, statement_size as i32).await?,
+                _ => unreachable!("unknown migration {name}"),
+            }
+            let mut marker = doc! {"migration": name};
+            if name == SCHEMA_MIGRATION {
+                // the data is only readable with the schema it was written with
+                marker.insert("schema", schema_for(&database, schema).await?.to_string());
+            }
+            migration.insert_one(marker, None).await?;
+            tracing::info!(migration = name, "Applied migration");
+        }
+        Ok(())
+    }
 
-impl TransactionRepository for MongoDatabase {
-    async fn add_transaction(&self, id: u32, transaction: Transaction) -> Result<Balance, ServerError> {
+    /// Names of the migrations the schema needs that were not applied yet.
+    pub async fn pending_migrations(client: &Client, schema: Option<MongoSchema>) -> Result<Vec<&'static str>, ServerError> {
+        let database = default_database(client)?;
+        pending_migrations(&database, schema).await
+    }
+
+    /// Runs the write in a multi-document transaction, retrying transient errors up to
+    /// `SESSION_ATTEMPTS` times.
+    async fn run_in_session(&self, id: u32, write: SessionWrite<'_>) -> Result<Balance, ServerError> {
+        self.run_session(id, write).await.map(|(balance, _)| balance)
+    }
+
+    /// Same as `run_in_session`, also returning the indexes of the batch transactions
+    /// skipped for not fitting in the limit.
+    async fn run_session(&self, id: u32, write: SessionWrite<'_>) -> Result<(Balance, Vec<usize>), ServerError> {
+        let mut session = self.client.start_session(None).await?;
+        let mut attempt = 0;
+        'transaction: loop {
+            attempt += 1;
+            session.start_transaction(None).await?;
+            let result = match write {
+                SessionWrite::Transaction(transaction, key) => self.write_transaction(&mut session, id, transaction, key).await.map(|balance| (balance, Vec::new())),
+                SessionWrite::Transfer(transfer) => match self.schema {
+                    MongoSchema::Collection => self.write_transfer(&mut session, id, transfer).await,
+                    MongoSchema::Embedded => self.write_embedded_transfer(&mut session, id, transfer).await,
+                }.map(|balance| (balance, Vec::new())),
+                SessionWrite::Capture(hold, transaction) => self.write_capture(&mut session, id, hold, transaction).await.map(|balance| (balance, Vec::new())),
+                SessionWrite::Batch(transactions, mode) => self.write_batch(&mut session, id, transactions, mode).await,
+            };
+            let written = match result {
+                Ok(written) => written,
+                Err(ServerError::MongoError(e)) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < SESSION_ATTEMPTS => {
+                    let _ = session.abort_transaction().await;
+                    backoff(attempt).await;
+                    continue 'transaction;
+                }
+                Err(e) => {
+                    let _ = session.abort_transaction().await;
+                    return Err(e);
+                }
+            };
+            loop {
+                match session.commit_transaction().await {
+                    Ok(()) => return Ok(written),
+                    Err(e) if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && attempt < SESSION_ATTEMPTS => {
+                        attempt += 1;
+                        backoff(attempt).await;
+                    }
+                    Err(e) if e.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < SESSION_ATTEMPTS => {
+                        backoff(attempt).await;
+                        continue 'transaction;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+    }
+
+    async fn write_transaction(&self, session: &mut ClientSession, id: u32, transaction: &Transaction, key: Option<&MongoIdempotencyKey>) -> Result<Balance, ServerError> {
         let collection = self.database.collection::<MongoBalance>(BALANCE);
-        let tx_value = transaction.value();
         let opts = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
-        let value = collection.find_one_and_update(
-            doc! {"id": id},
-            doc! { "$inc": { "balance.total": tx_value }},
+        let value = collection.find_one_and_update_with_session(
+            open_wallet(id),
+            doc! { "$inc": { "balance.total": transaction.value()? }},
             Some(opts),
+            session,
+        ).await.map_err(map_limit_error)?;
+        let Some(mut balance) = value else {
+            return Err(self.closed_or_missing(id).await);
+        };
+
+        let collection = self.database.collection::<MongoTransaction>(TRANSACTIONS);
+        collection.insert_one_with_session(
+            MongoTransaction {
+                _id: object_id(transaction)?,
+                wallet_id: id,
+                transaction: transaction.clone(),
+                transfer_id: None,
+                balance: Some(balance.balance.clone()),
+            },
+            None,
+            session,
         ).await?;
-        let mut balance = value.ok_or(ServerError::UserNotFound(id))?;
+
+        if let Some(key) = key {
+            let collection = self.database.collection::<MongoIdempotencyKey>(IDEMPOTENCY_KEYS);
+            let key = MongoIdempotencyKey {
+                balance: Some(balance.balance.clone()),
+                transaction: Some(transaction.clone()),
+                ..key.clone()
+            };
+            collection.insert_one_with_session(key, None, session).await?;
+        }
         balance.balance.statement_date = Some(chrono::Utc::now());
+        Ok(balance.balance)
+    }
 
+    async fn write_transfer(&self, session: &mut ClientSession, id: u32, transfer: &Transfer) -> Result<Balance, ServerError> {
+        let collection = self.database.collection::<MongoBalance>(BALANCE);
+        let mut updates = [(id, transfer.debit()), (transfer.to, transfer.credit())];
+        // same update order for every transfer between two wallets
+        updates.sort_by_key(|(wallet_id, _)| *wallet_id);
+        let mut balances = Vec::with_capacity(updates.len());
+        for (wallet_id, transaction) in &updates {
+            let opts = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+            let value = collection.find_one_and_update_with_session(
+                open_wallet(*wallet_id),
+                doc! { "$inc": { "balance.total": transaction.value()? }},
+                Some(opts),
+                &mut *session,
+            ).await.map_err(map_limit_error)?;
+            let Some(balance) = value else {
+                return Err(self.closed_or_missing(*wallet_id).await);
+            };
+            balances.push(balance.balance);
+        }
+        let payer = updates.iter().zip(&balances).find(|((wallet_id, _), _)| *wallet_id == id).map(|(_, balance)| balance.clone());
+
+        let transfer_id = bson::oid::ObjectId::new();
         let collection = self.database.collection::<MongoTransaction>(TRANSACTIONS);
-        collection.insert_one(
-            MongoTransaction {
+        collection.insert_many_with_session(
+            updates.into_iter().zip(balances).map(|((wallet_id, transaction), balance)| MongoTransaction {
                 _id: bson::oid::ObjectId::new(),
-                wallet_id: id,
+                wallet_id,
                 transaction,
+                transfer_id: Some(transfer_id),
+                balance: Some(balance),
+            }),
+            None,
+            session,
+        ).await?;
+
+        let mut balance = payer.ok_or(ServerError::UserNotFound(id))?;
+        balance.statement_date = Some(chrono::Utc::now());
+        Ok(balance)
+    }
+
+    /// Pushes the debit and the credit into the last transactions of both wallets.
+    async fn write_embedded_transfer(&self, session: &mut ClientSession, id: u32, transfer: &Transfer) -> Result<Balance, ServerError> {
+        let collection = self.database.collection::<MongoBalance>(BALANCE);
+        let transfer_id = Some(bson::oid::ObjectId::new().to_hex());
+        let mut updates = [(id, transfer.debit()), (transfer.to, transfer.credit())].map(|(wallet_id, transaction)| {
+            (wallet_id, Transaction { id: Some(bson::oid::ObjectId::new().to_hex()), transfer_id: transfer_id.clone(), ..transaction })
+        });
+        // same update order for every transfer between two wallets
+        updates.sort_by_key(|(wallet_id, _)| *wallet_id);
+        let mut payer = None;
+        for (wallet_id, transaction) in updates {
+            let opts = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .projection(doc! {EMBEDDED_TRANSACTIONS: 0, HOLDS: 0})
+                .build();
+            let value = collection.find_one_and_update_with_session(
+                open_wallet(wallet_id),
+                doc! {
+                    "$inc": { "balance.total": transaction.value()? },
+                    "$push": {
+                        EMBEDDED_TRANSACTIONS: {
+                            "$each": [transaction.clone()],
+                            "$position": 0,
+                            "$slice": self.statement_size,
+                        }
+                    },
+                },
+                Some(opts),
+                &mut *session,
+            ).await.map_err(map_limit_error)?;
+            let Some(mut balance) = value else {
+                return Err(self.closed_or_missing(wallet_id).await);
+            };
+            if wallet_id == id {
+                balance.balance.statement_date = Some(chrono::Utc::now());
+                payer = Some(balance.balance);
+            }
+        }
+        payer.ok_or(ServerError::UserNotFound(id))
+    }
+
+    /// Best effort batches check each transaction against the balance read in the
+    /// session and skip the ones over the limit, since a refused write would abort the
+    /// whole transaction.
+    async fn write_batch(&self, session: &mut ClientSession, id: u32, transactions: &[Transaction], mode: BatchMode) -> Result<(Balance, Vec<usize>), ServerError> {
+        let mut wallet = None;
+        if let BatchMode::BestEffort = mode {
+            let collection = self.database.collection::<MongoBalance>(BALANCE);
+            let opts = FindOneOptions::builder().projection(doc! {HOLDS: 0}).build();
+            match collection.find_one_with_session(open_wallet(id), opts, &mut *session).await? {
+                Some(found) => wallet = Some(found.balance),
+                None => return Err(self.closed_or_missing(id).await),
+            }
+        }
+        let mut available = wallet.as_ref().map(Balance::available);
+        let mut balance = None;
+        let mut skipped = Vec::new();
+        for (index, transaction) in transactions.iter().enumerate() {
+            if let Some(available) = available.as_mut() {
+                let value = transaction.value()? as i64;
+                if *available + value < 0 {
+                    skipped.push(index);
+                    continue;
+                }
+                *available += value;
+            }
+            let applied = self.write_transaction(&mut *session, id, transaction, None).await.map_err(|e| match e {
+                ServerError::TransactionWouldExceedLimit => ServerError::BatchRejected(index, Box::new(e)),
+                e => e,
+            })?;
+            balance = Some(applied);
+        }
+        let balance = match balance.or(wallet) {
+            Some(mut balance) => {
+                balance.statement_date = Some(chrono::Utc::now());
+                balance
+            }
+            None => return Err(ServerError::UserNotFound(id)),
+        };
+        Ok((balance, skipped))
+    }
+
+    async fn write_capture(&self, session: &mut ClientSession, id: u32, hold: &Hold, transaction: &Transaction) -> Result<Balance, ServerError> {
+        let collection = self.database.collection::<MongoBalance>(BALANCE);
+        let opts = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).projection(doc! {HOLDS: 0}).build();
+        let value = collection.find_one_and_update_with_session(
+            pending_hold(id, hold),
+            doc! {
+                "$inc": { "balance.total": transaction.value()?, "balance.reservado": -(hold.value as i64) },
+                "$pull": { HOLDS: {"id": &hold.id} },
+            },
+            Some(opts),
+            session,
+        ).await.map_err(map_limit_error)?;
+        let Some(mut balance) = value else {
+            return Err(self.hold_gone(id, hold).await);
+        };
+
+        let collection = self.database.collection::<MongoTransaction>(TRANSACTIONS);
+        collection.insert_one_with_session(
+            MongoTransaction {
+                _id: object_id(transaction)?,
+                wallet_id: id,
+                transaction: transaction.clone(),
+                transfer_id: None,
+                balance: Some(balance.balance.clone()),
             },
             None,
+            session,
         ).await?;
+        balance.balance.statement_date = Some(chrono::Utc::now());
         Ok(balance.balance)
     }
 
-    async fn get_statement(&self, id: &u32) -> Result<Statement, ServerError> {
+    /// The capture is a single update, pushing the debit into the last transactions.
+    async fn embedded_capture(&self, id: u32, hold: &Hold, transaction: &Transaction) -> Result<Balance, ServerError> {
         let collection = self.database.collection::<MongoBalance>(BALANCE);
-        let balance_match = doc! {
-            "$match": doc! {
-                "id": id
+        let opts = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .projection(doc! {EMBEDDED_TRANSACTIONS: 0, HOLDS: 0})
+            .build();
+        let value = collection.find_one_and_update(
+            pending_hold(id, hold),
+            doc! {
+                "$inc": { "balance.total": transaction.value()?, "balance.reservado": -(hold.value as i64) },
+                "$pull": { HOLDS: {"id": &hold.id} },
+                "$push": {
+                    EMBEDDED_TRANSACTIONS: {
+                        "$each": [transaction.clone()],
+                        "$position": 0,
+                        "$slice": self.statement_size,
+                    }
+                },
+            },
+            Some(opts),
+        ).await.map_err(map_limit_error)?;
+        match value {
+            Some(mut balance) => {
+                balance.balance.statement_date = Some(chrono::Utc::now());
+                Ok(balance.balance)
             }
-        };
-        let transactions_lookup = doc! {
-            "$lookup": {
-                "from": TRANSACTIONS,
-                "as": "transactions",
-                "let": doc! {"wallet_id": "$id"},
-                "pipeline": vec![
-                    doc! {
-                        "$match": doc! {
-                            "$expr": doc! {
-                                "$eq": ["$wallet_id", "$$wallet_id"]
-                            }
+            None => Err(self.hold_gone(id, hold).await),
+        }
+    }
+
+    /// The batch is a single update, only matching while the lowest running total of
+    /// the batch stays within the limit.
+    async fn embedded_batch(&self, id: u32, transactions: &[Transaction]) -> Result<Balance, ServerError> {
+        let values = transactions.iter().map(Transaction::value).collect::<Result<Vec<_>, _>>()?;
+        let running_totals: Vec<i64> = values.iter().scan(0, |total, &value| {
+            *total += value as i64;
+            Some(*total)
+        }).collect();
+        let lowest = running_totals.iter().copied().min().unwrap_or_default();
+        let mut filter = open_wallet(id);
+        filter.insert("$expr", doc! {
+            "$gte": [
+                doc! {"$subtract": [doc! {"$add": ["$balance.total", lowest]}, doc! {"$ifNull": ["$balance.reservado", 0]}]},
+                doc! {"$multiply": ["$balance.limite", -1]},
+            ]
+        });
+        // the last transactions are kept most recent first
+        let pushed: Vec<Transaction> = transactions.iter().rev().cloned().collect();
+        let collection = self.database.collection::<MongoBalance>(BALANCE);
+        loop {
+            let opts = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .projection(doc! {EMBEDDED_TRANSACTIONS: 0, HOLDS: 0})
+                .build();
+            let value = collection.find_one_and_update(
+                filter.clone(),
+                doc! {
+                    "$inc": { "balance.total": running_totals.last().copied().unwrap_or_default() },
+                    "$push": {
+                        EMBEDDED_TRANSACTIONS: {
+                            "$each": pushed.clone(),
+                            "$position": 0,
+                            "$slice": self.statement_size,
                         }
                     },
-                    doc! {
-                        "$sort": doc! {
-                            "transaction.realizada_em": -1
+                },
+                Some(opts),
+            ).await.map_err(map_limit_error)?;
+            if let Some(mut balance) = value {
+                balance.balance.statement_date = Some(chrono::Utc::now());
+                return Ok(balance.balance);
+            }
+            // tell which transaction does not fit, unless the wallet changed in between
+            let opts = FindOneOptions::builder().projection(doc! {EMBEDDED_TRANSACTIONS: 0, HOLDS: 0}).build();
+            let Some(wallet) = collection.find_one(open_wallet(id), opts).await? else {
+                return Err(self.closed_or_missing(id).await);
+            };
+            let available = wallet.balance.available();
+            if let Some(index) = running_totals.iter().position(|total| available + total < 0) {
+                return Err(ServerError::BatchRejected(index, Box::new(ServerError::TransactionWouldExceedLimit)));
+            }
+        }
+    }
+
+    /// Picks the transactions that fit in the balance read and applies them in a single
+    /// update, only matching while the wallet still holds that balance.
+    async fn embedded_best_effort_batch(&self, id: u32, transactions: &[Transaction]) -> Result<(Balance, Vec<usize>), ServerError> {
+        let values = transactions.iter().map(Transaction::value).collect::<Result<Vec<_>, _>>()?;
+        let collection = self.database.collection::<MongoBalance>(BALANCE);
+        loop {
+            let opts = FindOneOptions::builder().projection(doc! {EMBEDDED_TRANSACTIONS: 0, HOLDS: 0}).build();
+            let Some(wallet) = collection.find_one(open_wallet(id), opts).await? else {
+                return Err(self.closed_or_missing(id).await);
+            };
+            let mut available = wallet.balance.available();
+            let mut total = 0i64;
+            let mut skipped = Vec::new();
+            let mut pushed = Vec::with_capacity(transactions.len());
+            for (index, (transaction, &value)) in transactions.iter().zip(&values).enumerate() {
+                let value = value as i64;
+                if available + value < 0 {
+                    skipped.push(index);
+                    continue;
+                }
+                available += value;
+                total += value;
+                pushed.push(transaction.clone());
+            }
+            if pushed.is_empty() {
+                let mut balance = wallet.balance;
+                balance.statement_date = Some(chrono::Utc::now());
+                return Ok((balance, skipped));
+            }
+            // the last transactions are kept most recent first
+            pushed.reverse();
+            let mut filter = open_wallet(id);
+            filter.insert("balance.total", wallet.balance.total);
+            filter.insert("balance.limite", wallet.balance.limit as i64);
+            filter.insert("$expr", doc! {"$eq": [doc! {"$ifNull": ["$balance.reservado", 0]}, wallet.balance.held.unwrap_or_default() as i64]});
+            let opts = FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .projection(doc! {EMBEDDED_TRANSACTIONS: 0, HOLDS: 0})
+                .build();
+            let value = collection.find_one_and_update(
+                filter,
+                doc! {
+                    "$inc": { "balance.total": total },
+                    "$push": {
+                        EMBEDDED_TRANSACTIONS: {
+                            "$each": pushed,
+                            "$position": 0,
+                            "$slice": self.statement_size,
                         }
                     },
-                    doc! {
-                        "$limit": 10
-                    },
-                    doc! {
-                        "$replaceRoot": doc! {
-                            "newRoot": "$transaction"
-                        }
+                },
+                Some(opts),
+            ).await.map_err(map_limit_error)?;
+            // otherwise the wallet changed since it was read, so the batch is picked again
+            if let Some(mut balance) = value {
+                balance.balance.statement_date = Some(chrono::Utc::now());
+                return Ok((balance.balance, skipped));
+            }
+        }
+    }
+
+    /// Reads a pending hold, expired or not.
+    async fn get_hold(&self, id: u32, hold_id: &str) -> Result<Hold, ServerError> {
+        let collection = self.database.collection::<MongoHolds>(BALANCE);
+        let opts = FindOneOptions::builder().projection(doc! {HOLDS: 1}).build();
+        let wallet = collection.find_one(doc! {"id": id}, opts).await?.ok_or(ServerError::UserNotFound(id))?;
+        wallet.holds.into_iter().find(|hold| hold.id.as_deref() == Some(hold_id)).ok_or_else(|| ServerError::HoldNotFound(hold_id.to_string()))
+    }
+
+    /// Releases the hold unless it was already captured or released, returning the
+    /// wallet balance.
+    async fn remove_hold(&self, id: u32, hold: &Hold) -> Result<Option<Balance>, ServerError> {
+        let collection = self.database.collection::<MongoBalance>(BALANCE);
+        let opts = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .projection(doc! {EMBEDDED_TRANSACTIONS: 0, HOLDS: 0})
+            .build();
+        let value = collection.find_one_and_update(
+            doc! {"id": id, format!("{HOLDS}.id"): &hold.id},
+            doc! {
+                "$inc": { "balance.reservado": -(hold.value as i64) },
+                "$pull": { HOLDS: {"id": &hold.id} },
+            },
+            Some(opts),
+        ).await?;
+        Ok(value.map(|mut balance| {
+            balance.balance.statement_date = Some(chrono::Utc::now());
+            balance.balance
+        }))
+    }
+
+    /// Tells why a hold could not be captured, once the pending hold filter matched nothing.
+    async fn hold_gone(&self, id: u32, hold: &Hold) -> ServerError {
+        let collection = self.database.collection::<MongoBalance>(BALANCE);
+        match collection.count_documents(open_wallet(id), None).await {
+            Ok(0) => self.closed_or_missing(id).await,
+            Ok(_) => ServerError::HoldNotFound(hold.id.clone().unwrap_or_default()),
+            Err(e) => e.into(),
+        }
+    }
+
+    /// Returns the balance and transaction stored with the key, failing if it belongs to
+    /// a different request or if the request that created it is still being applied.
+    /// Keys stored before transactions were kept with them return the request instead.
+    async fn find_idempotency_key(&self, key: &str, fingerprint: &str, transaction: &Transaction) -> Result<Option<(Balance, Transaction)>, ServerError> {
+        let collection = self.database.collection::<MongoIdempotencyKey>(IDEMPOTENCY_KEYS);
+        match collection.find_one(doc! {"_id": key}, None).await? {
+            Some(stored) if stored.fingerprint != fingerprint => Err(ServerError::IdempotencyKeyConflict),
+            Some(MongoIdempotencyKey { balance: None, .. }) => Err(ServerError::IdempotencyKeyConflict),
+            Some(MongoIdempotencyKey { balance: Some(mut balance), transaction: stored, .. }) => {
+                balance.statement_date = Some(chrono::Utc::now());
+                let stored = stored.unwrap_or_else(|| Transaction { id: None, ..transaction.clone() });
+                Ok(Some((balance, stored)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Without multi-document transactions the key is reserved before applying the
+    /// transaction and released again if it fails.
+    async fn add_embedded_idempotent_transaction(&self, id: u32, transaction: Transaction, key: MongoIdempotencyKey) -> Result<(Balance, Transaction), ServerError> {
+        let collection = self.database.collection::<MongoIdempotencyKey>(IDEMPOTENCY_KEYS);
+        collection.insert_one(&key, None).await?;
+        let (balance, transaction) = match self.add_embedded_transaction(id, transaction).await {
+            Ok(applied) => applied,
+            Err(e) => {
+                collection.delete_one(doc! {"_id": &key._id}, None).await?;
+                return Err(e);
+            }
+        };
+        collection.update_one(doc! {"_id": &key._id}, doc! {"$set": {"balance": balance.clone(), "transaction": transaction.clone()}}, None).await?;
+        Ok((balance, transaction))
+    }
+
+    async fn add_embedded_transaction(&self, id: u32, transaction: Transaction) -> Result<(Balance, Transaction), ServerError> {
+        match self.push_embedded_transaction(open_wallet(id), transaction).await? {
+            Some(applied) => Ok(applied),
+            None => Err(self.closed_or_missing(id).await),
+        }
+    }
+
+    /// Applies the transaction to the wallet matched by `filter`, returning `None` when
+    /// nothing matched.
+    async fn push_embedded_transaction(&self, filter: Document, mut transaction: Transaction) -> Result<Option<(Balance, Transaction)>, ServerError> {
+        transaction.id.get_or_insert_with(|| bson::oid::ObjectId::new().to_hex());
+        let collection = self.database.collection::<MongoBalance>(BALANCE);
+        let opts = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .projection(doc! {EMBEDDED_TRANSACTIONS: 0})
+            .build();
+        let value = collection.find_one_and_update(
+            filter,
+            doc! {
+                "$inc": { "balance.total": transaction.value()? },
+                "$push": {
+                    EMBEDDED_TRANSACTIONS: {
+                        "$each": [transaction.clone()],
+                        "$position": 0,
+                        "$slice": self.statement_size,
                     }
-                ]
+                },
+            },
+            Some(opts),
+        ).await.map_err(map_limit_error)?;
+        Ok(value.map(|mut balance| {
+            balance.balance.statement_date = Some(chrono::Utc::now());
+            (balance.balance, transaction)
+        }))
+    }
+
+    async fn reverse_collection_transaction(&self, id: u32, transaction_id: &str) -> Result<(Balance, Transaction), ServerError> {
+        let original = self.get_transaction(id, transaction_id).await?;
+        if !original.is_reversible() {
+            return Err(ServerError::TransactionNotReversible);
+        }
+        let reversal = Transaction {
+            id: Some(bson::oid::ObjectId::new().to_hex()),
+            ..original.reversal()
+        };
+        match self.run_in_session(id, SessionWrite::Transaction(&reversal, None)).await {
+            Ok(balance) => Ok((balance, reversal)),
+            Err(ServerError::MongoError(e)) if error_code(&e) == Some(DUPLICATE_KEY) => Err(ServerError::TransactionAlreadyReversed),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Only the last transactions kept in the wallet can be reversed. The update only
+    /// matches while none of them reverses the original.
+    async fn reverse_embedded_transaction(&self, id: u32, transaction_id: &str) -> Result<(Balance, Transaction), ServerError> {
+        let original = self.get_transaction(id, transaction_id).await?;
+        if !original.is_reversible() {
+            return Err(ServerError::TransactionNotReversible);
+        }
+        let reversal = original.reversal();
+        let mut filter = open_wallet(id);
+        filter.insert(EMBEDDED_TRANSACTIONS, doc! {"$not": {"$elemMatch": {"estorno_de": transaction_id}}});
+        if let Some(applied) = self.push_embedded_transaction(filter, reversal).await? {
+            return Ok(applied);
+        }
+        let collection = self.database.collection::<MongoBalance>(BALANCE);
+        let reversed = doc! {"id": id, format!("{EMBEDDED_TRANSACTIONS}.estorno_de"): transaction_id};
+        match collection.count_documents(reversed, None).await? {
+            0 => Err(self.closed_or_missing(id).await),
+            _ => Err(ServerError::TransactionAlreadyReversed),
+        }
+    }
+
+    /// Tells why a wallet could not be updated, once the open wallet filter matched nothing.
+    async fn closed_or_missing(&self, id: u32) -> ServerError {
+        let collection = self.database.collection::<MongoBalance>(BALANCE);
+        match collection.count_documents(doc! {"id": id}, None).await {
+            Ok(0) => ServerError::UserNotFound(id),
+            Ok(_) => ServerError::AccountClosed,
+            Err(e) => e.into(),
+        }
+    }
+
+    async fn get_embedded_statement(&self, id: &u32) -> Result<Statement, ServerError> {
+        let collection = self.database.collection::<MongoEmbeddedStatement>(BALANCE);
+        let stmt = collection.find_one(doc! {"id": id}, None::<FindOneOptions>).await?;
+        let mut stmt = stmt.ok_or(ServerError::UserNotFound(*id))?;
+        stmt.balance.statement_date = Some(chrono::Utc::now());
+        Ok(Statement {
+            balance: stmt.balance,
+            last_transactions: stmt.transactions,
+        })
+    }
+}
+
+/// Forwards the transactions inserted by every instance to the local subscribers.
+async fn listen(database: Database, events: broadcast::Sender<AccountEvent>) {
+    loop {
+        if let Err(e) = forward_changes(&database, &events).await {
+            tracing::error!("Failed to watch {TRANSACTIONS}: {e}");
+        }
+        tokio::time::sleep(LISTEN_RETRY_INTERVAL).await;
+    }
+}
+
+async fn forward_changes(database: &Database, events: &broadcast::Sender<AccountEvent>) -> Result<(), ServerError> {
+    let collection = database.collection::<MongoTransaction>(TRANSACTIONS);
+    let mut changes = collection.watch([doc! {"$match": {"operationType": "insert"}}], None).await?;
+    while let Some(change) = changes.try_next().await? {
+        let Some(stored) = change.full_document else { continue };
+        // transactions stored before they were streamed have no balance
+        let Some(balance) = stored.balance.clone() else { continue };
+        // no subscribers is not an error
+        let _ = events.send(AccountEvent { wallet_id: stored.wallet_id, balance, transaction: stored.into() });
+    }
+    Ok(())
+}
+
+const SESSION_ATTEMPTS: u32 = 5;
+const SESSION_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Sleeps a random part of a window doubling on every attempt, so conflicting
+/// writers do not retry in lockstep.
+async fn backoff(attempt: u32) {
+    let window = SESSION_BACKOFF * 2u32.pow(attempt - 1);
+    tokio::time::sleep(window.mul_f64(rand::random::<f64>())).await;
+}
+
+/// Without an explicit schema, sessions are used whenever the server supports them.
+async fn resolve_schema(database: &Database, schema: Option<MongoSchema>) -> Result<MongoSchema, ServerError> {
+    let sessions = supports_transactions(database).await?;
+    match schema {
+        Some(MongoSchema::Collection) if !sessions => Err(ServerError::SchemaMismatch(
+            "the collection schema needs a replica set or a sharded cluster".to_string(),
+        )),
+        Some(schema) => Ok(schema),
+        None if sessions => Ok(MongoSchema::Collection),
+        None => Ok(MongoSchema::Embedded),
+    }
+}
+
+/// The resolved schema, refused when the stored data was written with the other one:
+/// neither schema keeps the transactions written with the other up to date.
+async fn schema_for(database: &Database, schema: Option<MongoSchema>) -> Result<MongoSchema, ServerError> {
+    let schema = resolve_schema(database, schema).await?;
+    match stored_schema(database).await? {
+        Some(stored) if stored != schema => Err(ServerError::SchemaMismatch(format!(
+            "the data was written with the {stored} schema and cannot be switched to the {schema} one"
+        ))),
+        _ => Ok(schema),
+    }
+}
+
+/// Schema recorded by the first `migrate`. Databases set up before it was recorded
+/// only copied the transactions into the balances when switching to embedded.
+async fn stored_schema(database: &Database) -> Result<Option<MongoSchema>, ServerError> {
+    let migration = database.collection::<Document>("migration");
+    if let Some(marker) = migration.find_one(doc! {"migration": SCHEMA_MIGRATION}, None).await? {
+        let stored = marker.get_str("schema").unwrap_or_default();
+        return MongoSchema::from_str(stored, false)
+            .map(Some)
+            .map_err(|_| ServerError::SchemaMismatch(format!("unknown stored schema {stored:?}")));
+    }
+    if migration.find_one(doc! {"migration": EMBEDDED_MIGRATION}, None).await?.is_some() {
+        return Ok(Some(MongoSchema::Embedded));
+    }
+    Ok(None)
+}
+
+/// Multi-document transactions are only available on replica sets and sharded clusters.
+async fn supports_transactions(database: &Database) -> Result<bool, ServerError> {
+    let hello = database.run_command(doc! {"hello": 1}, None).await?;
+    Ok(hello.contains_key("setName") || hello.get_str("msg") == Ok("isdbgrid"))
+}
+
+const NAMESPACE_EXISTS: i32 = 48;
+const DOCUMENT_VALIDATION_FAILURE: i32 = 121;
+const DUPLICATE_KEY: i32 = 11000;
+
+fn error_code(e: &mongodb::error::Error) -> Option<i32> {
+    match e.kind.as_ref() {
+        ErrorKind::Command(err) => Some(err.code),
+        ErrorKind::Write(WriteFailure::WriteError(err)) => Some(err.code),
+        _ => None,
+    }
+}
+
+/// The balances collection validator rejects a total below -limit.
+fn map_limit_error(e: mongodb::error::Error) -> ServerError {
+    match error_code(&e) {
+        Some(DOCUMENT_VALIDATION_FAILURE) => ServerError::TransactionWouldExceedLimit,
+        _ => e.into(),
+    }
+}
+
+/// Escapes a text to be matched literally by `$regex`.
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Document id of a transaction, taken from the transaction when it already has one.
+fn object_id(transaction: &Transaction) -> Result<bson::oid::ObjectId, ServerError> {
+    match &transaction.id {
+        Some(id) => bson::oid::ObjectId::parse_str(id).map_err(|_| ServerError::TransactionNotFound(id.clone())),
+        None => Ok(bson::oid::ObjectId::new()),
+    }
+}
+
+/// Matches the wallet only while it is open and the hold has not expired.
+fn pending_hold(id: u32, hold: &Hold) -> Document {
+    let mut filter = open_wallet(id);
+    filter.insert(HOLDS, doc! {"$elemMatch": {"id": &hold.id, "expira_em": {"$gt": bson::DateTime::now()}}});
+    filter
+}
+
+/// Matches the wallet only while it is open, closed wallets reject every update.
+fn open_wallet(id: u32) -> Document {
+    doc! {"id": id, "closed_at": null}
+}
+
+const BALANCE: &str = "balances";
+const TRANSACTIONS: &str = "transactions";
+const IDEMPOTENCY_KEYS: &str = "idempotency_keys";
+const EMBEDDED_TRANSACTIONS: &str = "ultimas_transacoes";
+/// Pending holds, kept in the balance document with both schemas.
+const HOLDS: &str = "reservas";
+/// Seconds an idempotency key is remembered.
+const IDEMPOTENCY_KEY_TTL: u64 = 24 * 60 * 60;
+
+impl TransactionRepository for MongoDatabase {
+    async fn add_transaction(&self, id: u32, transaction: Transaction) -> Result<(Balance, Transaction), ServerError> {
+        let transaction = Transaction { id: Some(bson::oid::ObjectId::new().to_hex()), ..transaction };
+        match self.schema {
+            MongoSchema::Collection => {
+                let balance = self.run_in_session(id, SessionWrite::Transaction(&transaction, None)).await?;
+                Ok((balance, transaction))
+            }
+            MongoSchema::Embedded => self.add_embedded_transaction(id, transaction).await,
+        }
+    }
+
+    async fn add_idempotent_transaction(&self, id: u32, transaction: Transaction, key: &str) -> Result<(Balance, Transaction), ServerError> {
+        let fingerprint = transaction.fingerprint(id);
+        if let Some(stored) = self.find_idempotency_key(key, &fingerprint, &transaction).await? {
+            return Ok(stored);
+        }
+        let transaction = Transaction { id: Some(bson::oid::ObjectId::new().to_hex()), ..transaction };
+        let record = MongoIdempotencyKey {
+            _id: key.to_string(),
+            wallet_id: id,
+            fingerprint: fingerprint.clone(),
+            balance: None,
+            transaction: None,
+            created_at: bson::DateTime::now(),
+        };
+        let result = match self.schema {
+            MongoSchema::Collection => self.run_in_session(id, SessionWrite::Transaction(&transaction, Some(&record))).await.map(|balance| (balance, transaction.clone())),
+            MongoSchema::Embedded => self.add_embedded_idempotent_transaction(id, transaction.clone(), record).await,
+        };
+        match result {
+            // a concurrent request with the same key won
+            Err(ServerError::MongoError(e)) if error_code(&e) == Some(DUPLICATE_KEY) => {
+                self.find_idempotency_key(key, &fingerprint, &transaction).await?.ok_or(ServerError::IdempotencyKeyConflict)
+            }
+            result => result,
+        }
+    }
+
+    async fn add_transactions(&self, id: u32, transactions: Vec<Transaction>, mode: BatchMode) -> Result<BatchOutcome, ServerError> {
+        let transactions: Vec<Transaction> = transactions.into_iter().map(|transaction| Transaction { id: Some(bson::oid::ObjectId::new().to_hex()), ..transaction }).collect();
+        let (balance, skipped) = match (self.schema, mode) {
+            (MongoSchema::Collection, _) => self.run_session(id, SessionWrite::Batch(&transactions, mode)).await?,
+            (MongoSchema::Embedded, BatchMode::AllOrNothing) => (self.embedded_batch(id, &transactions).await?, Vec::new()),
+            (MongoSchema::Embedded, BatchMode::BestEffort) => self.embedded_best_effort_batch(id, &transactions).await?,
+        };
+        let results = transactions.into_iter().enumerate().map(|(index, transaction)| match skipped.contains(&index) {
+            true => Err(ServerError::TransactionWouldExceedLimit),
+            false => Ok(transaction),
+        }).collect();
+        Ok(BatchOutcome { balance, results })
+    }
+
+    /// Both wallets are updated in one multi-document transaction, whatever the schema:
+    /// a debit and a credit applied separately could lose money in between.
+    async fn transfer(&self, id: u32, transfer: Transfer) -> Result<Balance, ServerError> {
+        if !self.transactions {
+            return Err(ServerError::Unsupported("transfers need a replica set or a sharded cluster"));
+        }
+        self.run_in_session(id, SessionWrite::Transfer(&transfer)).await
+    }
+
+    async fn reverse_transaction(&self, id: u32, transaction_id: &str) -> Result<(Balance, Transaction), ServerError> {
+        match self.schema {
+            MongoSchema::Collection => self.reverse_collection_transaction(id, transaction_id).await,
+            MongoSchema::Embedded => self.reverse_embedded_transaction(id, transaction_id).await,
+        }
+    }
+
+    async fn get_transaction(&self, id: u32, transaction_id: &str) -> Result<Transaction, ServerError> {
+        let not_found = || ServerError::TransactionNotFound(transaction_id.to_string());
+        if let MongoSchema::Embedded = self.schema {
+            // only the last transactions are kept
+            let stmt = self.get_embedded_statement(&id).await?;
+            return stmt.last_transactions.into_iter().find(|transaction| transaction.id.as_deref() == Some(transaction_id)).ok_or_else(not_found);
+        }
+        let transaction = match bson::oid::ObjectId::parse_str(transaction_id) {
+            Ok(transaction_id) => {
+                let collection = self.database.collection::<MongoTransaction>(TRANSACTIONS);
+                collection.find_one(doc! {"_id": transaction_id, "wallet_id": id}, None).await?
+            }
+            Err(_) => None,
+        };
+        match transaction {
+            Some(transaction) => Ok(transaction.into()),
+            None => {
+                let wallets = self.database.collection::<MongoBalance>(BALANCE);
+                match wallets.count_documents(doc! {"id": id}, None).await? {
+                    0 => Err(ServerError::UserNotFound(id)),
+                    _ => Err(not_found()),
+                }
+            }
+        }
+    }
+
+    async fn place_hold(&self, id: u32, hold: Hold) -> Result<(Balance, Hold), ServerError> {
+        let hold = Hold { id: Some(bson::oid::ObjectId::new().to_hex()), ..hold };
+        let collection = self.database.collection::<MongoBalance>(BALANCE);
+        let opts = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .projection(doc! {EMBEDDED_TRANSACTIONS: 0, HOLDS: 0})
+            .build();
+        let value = collection.find_one_and_update(
+            open_wallet(id),
+            doc! {
+                "$inc": { "balance.reservado": hold.value },
+                "$push": { HOLDS: hold.clone() },
+            },
+            Some(opts),
+        ).await.map_err(map_limit_error)?;
+        match value {
+            Some(mut balance) => {
+                balance.balance.statement_date = Some(chrono::Utc::now());
+                Ok((balance.balance, hold))
             }
+            None => Err(self.closed_or_missing(id).await),
+        }
+    }
+
+    async fn capture_hold(&self, id: u32, hold_id: &str, value: Option<u32>) -> Result<(Balance, Transaction), ServerError> {
+        let hold = self.get_hold(id, hold_id).await?;
+        if hold.is_expired() {
+            return Err(ServerError::HoldNotFound(hold_id.to_string()));
+        }
+        let transaction = Transaction { id: Some(bson::oid::ObjectId::new().to_hex()), ..hold.capture(value)? };
+        let balance = match self.schema {
+            MongoSchema::Collection => self.run_in_session(id, SessionWrite::Capture(&hold, &transaction)).await?,
+            MongoSchema::Embedded => self.embedded_capture(id, &hold, &transaction).await?,
         };
-        let pipeline = vec![balance_match, transactions_lookup];
+        Ok((balance, transaction))
+    }
+
+    async fn release_hold(&self, id: u32, hold_id: &str) -> Result<(Balance, Hold), ServerError> {
+        let hold = self.get_hold(id, hold_id).await?;
+        match self.remove_hold(id, &hold).await? {
+            Some(balance) => Ok((balance, hold)),
+            None => Err(ServerError::HoldNotFound(hold_id.to_string())),
+        }
+    }
+
+    async fn expire_holds(&self) -> Result<Vec<u32>, ServerError> {
+        let now = chrono::Utc::now();
+        let collection = self.database.collection::<MongoHolds>(BALANCE);
+        let opts = FindOptions::builder().projection(doc! {"id": 1, HOLDS: 1}).build();
+        let wallets: Vec<MongoHolds> = collection.find(doc! {format!("{HOLDS}.expira_em"): {"$lte": now}}, opts).await?.try_collect().await?;
+        let mut expired = Vec::with_capacity(wallets.len());
+        for wallet in wallets {
+            for hold in wallet.holds.iter().filter(|hold| hold.expires_at <= now) {
+                self.remove_hold(wallet.id, hold).await?;
+            }
+            expired.push(wallet.id);
+        }
+        Ok(expired)
+    }
+
+    async fn get_statement(&self, id: &u32) -> Result<Statement, ServerError> {
+        if let MongoSchema::Embedded = self.schema {
+            return self.get_embedded_statement(id).await;
+        }
+        let collection = self.database.collection::<MongoBalance>(BALANCE);
+        let balance_match = doc! {
+            "$match": doc! {
+                "id": id
+            }
+        };
+        let pipeline = vec![balance_match, transactions_lookup(self.statement_size)];
         let mut result = collection.aggregate(pipeline, None).await?;
         let result = result.next().await.ok_or(ServerError::UserNotFound(*id))??;
 
@@ -112,6 +968,184 @@ impl TransactionRepository for MongoDatabase {
             last_transactions: stmt.transactions,
         })
     }
+
+    async fn get_history(&self, id: u32, query: HistoryQuery) -> Result<HistoryPage, ServerError> {
+        if let MongoSchema::Embedded = self.schema {
+            // only the last transactions are kept, a page of them would pass for the whole history
+            return Err(ServerError::Unsupported("the history needs the collection schema"));
+        }
+        let mut filter = doc! {"wallet_id": id};
+        let mut date = Document::new();
+        if let Some(from) = query.from {
+            date.insert("$gte", bson::DateTime::from_chrono(from));
+        }
+        if let Some(to) = query.to {
+            date.insert("$lt", bson::DateTime::from_chrono(to));
+        }
+        if !date.is_empty() {
+            filter.insert("transaction.realizada_em", date);
+        }
+        if let Some(transaction_type) = query.transaction_type {
+            filter.insert("transaction.tipo", transaction_type);
+        }
+        if let Some(description) = &query.description {
+            filter.insert("transaction.descricao", doc! {"$regex": escape_regex(description), "$options": "i"});
+        }
+        if let Some(cursor) = &query.cursor {
+            let cursor_id = bson::oid::ObjectId::parse_str(&cursor.id).map_err(|_| ServerError::InvalidCursor)?;
+            let cursor_date = bson::DateTime::from_chrono(cursor.date);
+            filter.insert("$or", vec![
+                doc! {"transaction.realizada_em": {"$lt": cursor_date}},
+                doc! {"transaction.realizada_em": cursor_date, "_id": {"$lt": cursor_id}},
+            ]);
+        }
+        // one extra document tells whether there is a next page
+        let opts = FindOptions::builder()
+            .sort(doc! {"transaction.realizada_em": -1, "_id": -1})
+            .limit(query.limit as i64 + 1)
+            .build();
+        let collection = self.database.collection::<MongoTransaction>(TRANSACTIONS);
+        let mut transactions: Vec<MongoTransaction> = collection.find(filter, opts).await?.try_collect().await?;
+
+        let wallets = self.database.collection::<MongoBalance>(BALANCE);
+        if transactions.is_empty() && wallets.count_documents(doc! {"id": id}, None).await? == 0 {
+            return Err(ServerError::UserNotFound(id));
+        }
+        let mut next_cursor = None;
+        if transactions.len() > query.limit as usize {
+            transactions.truncate(query.limit as usize);
+            next_cursor = transactions.last().map(|last| HistoryCursor::new(last.transaction.date, last._id.to_hex()));
+        }
+        Ok(HistoryPage {
+            transactions: transactions.into_iter().map(Transaction::from).collect(),
+            next_cursor,
+        })
+    }
+
+    async fn create_account(&self, limit: u32) -> Result<Account, ServerError> {
+        let collection = self.database.collection::<MongoBalance>(BALANCE);
+        loop {
+            let opts = FindOptions::builder().sort(doc! {"id": -1}).limit(1).projection(doc! {EMBEDDED_TRANSACTIONS: 0}).build();
+            let last = collection.find(doc! {}, opts).await?.next().await.transpose()?;
+            let id = last.map_or(0, |wallet| wallet.id) + 1;
+            let wallet = doc! {"id": id, "balance": Balance::new(limit)};
+            match self.database.collection::<Document>(BALANCE).insert_one(wallet, None).await {
+                Ok(_) => return Ok(Account { id, limit, total: 0, closed_at: None }),
+                // another wallet was created with the same id, pick the next one
+                Err(e) if error_code(&e) == Some(DUPLICATE_KEY) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    async fn seed_account(&self, id: u32, limit: u32) -> Result<bool, ServerError> {
+        let wallet = doc! {"id": id, "balance": Balance::new(limit)};
+        match self.database.collection::<Document>(BALANCE).insert_one(wallet, None).await {
+            Ok(_) => Ok(true),
+            Err(e) if error_code(&e) == Some(DUPLICATE_KEY) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_account(&self, id: u32) -> Result<Account, ServerError> {
+        let collection = self.database.collection::<MongoBalance>(BALANCE);
+        let opts = FindOneOptions::builder().projection(doc! {EMBEDDED_TRANSACTIONS: 0}).build();
+        let wallet = collection.find_one(doc! {"id": id}, opts).await?;
+        Ok(wallet.ok_or(ServerError::UserNotFound(id))?.into())
+    }
+
+    async fn update_limit(&self, id: u32, limit: u32) -> Result<Account, ServerError> {
+        let collection = self.database.collection::<MongoBalance>(BALANCE);
+        let opts = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .projection(doc! {EMBEDDED_TRANSACTIONS: 0})
+            .build();
+        let value = collection.find_one_and_update(
+            open_wallet(id),
+            doc! {"$set": {"balance.limite": limit}},
+            Some(opts),
+        ).await.map_err(|e| match error_code(&e) {
+            Some(DOCUMENT_VALIDATION_FAILURE) => ServerError::LimitBelowBalance,
+            _ => e.into(),
+        })?;
+        match value {
+            Some(wallet) => Ok(wallet.into()),
+            None => Err(self.closed_or_missing(id).await),
+        }
+    }
+
+    async fn close_account(&self, id: u32) -> Result<Account, ServerError> {
+        let collection = self.database.collection::<MongoBalance>(BALANCE);
+        let opts = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .projection(doc! {EMBEDDED_TRANSACTIONS: 0})
+            .build();
+        let value = collection.find_one_and_update(
+            open_wallet(id),
+            doc! {"$set": {"closed_at": bson::DateTime::now()}},
+            Some(opts),
+        ).await?;
+        match value {
+            Some(wallet) => Ok(wallet.into()),
+            // already closed, or missing
+            None => self.get_account(id).await,
+        }
+    }
+
+    async fn ping(&self) -> Result<(), ServerError> {
+        self.database.run_command(doc! { "ping": 1 }, None).await?;
+        Ok(())
+    }
+
+    // the change stream listener never drops its cursor, so do not wait for it
+    async fn close(&self) {
+        self.client.clone().shutdown_immediate().await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<AccountEvent> {
+        self.events.subscribe()
+    }
+}
+
+/// Looks up the last transactions of each wallet into a `transactions` field.
+fn transactions_lookup(statement_size: i32) -> Document {
+    doc! {
+        "$lookup": {
+            "from": TRANSACTIONS,
+            "as": "transactions",
+            "let": doc! {"wallet_id": "$id"},
+            "pipeline": vec![
+                doc! {
+                    "$match": doc! {
+                        "$expr": doc! {
+                            "$eq": ["$wallet_id", "$$wallet_id"]
+                        }
+                    }
+                },
+                doc! {
+                    "$sort": doc! {
+                        "transaction.realizada_em": -1
+                    }
+                },
+                doc! {
+                    "$limit": statement_size
+                },
+                doc! {
+                    "$replaceRoot": doc! {
+                        "newRoot": doc! {
+                            "$mergeObjects": [
+                                "$transaction",
+                                doc! {
+                                    "id": doc! {"$toString": "$_id"},
+                                    "transferencia": doc! {"$toString": "$transfer_id"},
+                                },
+                            ]
+                        }
+                    }
+                }
+            ]
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -122,11 +1156,52 @@ struct MongoStatement {
     transactions: Vec<Transaction>,
 }
 
+#[derive(Serialize, Deserialize)]
+struct MongoEmbeddedStatement {
+    _id: bson::oid::ObjectId,
+    id: u32,
+    balance: Balance,
+    #[serde(rename = "ultimas_transacoes", default)]
+    transactions: Vec<Transaction>,
+}
+
 #[derive(Serialize, Deserialize)]
 struct MongoBalance {
     _id: bson::oid::ObjectId,
     id: u32,
     balance: Balance,
+    #[serde(default)]
+    closed_at: Option<bson::DateTime>,
+}
+
+impl From<MongoBalance> for Account {
+    fn from(wallet: MongoBalance) -> Self {
+        Self {
+            id: wallet.id,
+            limit: wallet.balance.limit,
+            total: wallet.balance.total,
+            closed_at: wallet.closed_at.map(|date| date.to_chrono()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct MongoHolds {
+    id: u32,
+    #[serde(rename = "reservas", default)]
+    holds: Vec<Hold>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct MongoIdempotencyKey {
+    _id: String,
+    wallet_id: u32,
+    fingerprint: String,
+    // not set while the transaction is being applied
+    balance: Option<Balance>,
+    #[serde(default)]
+    transaction: Option<Transaction>,
+    created_at: bson::DateTime,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -134,26 +1209,114 @@ struct MongoTransaction {
     _id: bson::oid::ObjectId,
     wallet_id: u32,
     transaction: Transaction,
+    // links the debit and credit created by a transfer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    transfer_id: Option<bson::oid::ObjectId>,
+    /// Wallet balance right after this transaction, for the change stream.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    balance: Option<Balance>,
 }
 
+impl From<MongoTransaction> for Transaction {
+    fn from(value: MongoTransaction) -> Self {
+        Self {
+            id: Some(value._id.to_hex()),
+            transfer_id: value.transfer_id.map(|id| id.to_hex()),
+            ..value.transaction
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SessionWrite<'a> {
+    Transaction(&'a Transaction, Option<&'a MongoIdempotencyKey>),
+    Transfer(&'a Transfer),
+    Capture(&'a Hold, &'a Transaction),
+    Batch(&'a [Transaction], BatchMode),
+}
+
+
+/// The database named in the connection string, such as `/rinha` in `mongodb://mongodb:27017/rinha`.
+fn default_database(client: &Client) -> Result<Database, ServerError> {
+    client.default_database().ok_or_else(|| ServerError::SchemaMismatch("MONGO_URL must name a database".to_string()))
+}
 
-async fn migrate(client: &Client) {
-    let database = client.default_database().expect("no default database");
+const SCHEMA_MIGRATION: &str = "v1-schema";
+const EMBEDDED_MIGRATION: &str = "v2-embedded";
+
+async fn pending_migrations(database: &Database, schema: Option<MongoSchema>) -> Result<Vec<&'static str>, ServerError> {
+    let mut migrations = vec![SCHEMA_MIGRATION];
+    if schema_for(database, schema).await? == MongoSchema::Embedded {
+        migrations.push(EMBEDDED_MIGRATION);
+    }
     let migration = database.collection::<Document>("migration");
-    if migration.find_one(doc! {"migration": "v1"}, None).await.expect("failed to find migration").is_some() {
-        return;
+    let mut pending = Vec::new();
+    for name in migrations {
+        if migration.find_one(doc! {"migration": name}, None).await?.is_none() {
+            pending.push(name);
+        }
     }
+    Ok(pending)
+}
 
+/// Databases set up before the migrations were tracked already have the collection,
+/// creating the indexes again is a no-op.
+async fn create_collections(database: &Database) -> Result<(), ServerError> {
+    // assure that total minus the held part cannot be less than -limit
+    let validator = doc! {
+        "$expr": doc! {
+            "$gte": [
+                doc! {"$subtract": ["$balance.total", doc! {"$ifNull": ["$balance.reservado", 0]}]},
+                doc! {"$multiply": [ "$balance.limite", -1]},
+            ]
+        }
+    };
+    let validation_opts = CreateCollectionOptions::builder().validator(validator).validation_action(Some(ValidationAction::Error)).validation_level(Some(ValidationLevel::Strict)).build();
+    match database.create_collection(BALANCE, validation_opts).await {
+        Err(e) if error_code(&e) != Some(NAMESPACE_EXISTS) => return Err(e.into()),
+        _ => {}
+    }
     let collection = database.collection::<Document>(BALANCE);
-    collection.insert_many(vec![
-        doc! {"id": 1, "balance": Balance::new(100000)},
-        doc! {"id": 2, "balance": Balance::new(80000)},
-        doc! {"id": 3, "balance": Balance::new(1000000)},
-        doc! {"id": 4, "balance": Balance::new(10000000)},
-        doc! {"id": 5, "balance": Balance::new(500000)},
-    ], None).await.expect("failed to insert documents");
+    let opts = IndexOptions::builder().unique(true).build();
+    let model = IndexModel::builder().keys(doc! {"id": 1}).options(Some(opts)).build();
+    collection.create_index(model, None).await?;
+    let collection = database.collection::<Document>(TRANSACTIONS);
+    let model = IndexModel::builder().keys(doc! {"wallet_id": 1, "transaction.realizada_em": -1, "_id": -1}).build();
+    collection.create_index(model, None).await?;
+    // a transaction can only be reversed once
+    let opts = IndexOptions::builder().unique(true).partial_filter_expression(doc! {"transaction.estorno_de": {"$exists": true}}).build();
+    let model = IndexModel::builder().keys(doc! {"transaction.estorno_de": 1}).options(Some(opts)).build();
+    collection.create_index(model, None).await?;
+    let collection = database.collection::<Document>(IDEMPOTENCY_KEYS);
+    let opts = IndexOptions::builder().expire_after(Some(Duration::from_secs(IDEMPOTENCY_KEY_TTL))).build();
+    let model = IndexModel::builder().keys(doc! {"created_at": 1}).options(Some(opts)).build();
+    collection.create_index(model, None).await?;
+    Ok(())
+}
 
-    migration.insert_one(doc! {"migration": "v1"}, None).await.expect("failed to insert migration");
+/// Copies the last transactions of every wallet from the transactions collection
+/// into its balance document, so data written with the collection schema is kept
+/// when switching to the embedded one.
+async fn migrate_embedded(database: &Database, statement_size: i32) -> Result<(), ServerError> {
+    let collection = database.collection::<Document>(BALANCE);
+    let pipeline = vec![
+        transactions_lookup(statement_size),
+        doc! {
+            "$project": doc! {
+                EMBEDDED_TRANSACTIONS: "$transactions"
+            }
+        },
+        doc! {
+            "$merge": doc! {
+                "into": BALANCE,
+                "on": "_id",
+                "whenMatched": "merge",
+                "whenNotMatched": "discard",
+            }
+        },
+    ];
+    collection.aggregate(pipeline, None).await?;
+    Ok(())
 }
 
 impl From<models::Statement> for Bson {
@@ -167,10 +1330,29 @@ impl From<models::Statement> for Bson {
 
 impl From<models::Balance> for Bson {
     fn from(value: Balance) -> Self {
-        Bson::Document(doc! {
+        let mut document = doc! {
             "total": value.total,
             "limite": value.limit,
-        })
+        };
+        if let Some(held) = value.held {
+            document.insert("reservado", held);
+        }
+        Bson::Document(document)
+    }
+}
+
+impl From<models::Hold> for Bson {
+    fn from(value: Hold) -> Self {
+        let mut document = doc! {
+            "valor": value.value,
+            "descricao": value.description,
+            "criada_em": value.created_at,
+            "expira_em": value.expires_at,
+        };
+        if let Some(id) = value.id {
+            document.insert("id", id);
+        }
+        Bson::Document(document)
     }
 }
 
@@ -185,11 +1367,21 @@ impl From<models::TransactionType> for Bson {
 
 impl From<models::Transaction> for Bson {
     fn from(value: Transaction) -> Self {
-        Bson::Document(doc! {
+        let mut document = doc! {
             "valor": value.value,
             "tipo": value.transaction_type,
             "descricao": value.description,
             "realizada_em": value.date,
-        })
+        };
+        if let Some(id) = value.id {
+            document.insert("id", id);
+        }
+        if let Some(reverses) = value.reverses {
+            document.insert("estorno_de", reverses);
+        }
+        if let Some(transfer_id) = value.transfer_id {
+            document.insert("transferencia", transfer_id);
+        }
+        Bson::Document(document)
     }
 }
\ No newline at end of file
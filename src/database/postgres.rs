@@ -1,74 +1,442 @@
-use super::{Balance, Statement, Transaction, TransactionRepository, TransactionType};
-use chrono::Utc;
-use sqlx::{Pool, Postgres};
+use super::{
+    events_channel, to_i32, Account, AccountEvent, Balance, BatchMode, BatchOutcome, HistoryCursor,
+    HistoryPage, HistoryQuery, Hold, Statement, Transaction, TransactionRepository,
+    TransactionType, Transfer, LISTEN_RETRY_INTERVAL,
+};
+use chrono::{NaiveDateTime, Utc};
+use serde::Deserialize;
+use sqlx::{
+    postgres::{PgExecutor, PgListener},
+    Connection, PgConnection, Pool, Postgres,
+};
+use tokio::sync::broadcast;
 
 use crate::error::ServerError;
 
+/// SQLSTATE raised by the `wallet_closed` trigger.
+const WALLET_CLOSED: &str = "RB001";
+/// SQLSTATEs raised by the `reverse_transaction` function.
+const WALLET_NOT_FOUND: &str = "RB002";
+const TRANSACTION_NOT_FOUND: &str = "RB003";
+const TRANSACTION_NOT_REVERSIBLE: &str = "RB004";
+/// SQLSTATEs raised by the hold functions.
+const HOLD_NOT_FOUND: &str = "RB005";
+const CAPTURE_EXCEEDS_HOLD: &str = "RB006";
+/// SQLSTATE of the wallet `- total <= "limit"` constraint.
+const CHECK_VIOLATION: &str = "23514";
+/// SQLSTATE of the `transaction.reversal_of` unique constraint.
+const UNIQUE_VIOLATION: &str = "23505";
+/// Channel the `transaction_notify` trigger announces new transactions on.
+const ACCOUNT_ACTIVITY: &str = "account_activity";
+
+/// The pool, the subscribers to the account activity and the statement size.
 #[derive(Clone)]
-pub struct PostgresDatabase(Pool<Postgres>);
+pub struct PostgresDatabase(Pool<Postgres>, broadcast::Sender<AccountEvent>, u32);
+
+struct WalletRow {
+    id: i32,
+    limit: i32,
+    total: i32,
+    closed_at: Option<NaiveDateTime>,
+}
+
+/// Payload of the `account_activity` notifications.
+#[derive(Deserialize)]
+struct Notification {
+    transaction: NotifiedTransaction,
+    wallet: NotifiedWallet,
+}
+
+#[derive(Deserialize)]
+struct NotifiedTransaction {
+    id: i32,
+    wallet_id: i32,
+    amount: i32,
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    description: String,
+    created_at: NaiveDateTime,
+    reversal_of: Option<i32>,
+    transfer_id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct NotifiedWallet {
+    total: i32,
+    limit: i32,
+    held: i32,
+}
+
+impl From<Notification> for AccountEvent {
+    fn from(
+        Notification {
+            transaction,
+            wallet,
+        }: Notification,
+    ) -> Self {
+        Self {
+            wallet_id: transaction.wallet_id as u32,
+            balance: Balance {
+                total: wallet.total,
+                limit: wallet.limit as u32,
+                statement_date: None,
+                held: Some(wallet.held as u32),
+            },
+            transaction: Transaction {
+                id: Some(transaction.id.to_string()),
+                value: transaction.amount as u32,
+                transaction_type: transaction.transaction_type,
+                description: transaction.description,
+                date: transaction.created_at.and_utc(),
+                reverses: transaction.reversal_of.map(|id| id.to_string()),
+                transfer_id: transaction.transfer_id.map(|id| id.to_string()),
+            },
+        }
+    }
+}
+
+impl From<WalletRow> for Account {
+    fn from(wallet: WalletRow) -> Self {
+        Self {
+            id: wallet.id as u32,
+            limit: wallet.limit as u32,
+            total: wallet.total,
+            closed_at: wallet.closed_at.map(|date| date.and_utc()),
+        }
+    }
+}
 
 impl TransactionRepository for PostgresDatabase {
     async fn add_transaction(
         &self,
         id: u32,
         transaction: Transaction,
-    ) -> Result<Balance, ServerError> {
+    ) -> Result<(Balance, Transaction), ServerError> {
+        apply_transaction(&self.0, id, &transaction).await
+    }
+
+    async fn add_idempotent_transaction(
+        &self,
+        id: u32,
+        transaction: Transaction,
+        key: &str,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        let fingerprint = transaction.fingerprint(id);
+        if let Some(stored) = self
+            .find_idempotency_key(key, &fingerprint, &transaction)
+            .await?
+        {
+            return Ok(stored);
+        }
+
+        let mut tx = self.0.begin().await?;
+        let (balance, stored) = apply_transaction(&mut *tx, id, &transaction).await?;
+        let transaction_id: Option<i32> = stored.id.as_deref().and_then(|id| id.parse().ok());
+        let inserted = sqlx::query!(
+            r#"INSERT INTO idempotency_key ("key", wallet_id, fingerprint, total, "limit", transaction_id)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT ("key") DO NOTHING;"#,
+            key,
+            id as i32,
+            fingerprint,
+            balance.total,
+            balance.limit as i32,
+            transaction_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if inserted.rows_affected() == 0 {
+            // a concurrent request with the same key won, discard this one
+            tx.rollback().await?;
+            return self
+                .find_idempotency_key(key, &fingerprint, &transaction)
+                .await?
+                .ok_or(ServerError::IdempotencyKeyConflict);
+        }
+        tx.commit().await?;
+        Ok((balance, stored))
+    }
+
+    async fn add_transactions(
+        &self,
+        id: u32,
+        transactions: Vec<Transaction>,
+        mode: BatchMode,
+    ) -> Result<BatchOutcome, ServerError> {
         let mut tx = self.0.begin().await?;
+        let mut results = Vec::with_capacity(transactions.len());
+        for (index, transaction) in transactions.iter().enumerate() {
+            let applied = match mode {
+                BatchMode::AllOrNothing => apply_transaction(&mut *tx, id, transaction).await,
+                BatchMode::BestEffort => apply_in_savepoint(&mut tx, id, transaction).await,
+            };
+            match applied {
+                Ok((_, transaction)) => results.push(Ok(transaction)),
+                Err(e) => results.push(Err(mode.on_failure(index, e)?)),
+            }
+        }
+        let balance = sqlx::query!(
+            "SELECT total, \"limit\", held FROM wallet WHERE id = $1;",
+            id as i32
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ServerError::UserNotFound(id))?;
+        tx.commit().await?;
+        Ok(BatchOutcome {
+            balance: Balance {
+                total: balance.total,
+                limit: balance.limit as u32,
+                statement_date: Some(Utc::now()),
+                held: Some(balance.held as u32),
+            },
+            results,
+        })
+    }
+
+    async fn transfer(&self, id: u32, transfer: Transfer) -> Result<Balance, ServerError> {
+        let value = to_i32(transfer.value)?;
         let balance = sqlx::query!(
-            "UPDATE wallet SET total = total + $2 WHERE id = $1 RETURNING *;",
+            "SELECT new_total, new_limit, missing_wallet_id FROM transfer($1, $2, $3, $4);",
             id as i32,
-            transaction.value()
+            transfer.to as i32,
+            value,
+            transfer.description
         )
-        .fetch_one(&mut *tx)
+        .fetch_one(&self.0)
         .await
-        .map_err(|e| match e {
-            sqlx::Error::Database(db_err) if db_err.code() == Some("23514".into()) => {
-                ServerError::TransactionWouldExceedLimit
+        .map_err(|e| map_wallet_error(e, ServerError::TransactionWouldExceedLimit))?;
+        if let Some(missing) = balance.missing_wallet_id {
+            return Err(ServerError::UserNotFound(missing as u32));
+        }
+        let (Some(total), Some(limit)) = (balance.new_total, balance.new_limit) else {
+            return Err(ServerError::UserNotFound(id));
+        };
+        Ok(Balance {
+            total,
+            limit: limit as u32,
+            statement_date: Some(Utc::now()),
+            held: None,
+        })
+    }
+
+    async fn get_transaction(
+        &self,
+        id: u32,
+        transaction_id: &str,
+    ) -> Result<Transaction, ServerError> {
+        let transaction = match transaction_id.parse::<i32>() {
+            Ok(transaction_id) => {
+                sqlx::query!(
+                    r#"SELECT
+                        id,
+                        amount,
+                        "type" as "type: TransactionType",
+                        "description",
+                        created_at,
+                        reversal_of,
+                        transfer_id
+                    FROM transaction
+                    WHERE id = $1 AND wallet_id = $2;"#,
+                    transaction_id,
+                    id as i32
+                )
+                .fetch_optional(&self.0)
+                .await?
             }
-            _ => e.into(),
-        })?;
+            Err(_) => None,
+        };
+        let Some(t) = transaction else {
+            let wallet = sqlx::query!("SELECT id FROM wallet WHERE id = $1;", id as i32)
+                .fetch_optional(&self.0)
+                .await?;
+            return Err(match wallet {
+                Some(_) => ServerError::TransactionNotFound(transaction_id.to_string()),
+                None => ServerError::UserNotFound(id),
+            });
+        };
+        Ok(Transaction {
+            id: Some(t.id.to_string()),
+            value: t.amount as u32,
+            transaction_type: t.r#type,
+            description: t.description,
+            date: t.created_at.and_utc(),
+            reverses: t.reversal_of.map(|id| id.to_string()),
+            transfer_id: t.transfer_id.map(|id| id.to_string()),
+        })
+    }
+
+    async fn place_hold(&self, id: u32, hold: Hold) -> Result<(Balance, Hold), ServerError> {
+        let value = to_i32(hold.value)?;
+        let placed = sqlx::query!(
+            r#"SELECT
+                new_total as "new_total!",
+                new_limit as "new_limit!",
+                new_held as "new_held!",
+                hold_id as "hold_id!"
+            FROM place_hold($1, $2, $3, $4, $5);"#,
+            id as i32,
+            value,
+            hold.description,
+            hold.created_at.naive_utc(),
+            hold.expires_at.naive_utc()
+        )
+        .fetch_one(&self.0)
+        .await
+        .map_err(|e| map_hold_error(e, id, ""))?;
         let balance = Balance {
-            total: balance.total,
-            limit: balance.limit as u32,
+            total: placed.new_total,
+            limit: placed.new_limit as u32,
             statement_date: Some(Utc::now()),
+            held: Some(placed.new_held as u32),
+        };
+        let hold = Hold {
+            id: Some(placed.hold_id.to_string()),
+            ..hold
         };
-        sqlx::query!(
-            "INSERT INTO transaction (wallet_id, amount, \"type\", description) VALUES ($1, $2, $3, $4);",
+        Ok((balance, hold))
+    }
+
+    async fn capture_hold(
+        &self,
+        id: u32,
+        hold_id: &str,
+        value: Option<u32>,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        let hold = hold_id
+            .parse::<i32>()
+            .map_err(|_| ServerError::HoldNotFound(hold_id.to_string()))?;
+        let value = value.map(to_i32).transpose()?;
+        let captured = sqlx::query!(
+            r#"SELECT
+                new_total as "new_total!",
+                new_limit as "new_limit!",
+                new_held as "new_held!",
+                transaction_id as "transaction_id!",
+                transaction_amount as "transaction_amount!",
+                transaction_description as "transaction_description!",
+                transaction_created_at as "transaction_created_at!"
+            FROM capture_hold($1, $2, $3);"#,
             id as i32,
-            transaction.value as i32,
-            transaction.transaction_type as TransactionType,
-            transaction.description
-        ).execute(&mut *tx).await?;
-        tx.commit().await?;
-        Ok(balance)
+            hold,
+            value
+        )
+        .fetch_one(&self.0)
+        .await
+        .map_err(|e| map_hold_error(e, id, hold_id))?;
+        let balance = Balance {
+            total: captured.new_total,
+            limit: captured.new_limit as u32,
+            statement_date: Some(Utc::now()),
+            held: Some(captured.new_held as u32),
+        };
+        let transaction = Transaction {
+            id: Some(captured.transaction_id.to_string()),
+            date: captured.transaction_created_at.and_utc(),
+            ..Transaction::new(
+                captured.transaction_amount as u32,
+                TransactionType::Withdraw,
+                captured.transaction_description,
+            )
+        };
+        Ok((balance, transaction))
+    }
+
+    async fn release_hold(&self, id: u32, hold_id: &str) -> Result<(Balance, Hold), ServerError> {
+        let hold = hold_id
+            .parse::<i32>()
+            .map_err(|_| ServerError::HoldNotFound(hold_id.to_string()))?;
+        let released = sqlx::query!(
+            r#"SELECT
+                new_total as "new_total!",
+                new_limit as "new_limit!",
+                new_held as "new_held!",
+                hold_amount as "hold_amount!",
+                hold_description as "hold_description!",
+                hold_created_at as "hold_created_at!",
+                hold_expires_at as "hold_expires_at!"
+            FROM release_hold($1, $2);"#,
+            id as i32,
+            hold
+        )
+        .fetch_one(&self.0)
+        .await
+        .map_err(|e| map_hold_error(e, id, hold_id))?;
+        let balance = Balance {
+            total: released.new_total,
+            limit: released.new_limit as u32,
+            statement_date: Some(Utc::now()),
+            held: Some(released.new_held as u32),
+        };
+        let hold = Hold {
+            id: Some(hold_id.to_string()),
+            value: released.hold_amount as u32,
+            description: released.hold_description,
+            created_at: released.hold_created_at.and_utc(),
+            expires_at: released.hold_expires_at.and_utc(),
+        };
+        Ok((balance, hold))
+    }
+
+    /// Each wallet is released in its own statement, so the sweep never locks
+    /// several wallets at once and cannot deadlock with transfers.
+    async fn expire_holds(&self) -> Result<Vec<u32>, ServerError> {
+        let wallets = sqlx::query_scalar!(
+            r#"SELECT DISTINCT wallet_id FROM hold WHERE expires_at <= now() AT TIME ZONE 'UTC';"#
+        )
+        .fetch_all(&self.0)
+        .await?;
+        for wallet_id in &wallets {
+            sqlx::query!(
+                r#"WITH expired AS (
+                    DELETE FROM hold
+                    WHERE wallet_id = $1 AND expires_at <= now() AT TIME ZONE 'UTC'
+                    RETURNING amount
+                )
+                UPDATE wallet SET held = held - (SELECT COALESCE(SUM(amount), 0) FROM expired)
+                WHERE id = $1;"#,
+                wallet_id
+            )
+            .execute(&self.0)
+            .await?;
+        }
+        Ok(wallets.into_iter().map(|id| id as u32).collect())
     }
 
     async fn get_statement(&self, id: &u32) -> Result<Statement, ServerError> {
         let id = *id as i32;
-        let balance = sqlx::query!("SELECT total, \"limit\" FROM wallet WHERE id = $1;", id)
-            .fetch_one(&self.0)
-            .await
-            .map_err(|e| match e {
-                sqlx::Error::RowNotFound => ServerError::UserNotFound(id as u32),
-                _ => e.into(),
-            })?;
+        let balance = sqlx::query!(
+            "SELECT total, \"limit\", held FROM wallet WHERE id = $1;",
+            id
+        )
+        .fetch_one(&self.0)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => ServerError::UserNotFound(id as u32),
+            _ => e.into(),
+        })?;
         let balance = Balance {
             total: balance.total,
             limit: balance.limit as u32,
             statement_date: Some(Utc::now()),
+            held: Some(balance.held as u32),
         };
         let transactions = sqlx::query!(
             r#"SELECT 
+                id,
                 amount, 
                 "type" as "type: TransactionType", 
                 "description", 
-                created_at 
+                created_at,
+                reversal_of,
+                transfer_id
             FROM transaction 
             WHERE 
                 wallet_id = $1 
-            ORDER BY created_at DESC
-            LIMIT 10;"#,
-            id
+            ORDER BY created_at DESC, id DESC
+            LIMIT $2;"#,
+            id,
+            self.2 as i64
         )
         .fetch_all(&self.0)
         .await?;
@@ -78,18 +446,402 @@ impl TransactionRepository for PostgresDatabase {
             last_transactions: transactions
                 .into_iter()
                 .map(|t| Transaction {
+                    id: Some(t.id.to_string()),
+                    value: t.amount as u32,
+                    transaction_type: t.r#type,
+                    description: t.description,
+                    date: t.created_at.and_utc(),
+                    reverses: t.reversal_of.map(|id| id.to_string()),
+                    transfer_id: t.transfer_id.map(|id| id.to_string()),
+                })
+                .collect(),
+        })
+    }
+
+    async fn get_history(&self, id: u32, query: HistoryQuery) -> Result<HistoryPage, ServerError> {
+        let cursor = match &query.cursor {
+            Some(cursor) => {
+                let cursor_id: i32 = cursor.id.parse().map_err(|_| ServerError::InvalidCursor)?;
+                Some((cursor.date.naive_utc(), cursor_id))
+            }
+            None => None,
+        };
+        // one extra row tells whether there is a next page
+        let mut transactions = sqlx::query!(
+            r#"SELECT
+                id,
+                amount,
+                "type" as "type: TransactionType",
+                "description",
+                created_at,
+                reversal_of,
+                transfer_id
+            FROM transaction
+            WHERE
+                wallet_id = $1
+                AND ($2::timestamp IS NULL OR created_at >= $2)
+                AND ($3::timestamp IS NULL OR created_at < $3)
+                AND ($4::transaction_type IS NULL OR "type" = $4)
+                AND ($5::text IS NULL OR strpos(lower("description"), lower($5)) > 0)
+                AND ($6::timestamp IS NULL OR (created_at, id) < ($6, $7))
+            ORDER BY created_at DESC, id DESC
+            LIMIT $8;"#,
+            id as i32,
+            query.from.map(|date| date.naive_utc()),
+            query.to.map(|date| date.naive_utc()),
+            query.transaction_type as Option<TransactionType>,
+            query.description,
+            cursor.map(|(date, _)| date),
+            cursor.map(|(_, id)| id),
+            query.limit as i64 + 1
+        )
+        .fetch_all(&self.0)
+        .await?;
+
+        if transactions.is_empty() {
+            let wallet = sqlx::query!("SELECT id FROM wallet WHERE id = $1;", id as i32)
+                .fetch_optional(&self.0)
+                .await?;
+            if wallet.is_none() {
+                return Err(ServerError::UserNotFound(id));
+            }
+        }
+        let mut next_cursor = None;
+        if transactions.len() > query.limit as usize {
+            transactions.truncate(query.limit as usize);
+            next_cursor = transactions
+                .last()
+                .map(|last| HistoryCursor::new(last.created_at.and_utc(), last.id));
+        }
+        Ok(HistoryPage {
+            transactions: transactions
+                .into_iter()
+                .map(|t| Transaction {
+                    id: Some(t.id.to_string()),
                     value: t.amount as u32,
                     transaction_type: t.r#type,
                     description: t.description,
                     date: t.created_at.and_utc(),
+                    reverses: t.reversal_of.map(|id| id.to_string()),
+                    transfer_id: t.transfer_id.map(|id| id.to_string()),
                 })
                 .collect(),
+            next_cursor,
         })
     }
+
+    async fn reverse_transaction(
+        &self,
+        id: u32,
+        transaction_id: &str,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        let not_found = || ServerError::TransactionNotFound(transaction_id.to_string());
+        let original_id: i32 = transaction_id.parse().map_err(|_| not_found())?;
+        let reversal = sqlx::query!(
+            r#"SELECT
+                new_total as "new_total!",
+                new_limit as "new_limit!",
+                reversal_id as "reversal_id!",
+                reversal_type as "reversal_type!: TransactionType",
+                reversal_amount as "reversal_amount!",
+                reversal_description as "reversal_description!",
+                reversal_created_at as "reversal_created_at!"
+            FROM reverse_transaction($1, $2);"#,
+            id as i32,
+            original_id
+        )
+        .fetch_one(&self.0)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(db_err) => match db_err.code().as_deref() {
+                Some(WALLET_NOT_FOUND) => ServerError::UserNotFound(id),
+                Some(TRANSACTION_NOT_FOUND) => not_found(),
+                Some(TRANSACTION_NOT_REVERSIBLE) => ServerError::TransactionNotReversible,
+                Some(UNIQUE_VIOLATION) => ServerError::TransactionAlreadyReversed,
+                _ => map_wallet_error(
+                    sqlx::Error::Database(db_err),
+                    ServerError::TransactionWouldExceedLimit,
+                ),
+            },
+            _ => e.into(),
+        })?;
+        let balance = Balance {
+            total: reversal.new_total,
+            limit: reversal.new_limit as u32,
+            statement_date: Some(Utc::now()),
+            held: None,
+        };
+        let transaction = Transaction {
+            id: Some(reversal.reversal_id.to_string()),
+            value: reversal.reversal_amount as u32,
+            transaction_type: reversal.reversal_type,
+            description: reversal.reversal_description,
+            date: reversal.reversal_created_at.and_utc(),
+            reverses: Some(original_id.to_string()),
+            transfer_id: None,
+        };
+        Ok((balance, transaction))
+    }
+
+    async fn create_account(&self, limit: u32) -> Result<Account, ServerError> {
+        let wallet = sqlx::query_as!(
+            WalletRow,
+            r#"INSERT INTO wallet ("limit") VALUES ($1) RETURNING id, "limit", total, closed_at;"#,
+            limit as i32
+        )
+        .fetch_one(&self.0)
+        .await?;
+        Ok(wallet.into())
+    }
+
+    async fn seed_account(&self, id: u32, limit: u32) -> Result<bool, ServerError> {
+        let seeded = sqlx::query_scalar!(
+            r#"INSERT INTO wallet (id, "limit") VALUES ($1, $2)
+            ON CONFLICT (id) DO NOTHING RETURNING id;"#,
+            id as i32,
+            limit as i32
+        )
+        .fetch_optional(&self.0)
+        .await?
+        .is_some();
+        // the serial would hand out the seeded ids again
+        sqlx::query_scalar!(
+            "SELECT setval(pg_get_serial_sequence('wallet', 'id'), MAX(id)) FROM wallet;"
+        )
+        .fetch_one(&self.0)
+        .await?;
+        Ok(seeded)
+    }
+
+    async fn get_account(&self, id: u32) -> Result<Account, ServerError> {
+        let wallet = sqlx::query_as!(
+            WalletRow,
+            r#"SELECT id, "limit", total, closed_at FROM wallet WHERE id = $1;"#,
+            id as i32
+        )
+        .fetch_optional(&self.0)
+        .await?
+        .ok_or(ServerError::UserNotFound(id))?;
+        Ok(wallet.into())
+    }
+
+    async fn update_limit(&self, id: u32, limit: u32) -> Result<Account, ServerError> {
+        let wallet = sqlx::query_as!(
+            WalletRow,
+            r#"UPDATE wallet SET "limit" = $2 WHERE id = $1 RETURNING id, "limit", total, closed_at;"#,
+            id as i32,
+            limit as i32
+        )
+        .fetch_optional(&self.0)
+        .await
+        .map_err(|e| map_wallet_error(e, ServerError::LimitBelowBalance))?
+        .ok_or(ServerError::UserNotFound(id))?;
+        Ok(wallet.into())
+    }
+
+    async fn close_account(&self, id: u32) -> Result<Account, ServerError> {
+        let wallet = sqlx::query_as!(
+            WalletRow,
+            r#"UPDATE wallet SET closed_at = COALESCE(closed_at, CURRENT_TIMESTAMP)
+            WHERE id = $1
+            RETURNING id, "limit", total, closed_at;"#,
+            id as i32
+        )
+        .fetch_optional(&self.0)
+        .await?
+        .ok_or(ServerError::UserNotFound(id))?;
+        Ok(wallet.into())
+    }
+
+    async fn ping(&self) -> Result<(), ServerError> {
+        let mut connection = self.0.acquire().await?;
+        sqlx::query("SELECT 1").execute(&mut *connection).await?;
+        Ok(())
+    }
+
+    async fn close(&self) {
+        self.0.close().await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<AccountEvent> {
+        self.1.subscribe()
+    }
 }
 
 impl PostgresDatabase {
-    pub fn new(pool: Pool<Postgres>) -> Self {
-        Self(pool)
+    /// Only listens to the other instances when `account_events` is set, the pool
+    /// connections then announcing their own transactions.
+    pub fn new(pool: Pool<Postgres>, statement_size: u32, account_events: bool) -> Self {
+        let events = events_channel();
+        if account_events {
+            tokio::spawn(listen(pool.clone(), events.clone()));
+        }
+        Self(pool, events, statement_size)
+    }
+
+    /// Returns what the request that created the key returned. Keys created before
+    /// transactions were linked to them only return the balance.
+    async fn find_idempotency_key(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        transaction: &Transaction,
+    ) -> Result<Option<(Balance, Transaction)>, ServerError> {
+        let stored = sqlx::query!(
+            r#"SELECT k.fingerprint, k.total, k."limit", t.id as "id?", t.created_at as "created_at?"
+            FROM idempotency_key k
+            LEFT JOIN transaction t ON t.id = k.transaction_id
+            WHERE k."key" = $1;"#,
+            key
+        )
+        .fetch_optional(&self.0)
+        .await?;
+        match stored {
+            Some(stored) if stored.fingerprint != fingerprint => {
+                Err(ServerError::IdempotencyKeyConflict)
+            }
+            Some(stored) => {
+                let balance = Balance {
+                    total: stored.total,
+                    limit: stored.limit as u32,
+                    statement_date: Some(Utc::now()),
+                    held: None,
+                };
+                // same fingerprint, so the request describes the stored transaction
+                let transaction = Transaction {
+                    id: stored.id.map(|id| id.to_string()),
+                    date: stored
+                        .created_at
+                        .map_or(transaction.date, |date| date.and_utc()),
+                    ..transaction.clone()
+                };
+                Ok(Some((balance, transaction)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+async fn apply_transaction<'c, E>(
+    executor: E,
+    id: u32,
+    transaction: &Transaction,
+) -> Result<(Balance, Transaction), ServerError>
+where
+    E: PgExecutor<'c>,
+{
+    let balance = sqlx::query!(
+        r#"SELECT new_total, new_limit, transaction_id, transaction_created_at
+        FROM add_transaction($1, $2, $3, $4);"#,
+        id as i32,
+        to_i32(transaction.value)?,
+        transaction.transaction_type as TransactionType,
+        transaction.description
+    )
+    .fetch_one(executor)
+    .await
+    .map_err(|e| map_wallet_error(e, ServerError::TransactionWouldExceedLimit))?;
+    // the function returns nulls when the wallet does not exist
+    let (Some(total), Some(limit), Some(transaction_id), Some(created_at)) = (
+        balance.new_total,
+        balance.new_limit,
+        balance.transaction_id,
+        balance.transaction_created_at,
+    ) else {
+        return Err(ServerError::UserNotFound(id));
+    };
+    let balance = Balance {
+        total,
+        limit: limit as u32,
+        statement_date: Some(Utc::now()),
+        held: None,
+    };
+    let transaction = Transaction {
+        id: Some(transaction_id.to_string()),
+        date: created_at.and_utc(),
+        ..transaction.clone()
+    };
+    Ok((balance, transaction))
+}
+
+/// Forwards the transactions announced by every instance to the local subscribers.
+async fn listen(pool: Pool<Postgres>, events: broadcast::Sender<AccountEvent>) {
+    loop {
+        let result = forward_notifications(&pool, &events).await;
+        // the pool is only closed on shutdown
+        if pool.is_closed() {
+            return;
+        }
+        if let Err(e) = result {
+            tracing::error!("Failed to listen to {ACCOUNT_ACTIVITY}: {e}");
+        }
+        tokio::time::sleep(LISTEN_RETRY_INTERVAL).await;
+    }
+}
+
+async fn forward_notifications(
+    pool: &Pool<Postgres>,
+    events: &broadcast::Sender<AccountEvent>,
+) -> Result<(), ServerError> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(ACCOUNT_ACTIVITY).await?;
+    loop {
+        let notification = listener.recv().await?;
+        match serde_json::from_str::<Notification>(notification.payload()) {
+            // no subscribers is not an error
+            Ok(notification) => _ = events.send(notification.into()),
+            Err(e) => tracing::warn!("Invalid {ACCOUNT_ACTIVITY} notification: {e}"),
+        }
+    }
+}
+
+/// Applies the transaction in a savepoint, so a failure leaves the surrounding
+/// transaction usable.
+async fn apply_in_savepoint(
+    connection: &mut PgConnection,
+    id: u32,
+    transaction: &Transaction,
+) -> Result<(Balance, Transaction), ServerError> {
+    let mut savepoint = connection.begin().await?;
+    match apply_transaction(&mut *savepoint, id, transaction).await {
+        Ok(applied) => {
+            savepoint.commit().await?;
+            Ok(applied)
+        }
+        Err(e) => {
+            savepoint.rollback().await?;
+            Err(e)
+        }
+    }
+}
+
+/// Maps the errors raised by the hold functions, a broken limit constraint meaning the
+/// hold does not fit in what is left of the limit.
+fn map_hold_error(e: sqlx::Error, id: u32, hold_id: &str) -> ServerError {
+    match e {
+        sqlx::Error::Database(db_err) => match db_err.code().as_deref() {
+            Some(WALLET_NOT_FOUND) => ServerError::UserNotFound(id),
+            Some(HOLD_NOT_FOUND) => ServerError::HoldNotFound(hold_id.to_string()),
+            Some(CAPTURE_EXCEEDS_HOLD) => ServerError::CaptureExceedsHold,
+            _ => map_wallet_error(
+                sqlx::Error::Database(db_err),
+                ServerError::TransactionWouldExceedLimit,
+            ),
+        },
+        _ => e.into(),
+    }
+}
+
+/// Maps the errors raised when a wallet balance or limit is updated, `check_violation`
+/// being what a broken limit constraint means for the caller.
+fn map_wallet_error(e: sqlx::Error, check_violation: ServerError) -> ServerError {
+    match e {
+        sqlx::Error::Database(db_err) if db_err.code() == Some(CHECK_VIOLATION.into()) => {
+            check_violation
+        }
+        sqlx::Error::Database(db_err) if db_err.code() == Some(WALLET_CLOSED.into()) => {
+            ServerError::AccountClosed
+        }
+        _ => e.into(),
     }
 }
@@ -0,0 +1,821 @@
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use redis::{AsyncCommands, Client, Script};
+use tokio::sync::broadcast;
+use tokio::task::AbortHandle;
+
+use crate::database::{
+    events_channel, Account, AccountEvent, Balance, BatchMode, BatchOutcome, HistoryPage,
+    HistoryQuery, Hold, Statement, Transaction, TransactionRepository, Transfer,
+    LISTEN_RETRY_INTERVAL,
+};
+use crate::error::ServerError;
+
+/// Counter holding the id of the last created wallet.
+const LAST_WALLET_ID: &str = "wallet:last_id";
+/// Seconds an idempotency key is remembered.
+const IDEMPOTENCY_KEY_TTL: u64 = 24 * 60 * 60;
+/// Total, limit and held part read from a wallet hash.
+type WalletFields = (Option<i32>, Option<u32>, Option<u32>);
+/// Sorted set of the pending holds, as `{wallet id}:{hold id}` scored by their
+/// expiration date in milliseconds.
+const HOLDS_EXPIRY: &str = "holds:expiry";
+/// Channel the scripts publish every applied transaction on, as an `AccountEvent`.
+const ACCOUNT_ACTIVITY: &str = "account_activity";
+
+// checks the limit, updates the balance and records the transaction atomically
+// returns {0, total, limit} on success, {1} when the wallet does not exist,
+// {2} when the transaction would exceed the limit, {3} when the
+// idempotency key (optional third key) was used with a different request
+// and {4} when the wallet is closed. A repeated idempotency key returns
+// {0, total, limit, 1} and keeps the first transaction in the key.
+// Applied transactions are published on ACCOUNT_ACTIVITY, here and in the other
+// scripts that apply them
+const ADD_TRANSACTION: &str = r#"
+if KEYS[3] then
+    local stored = redis.call("HMGET", KEYS[3], "fingerprint", "total", "limit")
+    if stored[1] then
+        if stored[1] ~= ARGV[4] then
+            return {3}
+        end
+        return {0, tonumber(stored[2]), tonumber(stored[3]), 1}
+    end
+end
+local limit = redis.call("HGET", KEYS[1], "limit")
+if not limit then
+    return {1}
+end
+if redis.call("HEXISTS", KEYS[1], "closed_at") == 1 then
+    return {4}
+end
+limit = tonumber(limit)
+local held = tonumber(redis.call("HGET", KEYS[1], "held") or 0)
+local total = tonumber(redis.call("HGET", KEYS[1], "total")) + tonumber(ARGV[1])
+if total - held < -limit then
+    return {2}
+end
+redis.call("HSET", KEYS[1], "total", total)
+redis.call("LPUSH", KEYS[2], ARGV[2])
+redis.call("LTRIM", KEYS[2], 0, ARGV[3] - 1)
+if KEYS[3] then
+    redis.call("HSET", KEYS[3], "fingerprint", ARGV[4], "total", total, "limit", limit, "transaction", ARGV[2])
+    redis.call("EXPIRE", KEYS[3], ARGV[5])
+end
+redis.call("PUBLISH", "account_activity", '{"wallet_id":' .. string.match(KEYS[1], "%d+") .. ',"balance":{"total":' .. total .. ',"limite":' .. limit .. ',"reservado":' .. held .. '},"transaction":' .. ARGV[2] .. '}')
+return {0, total, limit}
+"#;
+
+// applies the (value, transaction) pairs from ARGV[3] in order, checking each one
+// against the limit like ADD_TRANSACTION. Nothing is written until all of them were
+// checked: when one would exceed the limit it returns {2, index} unless ARGV[1] is
+// 1, in which case that one is skipped. Returns {0, total, limit, held} followed by
+// 1 for each applied transaction and 0 for each skipped one
+const ADD_TRANSACTIONS: &str = r#"
+local limit = redis.call("HGET", KEYS[1], "limit")
+if not limit then
+    return {1}
+end
+if redis.call("HEXISTS", KEYS[1], "closed_at") == 1 then
+    return {4}
+end
+limit = tonumber(limit)
+local held = tonumber(redis.call("HGET", KEYS[1], "held") or 0)
+local total = tonumber(redis.call("HGET", KEYS[1], "total"))
+local running_total = total
+local result = {0, 0, limit, held}
+for i = 3, #ARGV, 2 do
+    local next_total = total + tonumber(ARGV[i])
+    if next_total - held >= -limit then
+        total = next_total
+        table.insert(result, 1)
+    elseif ARGV[1] == "1" then
+        table.insert(result, 0)
+    else
+        return {2, (i - 3) / 2}
+    end
+end
+redis.call("HSET", KEYS[1], "total", total)
+for n = 5, #result do
+    if result[n] == 1 then
+        running_total = running_total + tonumber(ARGV[2 * n - 7])
+        redis.call("LPUSH", KEYS[2], ARGV[2 * n - 6])
+        redis.call("PUBLISH", "account_activity", '{"wallet_id":' .. string.match(KEYS[1], "%d+") .. ',"balance":{"total":' .. running_total .. ',"limite":' .. limit .. ',"reservado":' .. held .. '},"transaction":' .. ARGV[2 * n - 6] .. '}')
+    end
+end
+redis.call("LTRIM", KEYS[2], 0, ARGV[2] - 1)
+result[2] = total
+return result
+"#;
+
+// debits the payer (KEYS[1], KEYS[2]) and credits the payee (KEYS[3], KEYS[4])
+// atomically, returning the payer balance with the same codes as ADD_TRANSACTION
+const TRANSFER: &str = r#"
+local limit = redis.call("HGET", KEYS[1], "limit")
+if not limit or redis.call("EXISTS", KEYS[3]) == 0 then
+    return {1}
+end
+if redis.call("HEXISTS", KEYS[1], "closed_at") == 1 or redis.call("HEXISTS", KEYS[3], "closed_at") == 1 then
+    return {4}
+end
+limit = tonumber(limit)
+local held = tonumber(redis.call("HGET", KEYS[1], "held") or 0)
+local total = tonumber(redis.call("HGET", KEYS[1], "total")) - tonumber(ARGV[1])
+if total - held < -limit then
+    return {2}
+end
+redis.call("HSET", KEYS[1], "total", total)
+local payee_total = redis.call("HINCRBY", KEYS[3], "total", ARGV[1])
+local payee = redis.call("HMGET", KEYS[3], "limit", "held")
+redis.call("LPUSH", KEYS[2], ARGV[2])
+redis.call("LTRIM", KEYS[2], 0, ARGV[4] - 1)
+redis.call("LPUSH", KEYS[4], ARGV[3])
+redis.call("LTRIM", KEYS[4], 0, ARGV[4] - 1)
+redis.call("PUBLISH", "account_activity", '{"wallet_id":' .. string.match(KEYS[1], "%d+") .. ',"balance":{"total":' .. total .. ',"limite":' .. limit .. ',"reservado":' .. held .. '},"transaction":' .. ARGV[2] .. '}')
+redis.call("PUBLISH", "account_activity", '{"wallet_id":' .. string.match(KEYS[3], "%d+") .. ',"balance":{"total":' .. payee_total .. ',"limite":' .. payee[1] .. ',"reservado":' .. (payee[2] or 0) .. '},"transaction":' .. ARGV[3] .. '}')
+return {0, total, limit}
+"#;
+
+// sets the limit of an open wallet, with the same codes as ADD_TRANSACTION
+// plus {5} when the current balance is below the new limit
+const UPDATE_LIMIT: &str = r#"
+local total = redis.call("HGET", KEYS[1], "total")
+if not total then
+    return {1}
+end
+if redis.call("HEXISTS", KEYS[1], "closed_at") == 1 then
+    return {4}
+end
+total = tonumber(total)
+if total - tonumber(redis.call("HGET", KEYS[1], "held") or 0) < -tonumber(ARGV[1]) then
+    return {5}
+end
+redis.call("HSET", KEYS[1], "limit", ARGV[1])
+return {0, total, tonumber(ARGV[1])}
+"#;
+
+// opens the wallet unless it exists, moving the last id (KEYS[2]) past it so
+// create_account does not reuse it, returns 0 when the wallet already existed
+const SEED_ACCOUNT: &str = r#"
+if redis.call("HSETNX", KEYS[1], "limit", ARGV[2]) == 0 then
+    return 0
+end
+redis.call("HSET", KEYS[1], "total", 0)
+if tonumber(redis.call("GET", KEYS[2]) or 0) < tonumber(ARGV[1]) then
+    redis.call("SET", KEYS[2], ARGV[1])
+end
+return 1
+"#;
+
+// keeps the first closing date, returns 0 when the wallet does not exist
+const CLOSE_ACCOUNT: &str = r#"
+if redis.call("EXISTS", KEYS[1]) == 0 then
+    return 0
+end
+redis.call("HSETNX", KEYS[1], "closed_at", ARGV[1])
+return 1
+"#;
+
+// applies a reversal (ARGV[2]) like ADD_TRANSACTION while the original (ARGV[4]) is
+// still among the last transactions, with the same codes plus {6} when it is not
+// and {7} when it was already reversed
+const REVERSE_TRANSACTION: &str = r#"
+local limit = redis.call("HGET", KEYS[1], "limit")
+if not limit then
+    return {1}
+end
+if redis.call("HEXISTS", KEYS[1], "closed_at") == 1 then
+    return {4}
+end
+local found = false
+for _, entry in ipairs(redis.call("LRANGE", KEYS[2], 0, -1)) do
+    local transaction = cjson.decode(entry)
+    if transaction.estorno_de == ARGV[4] then
+        return {7}
+    end
+    if transaction.id == ARGV[4] then
+        found = true
+    end
+end
+if not found then
+    return {6}
+end
+limit = tonumber(limit)
+local held = tonumber(redis.call("HGET", KEYS[1], "held") or 0)
+local total = tonumber(redis.call("HGET", KEYS[1], "total")) + tonumber(ARGV[1])
+if total - held < -limit then
+    return {2}
+end
+redis.call("HSET", KEYS[1], "total", total)
+redis.call("LPUSH", KEYS[2], ARGV[2])
+redis.call("LTRIM", KEYS[2], 0, ARGV[3] - 1)
+redis.call("PUBLISH", "account_activity", '{"wallet_id":' .. string.match(KEYS[1], "%d+") .. ',"balance":{"total":' .. total .. ',"limite":' .. limit .. ',"reservado":' .. held .. '},"transaction":' .. ARGV[2] .. '}')
+return {0, total, limit}
+"#;
+
+// reserves ARGV[1] of an open wallet limit, storing the hold (ARGV[3]) in the
+// wallet holds (KEYS[2]) and its expiration date (ARGV[4]) in the expiry set
+// (KEYS[3]). Returns {0, total, limit, held} with the same error codes as
+// ADD_TRANSACTION
+const PLACE_HOLD: &str = r#"
+local limit = redis.call("HGET", KEYS[1], "limit")
+if not limit then
+    return {1}
+end
+if redis.call("HEXISTS", KEYS[1], "closed_at") == 1 then
+    return {4}
+end
+limit = tonumber(limit)
+local total = tonumber(redis.call("HGET", KEYS[1], "total"))
+local held = tonumber(redis.call("HGET", KEYS[1], "held") or 0) + tonumber(ARGV[1])
+if total - held < -limit then
+    return {2}
+end
+redis.call("HSET", KEYS[1], "held", held)
+redis.call("HSET", KEYS[2], ARGV[2], ARGV[3])
+redis.call("ZADD", KEYS[3], ARGV[4], ARGV[5])
+return {0, total, limit, held}
+"#;
+
+// posts the debit (ARGV[3]) of a hold (ARGV[1]) holding ARGV[4] and releases it,
+// with the same codes as PLACE_HOLD plus {6} when the hold is gone or expired
+// at ARGV[6]
+const CAPTURE_HOLD: &str = r#"
+local limit = redis.call("HGET", KEYS[1], "limit")
+if not limit then
+    return {1}
+end
+if redis.call("HEXISTS", KEYS[1], "closed_at") == 1 then
+    return {4}
+end
+local expires_at = redis.call("ZSCORE", KEYS[4], ARGV[5])
+if redis.call("HEXISTS", KEYS[3], ARGV[1]) == 0 or not expires_at or tonumber(expires_at) <= tonumber(ARGV[6]) then
+    return {6}
+end
+local total = tonumber(redis.call("HGET", KEYS[1], "total")) - tonumber(ARGV[2])
+local held = tonumber(redis.call("HGET", KEYS[1], "held") or 0) - tonumber(ARGV[4])
+redis.call("HSET", KEYS[1], "total", total, "held", held)
+redis.call("HDEL", KEYS[3], ARGV[1])
+redis.call("ZREM", KEYS[4], ARGV[5])
+redis.call("LPUSH", KEYS[2], ARGV[3])
+redis.call("LTRIM", KEYS[2], 0, ARGV[7] - 1)
+redis.call("PUBLISH", "account_activity", '{"wallet_id":' .. string.match(KEYS[1], "%d+") .. ',"balance":{"total":' .. total .. ',"limite":' .. limit .. ',"reservado":' .. held .. '},"transaction":' .. ARGV[3] .. '}')
+return {0, total, tonumber(limit), held}
+"#;
+
+// releases a hold (ARGV[1]) without posting anything, with the same codes as
+// CAPTURE_HOLD
+const RELEASE_HOLD: &str = r#"
+local limit = redis.call("HGET", KEYS[1], "limit")
+if not limit then
+    return {1}
+end
+local hold = redis.call("HGET", KEYS[2], ARGV[1])
+if not hold then
+    return {6}
+end
+local held = redis.call("HINCRBY", KEYS[1], "held", -cjson.decode(hold).valor)
+redis.call("HDEL", KEYS[2], ARGV[1])
+redis.call("ZREM", KEYS[3], ARGV[2])
+return {0, tonumber(redis.call("HGET", KEYS[1], "total")), tonumber(limit), held}
+"#;
+
+// releases the holds of the expiry set (KEYS[1]) expired at ARGV[1], returning
+// the wallets they held
+const EXPIRE_HOLDS: &str = r#"
+local wallets = {}
+for _, member in ipairs(redis.call("ZRANGEBYSCORE", KEYS[1], "-inf", ARGV[1])) do
+    local id, hold_id = string.match(member, "^(%d+):(.+)$")
+    local hold = redis.call("HGET", "wallet:" .. id .. ":holds", hold_id)
+    if hold then
+        redis.call("HINCRBY", "wallet:" .. id, "held", -cjson.decode(hold).valor)
+        redis.call("HDEL", "wallet:" .. id .. ":holds", hold_id)
+        table.insert(wallets, tonumber(id))
+    end
+    redis.call("ZREM", KEYS[1], member)
+end
+return wallets
+"#;
+
+#[derive(Clone)]
+pub struct RedisDatabase {
+    client: Client,
+    add_transaction: Script,
+    add_transactions: Script,
+    transfer: Script,
+    reverse_transaction: Script,
+    update_limit: Script,
+    close_account: Script,
+    seed_account: Script,
+    place_hold: Script,
+    capture_hold: Script,
+    release_hold: Script,
+    expire_holds: Script,
+    events: broadcast::Sender<AccountEvent>,
+    // stops forwarding the account events on close
+    listener: Option<AbortHandle>,
+    statement_size: isize,
+}
+
+impl RedisDatabase {
+    /// Account events are only forwarded when enabled, the subscription holds a
+    /// connection open.
+    pub fn new(client: Client, statement_size: u32, account_events: bool) -> Self {
+        let events = events_channel();
+        let listener = account_events
+            .then(|| tokio::spawn(listen(client.clone(), events.clone())).abort_handle());
+        Self {
+            client,
+            add_transaction: Script::new(ADD_TRANSACTION),
+            add_transactions: Script::new(ADD_TRANSACTIONS),
+            transfer: Script::new(TRANSFER),
+            reverse_transaction: Script::new(REVERSE_TRANSACTION),
+            update_limit: Script::new(UPDATE_LIMIT),
+            close_account: Script::new(CLOSE_ACCOUNT),
+            seed_account: Script::new(SEED_ACCOUNT),
+            place_hold: Script::new(PLACE_HOLD),
+            capture_hold: Script::new(CAPTURE_HOLD),
+            release_hold: Script::new(RELEASE_HOLD),
+            expire_holds: Script::new(EXPIRE_HOLDS),
+            events,
+            listener,
+            statement_size: statement_size as isize,
+        }
+    }
+
+    /// Reads a pending hold, telling a missing wallet from a missing hold.
+    async fn get_hold(&self, id: u32, hold_id: &str) -> Result<Hold, ServerError> {
+        let mut connection = self.client.get_async_connection().await?;
+        let stored: Option<String> = connection
+            .hget(format!("wallet:{id}:holds"), hold_id)
+            .await?;
+        match stored {
+            Some(stored) => Ok(serde_json::from_str(&stored)?),
+            None if connection.exists(format!("wallet:{id}")).await? => {
+                Err(ServerError::HoldNotFound(hold_id.to_string()))
+            }
+            None => Err(ServerError::UserNotFound(id)),
+        }
+    }
+}
+
+impl TransactionRepository for RedisDatabase {
+    async fn add_transaction(
+        &self,
+        id: u32,
+        mut transaction: Transaction,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        transaction.id = Some(new_id());
+        let mut connection = self.client.get_async_connection().await?;
+        let result: Vec<i64> = self
+            .add_transaction
+            .key(format!("wallet:{id}"))
+            .key(format!("wallet:{id}:transactions"))
+            .arg(transaction.value()?)
+            .arg(serde_json::to_string(&transaction)?)
+            .arg(self.statement_size)
+            .invoke_async(&mut connection)
+            .await?;
+        Ok((to_balance(id, &result)?, transaction))
+    }
+
+    async fn add_idempotent_transaction(
+        &self,
+        id: u32,
+        mut transaction: Transaction,
+        key: &str,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        transaction.id = Some(new_id());
+        let mut connection = self.client.get_async_connection().await?;
+        let result: Vec<i64> = self
+            .add_transaction
+            .key(format!("wallet:{id}"))
+            .key(format!("wallet:{id}:transactions"))
+            .key(format!("idempotency:{key}"))
+            .arg(transaction.value()?)
+            .arg(serde_json::to_string(&transaction)?)
+            .arg(self.statement_size)
+            .arg(transaction.fingerprint(id))
+            .arg(IDEMPOTENCY_KEY_TTL)
+            .invoke_async(&mut connection)
+            .await?;
+        let balance = to_balance(id, &result)?;
+        if let [_, _, _, 1] = result[..] {
+            // keys stored before transactions were kept with them only hold the balance
+            let stored: Option<String> = connection
+                .hget(format!("idempotency:{key}"), "transaction")
+                .await?;
+            transaction = match stored {
+                Some(stored) => serde_json::from_str(&stored)?,
+                None => Transaction {
+                    id: None,
+                    ..transaction
+                },
+            };
+        }
+        Ok((balance, transaction))
+    }
+
+    async fn add_transactions(
+        &self,
+        id: u32,
+        transactions: Vec<Transaction>,
+        mode: BatchMode,
+    ) -> Result<BatchOutcome, ServerError> {
+        let transactions: Vec<Transaction> = transactions
+            .into_iter()
+            .map(|transaction| Transaction {
+                id: Some(new_id()),
+                ..transaction
+            })
+            .collect();
+        let mut invocation = self.add_transactions.key(format!("wallet:{id}"));
+        invocation
+            .key(format!("wallet:{id}:transactions"))
+            .arg(mode == BatchMode::BestEffort)
+            .arg(self.statement_size);
+        for transaction in &transactions {
+            invocation
+                .arg(transaction.value()?)
+                .arg(serde_json::to_string(transaction)?);
+        }
+        let mut connection = self.client.get_async_connection().await?;
+        let result: Vec<i64> = invocation.invoke_async(&mut connection).await?;
+        match result[..] {
+            [0, total, limit, held, ref applied @ ..] => Ok(BatchOutcome {
+                balance: Balance {
+                    total: checked(total)?,
+                    limit: checked(limit)?,
+                    statement_date: Some(Utc::now()),
+                    held: Some(checked(held)?),
+                },
+                results: transactions
+                    .into_iter()
+                    .zip(applied)
+                    .map(|(transaction, applied)| match applied {
+                        1 => Ok(transaction),
+                        _ => Err(ServerError::TransactionWouldExceedLimit),
+                    })
+                    .collect(),
+            }),
+            [2, index] => Err(ServerError::BatchRejected(
+                index as usize,
+                Box::new(ServerError::TransactionWouldExceedLimit),
+            )),
+            [4] => Err(ServerError::AccountClosed),
+            _ => Err(ServerError::UserNotFound(id)),
+        }
+    }
+
+    async fn transfer(&self, id: u32, transfer: Transfer) -> Result<Balance, ServerError> {
+        let to = transfer.to;
+        let transfer_id = Some(new_id());
+        let debit = Transaction {
+            id: Some(new_id()),
+            transfer_id: transfer_id.clone(),
+            ..transfer.debit()
+        };
+        let credit = Transaction {
+            id: Some(new_id()),
+            transfer_id,
+            ..transfer.credit()
+        };
+        let mut connection = self.client.get_async_connection().await?;
+        let result: Vec<i64> = self
+            .transfer
+            .key(format!("wallet:{id}"))
+            .key(format!("wallet:{id}:transactions"))
+            .key(format!("wallet:{to}"))
+            .key(format!("wallet:{to}:transactions"))
+            .arg(transfer.value)
+            .arg(serde_json::to_string(&debit)?)
+            .arg(serde_json::to_string(&credit)?)
+            .arg(self.statement_size)
+            .invoke_async(&mut connection)
+            .await?;
+        match result[..] {
+            // tell which wallet is missing
+            [1] if connection.exists(format!("wallet:{id}")).await? => {
+                Err(ServerError::UserNotFound(to))
+            }
+            _ => to_balance(id, &result),
+        }
+    }
+
+    async fn reverse_transaction(
+        &self,
+        id: u32,
+        transaction_id: &str,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        let original = self.get_transaction(id, transaction_id).await?;
+        if !original.is_reversible() {
+            return Err(ServerError::TransactionNotReversible);
+        }
+        let reversal = Transaction {
+            id: Some(new_id()),
+            ..original.reversal()
+        };
+        let mut connection = self.client.get_async_connection().await?;
+        let result: Vec<i64> = self
+            .reverse_transaction
+            .key(format!("wallet:{id}"))
+            .key(format!("wallet:{id}:transactions"))
+            .arg(reversal.value()?)
+            .arg(serde_json::to_string(&reversal)?)
+            .arg(self.statement_size)
+            .arg(transaction_id)
+            .invoke_async(&mut connection)
+            .await?;
+        match result[..] {
+            [6] => Err(ServerError::TransactionNotFound(transaction_id.to_string())),
+            [7] => Err(ServerError::TransactionAlreadyReversed),
+            _ => Ok((to_balance(id, &result)?, reversal)),
+        }
+    }
+
+    async fn get_transaction(
+        &self,
+        id: u32,
+        transaction_id: &str,
+    ) -> Result<Transaction, ServerError> {
+        // only the last transactions are kept
+        let stmt = self.get_statement(&id).await?;
+        stmt.last_transactions
+            .into_iter()
+            .find(|transaction| transaction.id.as_deref() == Some(transaction_id))
+            .ok_or_else(|| ServerError::TransactionNotFound(transaction_id.to_string()))
+    }
+
+    async fn place_hold(&self, id: u32, mut hold: Hold) -> Result<(Balance, Hold), ServerError> {
+        let hold_id = new_id();
+        hold.id = Some(hold_id.clone());
+        let mut connection = self.client.get_async_connection().await?;
+        let result: Vec<i64> = self
+            .place_hold
+            .key(format!("wallet:{id}"))
+            .key(format!("wallet:{id}:holds"))
+            .key(HOLDS_EXPIRY)
+            .arg(hold.value)
+            .arg(&hold_id)
+            .arg(serde_json::to_string(&hold)?)
+            .arg(hold.expires_at.timestamp_millis())
+            .arg(format!("{id}:{hold_id}"))
+            .invoke_async(&mut connection)
+            .await?;
+        Ok((to_held_balance(id, &hold_id, &result)?, hold))
+    }
+
+    async fn capture_hold(
+        &self,
+        id: u32,
+        hold_id: &str,
+        value: Option<u32>,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        let hold = self.get_hold(id, hold_id).await?;
+        if hold.is_expired() {
+            return Err(ServerError::HoldNotFound(hold_id.to_string()));
+        }
+        let transaction = Transaction {
+            id: Some(new_id()),
+            ..hold.capture(value)?
+        };
+        let mut connection = self.client.get_async_connection().await?;
+        let result: Vec<i64> = self
+            .capture_hold
+            .key(format!("wallet:{id}"))
+            .key(format!("wallet:{id}:transactions"))
+            .key(format!("wallet:{id}:holds"))
+            .key(HOLDS_EXPIRY)
+            .arg(hold_id)
+            .arg(transaction.value)
+            .arg(serde_json::to_string(&transaction)?)
+            .arg(hold.value)
+            .arg(format!("{id}:{hold_id}"))
+            .arg(Utc::now().timestamp_millis())
+            .arg(self.statement_size)
+            .invoke_async(&mut connection)
+            .await?;
+        Ok((to_held_balance(id, hold_id, &result)?, transaction))
+    }
+
+    async fn release_hold(&self, id: u32, hold_id: &str) -> Result<(Balance, Hold), ServerError> {
+        let hold = self.get_hold(id, hold_id).await?;
+        let mut connection = self.client.get_async_connection().await?;
+        let result: Vec<i64> = self
+            .release_hold
+            .key(format!("wallet:{id}"))
+            .key(format!("wallet:{id}:holds"))
+            .key(HOLDS_EXPIRY)
+            .arg(hold_id)
+            .arg(format!("{id}:{hold_id}"))
+            .invoke_async(&mut connection)
+            .await?;
+        Ok((to_held_balance(id, hold_id, &result)?, hold))
+    }
+
+    async fn expire_holds(&self) -> Result<Vec<u32>, ServerError> {
+        let mut connection = self.client.get_async_connection().await?;
+        let mut wallets: Vec<u32> = self
+            .expire_holds
+            .key(HOLDS_EXPIRY)
+            .arg(Utc::now().timestamp_millis())
+            .invoke_async(&mut connection)
+            .await?;
+        wallets.sort_unstable();
+        wallets.dedup();
+        Ok(wallets)
+    }
+
+    async fn get_statement(&self, id: &u32) -> Result<Statement, ServerError> {
+        let mut connection = self.client.get_async_connection().await?;
+        let ((total, limit, held), transactions): (WalletFields, Vec<String>) = redis::pipe()
+            .atomic()
+            .hget(format!("wallet:{id}"), &["total", "limit", "held"])
+            .lrange(format!("wallet:{id}:transactions"), 0, self.statement_size - 1)
+            .query_async(&mut connection)
+            .await?;
+        let (Some(total), Some(limit)) = (total, limit) else {
+            return Err(ServerError::UserNotFound(*id));
+        };
+
+        Ok(Statement {
+            balance: Balance {
+                total,
+                limit,
+                statement_date: Some(Utc::now()),
+                held: Some(held.unwrap_or_default()),
+            },
+            last_transactions: transactions
+                .iter()
+                .map(|transaction| serde_json::from_str(transaction))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    async fn get_history(&self, id: u32, query: HistoryQuery) -> Result<HistoryPage, ServerError> {
+        // only the last transactions are kept
+        let stmt = self.get_statement(&id).await?;
+        HistoryPage::from_window(stmt.last_transactions, &query)
+    }
+
+    async fn create_account(&self, limit: u32) -> Result<Account, ServerError> {
+        let mut connection = self.client.get_async_connection().await?;
+        let id: u32 = connection.incr(LAST_WALLET_ID, 1).await?;
+        connection
+            .hset_multiple::<_, _, _, ()>(format!("wallet:{id}"), &[("limit", limit), ("total", 0)])
+            .await?;
+        Ok(Account {
+            id,
+            limit,
+            total: 0,
+            closed_at: None,
+        })
+    }
+
+    async fn seed_account(&self, id: u32, limit: u32) -> Result<bool, ServerError> {
+        let mut connection = self.client.get_async_connection().await?;
+        let seeded: bool = self
+            .seed_account
+            .key(format!("wallet:{id}"))
+            .key(LAST_WALLET_ID)
+            .arg(id)
+            .arg(limit)
+            .invoke_async(&mut connection)
+            .await?;
+        Ok(seeded)
+    }
+
+    async fn get_account(&self, id: u32) -> Result<Account, ServerError> {
+        let mut connection = self.client.get_async_connection().await?;
+        let (total, limit, closed_at): (Option<i32>, Option<u32>, Option<i64>) = connection
+            .hget(format!("wallet:{id}"), &["total", "limit", "closed_at"])
+            .await?;
+        let (Some(total), Some(limit)) = (total, limit) else {
+            return Err(ServerError::UserNotFound(id));
+        };
+        Ok(Account {
+            id,
+            limit,
+            total,
+            closed_at: closed_at.and_then(DateTime::from_timestamp_millis),
+        })
+    }
+
+    async fn update_limit(&self, id: u32, limit: u32) -> Result<Account, ServerError> {
+        let mut connection = self.client.get_async_connection().await?;
+        let result: Vec<i64> = self
+            .update_limit
+            .key(format!("wallet:{id}"))
+            .arg(limit)
+            .invoke_async(&mut connection)
+            .await?;
+        let balance = to_balance(id, &result)?;
+        Ok(Account {
+            id,
+            limit: balance.limit,
+            total: balance.total,
+            closed_at: None,
+        })
+    }
+
+    async fn close_account(&self, id: u32) -> Result<Account, ServerError> {
+        let mut connection = self.client.get_async_connection().await?;
+        let closed: bool = self
+            .close_account
+            .key(format!("wallet:{id}"))
+            .arg(Utc::now().timestamp_millis())
+            .invoke_async(&mut connection)
+            .await?;
+        if !closed {
+            return Err(ServerError::UserNotFound(id));
+        }
+        self.get_account(id).await
+    }
+
+    async fn ping(&self) -> Result<(), ServerError> {
+        let mut connection = self.client.get_async_connection().await?;
+        redis::cmd("PING").query_async::<_, ()>(&mut connection).await?;
+        Ok(())
+    }
+
+    // connections are opened per call, only the listener is left open
+    async fn close(&self) {
+        if let Some(listener) = &self.listener {
+            listener.abort();
+        }
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<AccountEvent> {
+        self.events.subscribe()
+    }
+}
+
+/// Transaction ids are generated by the client, so scripts do not need a counter.
+fn new_id() -> String {
+    bson::oid::ObjectId::new().to_hex()
+}
+
+fn to_balance(id: u32, result: &[i64]) -> Result<Balance, ServerError> {
+    match result {
+        [0, total, limit, ..] => Ok(Balance {
+            total: checked(*total)?,
+            limit: checked(*limit)?,
+            statement_date: Some(Utc::now()),
+            held: None,
+        }),
+        [2] => Err(ServerError::TransactionWouldExceedLimit),
+        [3] => Err(ServerError::IdempotencyKeyConflict),
+        [4] => Err(ServerError::AccountClosed),
+        [5] => Err(ServerError::LimitBelowBalance),
+        _ => Err(ServerError::UserNotFound(id)),
+    }
+}
+
+/// Lua numbers are doubles, refuse the ones that do not fit instead of wrapping.
+fn checked<T: TryFrom<i64>>(value: i64) -> Result<T, ServerError> {
+    T::try_from(value).map_err(|_| {
+        let detail = format!("{value} is out of range");
+        redis::RedisError::from((redis::ErrorKind::TypeError, "Invalid script result", detail))
+            .into()
+    })
+}
+
+/// Balance returned by the hold scripts, which also return the held part.
+fn to_held_balance(id: u32, hold_id: &str, result: &[i64]) -> Result<Balance, ServerError> {
+    match result {
+        [0, _, _, held] => Ok(Balance {
+            held: Some(checked(*held)?),
+            ..to_balance(id, result)?
+        }),
+        [6] => Err(ServerError::HoldNotFound(hold_id.to_string())),
+        _ => to_balance(id, result),
+    }
+}
+
+/// Forwards the transactions published by every instance to the local subscribers.
+async fn listen(client: Client, events: broadcast::Sender<AccountEvent>) {
+    loop {
+        if let Err(e) = forward_messages(&client, &events).await {
+            tracing::error!("Failed to listen to {ACCOUNT_ACTIVITY}: {e}");
+        }
+        tokio::time::sleep(LISTEN_RETRY_INTERVAL).await;
+    }
+}
+
+/// Returns once the connection is closed.
+async fn forward_messages(
+    client: &Client,
+    events: &broadcast::Sender<AccountEvent>,
+) -> Result<(), ServerError> {
+    let mut pubsub = client.get_async_connection().await?.into_pubsub();
+    pubsub.subscribe(ACCOUNT_ACTIVITY).await?;
+    let mut messages = pubsub.on_message();
+    while let Some(message) = messages.next().await {
+        let payload: String = message.get_payload()?;
+        match serde_json::from_str::<AccountEvent>(&payload) {
+            // no subscribers is not an error
+            Ok(event) => _ = events.send(event),
+            Err(e) => tracing::warn!("Invalid {ACCOUNT_ACTIVITY} message: {e}"),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,911 @@
+use super::{
+    events_channel, Account, AccountEvent, Balance, BatchMode, BatchOutcome, HistoryCursor,
+    HistoryPage, HistoryQuery, Hold, Statement, Transaction, TransactionRepository,
+    TransactionType, Transfer,
+};
+use chrono::{NaiveDateTime, Utc};
+use sqlx::{Connection, Pool, Sqlite, SqliteConnection};
+use tokio::sync::broadcast;
+
+use crate::error::ServerError;
+
+/// Message raised by the `wallet_closed` trigger.
+const WALLET_CLOSED: &str = "wallet is closed";
+/// Message raised by the `wallet_held_limit` trigger.
+const WALLET_LIMIT_EXCEEDED: &str = "wallet limit exceeded";
+
+/// The pool, the subscribers to the account activity and the statement size.
+#[derive(Clone)]
+pub struct SqliteDatabase(Pool<Sqlite>, broadcast::Sender<AccountEvent>, u32);
+
+struct WalletRow {
+    id: i64,
+    limit: i64,
+    total: i64,
+    closed_at: Option<NaiveDateTime>,
+}
+
+impl From<WalletRow> for Account {
+    fn from(wallet: WalletRow) -> Self {
+        Self {
+            id: wallet.id as u32,
+            limit: wallet.limit as u32,
+            total: wallet.total as i32,
+            closed_at: wallet.closed_at.map(|date| date.and_utc()),
+        }
+    }
+}
+
+impl TransactionRepository for SqliteDatabase {
+    async fn add_transaction(
+        &self,
+        id: u32,
+        transaction: Transaction,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        let mut tx = self.0.begin().await?;
+        let applied = apply_transaction(&mut tx, id, &transaction).await?;
+        tx.commit().await?;
+        self.publish(id, &applied);
+        Ok(applied)
+    }
+
+    async fn add_idempotent_transaction(
+        &self,
+        id: u32,
+        transaction: Transaction,
+        key: &str,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        let fingerprint = transaction.fingerprint(id);
+        if let Some(stored) = self
+            .find_idempotency_key(key, &fingerprint, &transaction)
+            .await?
+        {
+            return Ok(stored);
+        }
+
+        let mut tx = self.0.begin().await?;
+        let (balance, stored) = apply_transaction(&mut tx, id, &transaction).await?;
+        let transaction_id: Option<i64> = stored.id.as_deref().and_then(|id| id.parse().ok());
+        let inserted = sqlx::query!(
+            r#"INSERT INTO idempotency_key ("key", wallet_id, fingerprint, total, "limit", transaction_id)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT ("key") DO NOTHING;"#,
+            key,
+            id,
+            fingerprint,
+            balance.total,
+            balance.limit,
+            transaction_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if inserted.rows_affected() == 0 {
+            // a concurrent request with the same key won, discard this one
+            tx.rollback().await?;
+            return self
+                .find_idempotency_key(key, &fingerprint, &transaction)
+                .await?
+                .ok_or(ServerError::IdempotencyKeyConflict);
+        }
+        tx.commit().await?;
+        let applied = (balance, stored);
+        self.publish(id, &applied);
+        Ok(applied)
+    }
+
+    async fn add_transactions(
+        &self,
+        id: u32,
+        transactions: Vec<Transaction>,
+        mode: BatchMode,
+    ) -> Result<BatchOutcome, ServerError> {
+        let mut tx = self.0.begin().await?;
+        let mut results = Vec::with_capacity(transactions.len());
+        let mut applied_transactions = Vec::with_capacity(transactions.len());
+        for (index, transaction) in transactions.iter().enumerate() {
+            let applied = match mode {
+                BatchMode::AllOrNothing => apply_transaction(&mut tx, id, transaction).await,
+                BatchMode::BestEffort => apply_in_savepoint(&mut tx, id, transaction).await,
+            };
+            match applied {
+                Ok(applied) => {
+                    results.push(Ok(applied.1.clone()));
+                    applied_transactions.push(applied);
+                }
+                Err(e) => results.push(Err(mode.on_failure(index, e)?)),
+            }
+        }
+        let wallet_id = id as i64;
+        let balance = sqlx::query!(
+            r#"SELECT total, "limit", held FROM wallet WHERE id = ?1;"#,
+            wallet_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ServerError::UserNotFound(id))?;
+        tx.commit().await?;
+        for applied in &applied_transactions {
+            self.publish(id, applied);
+        }
+        Ok(BatchOutcome {
+            balance: Balance {
+                total: balance.total as i32,
+                limit: balance.limit as u32,
+                statement_date: Some(Utc::now()),
+                held: Some(balance.held as u32),
+            },
+            results,
+        })
+    }
+
+    async fn transfer(&self, id: u32, transfer: Transfer) -> Result<Balance, ServerError> {
+        let (debit, credit) = (transfer.debit(), transfer.credit());
+        let mut tx = self.0.begin().await?;
+        let balance = update_balance(&mut tx, id, debit.value()?).await?;
+        let payee = update_balance(&mut tx, transfer.to, credit.value()?).await?;
+        let transfer_id = sqlx::query!(
+            r#"INSERT INTO transfer (from_wallet_id, to_wallet_id, amount, description)
+            VALUES (?1, ?2, ?3, ?4)
+            RETURNING id;"#,
+            id,
+            transfer.to,
+            transfer.value,
+            transfer.description
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .id;
+        let debit = insert_transaction(&mut tx, id, &debit, Some(transfer_id), None).await?;
+        let credit =
+            insert_transaction(&mut tx, transfer.to, &credit, Some(transfer_id), None).await?;
+        tx.commit().await?;
+        self.publish(id, &(balance.clone(), debit));
+        self.publish(transfer.to, &(payee, credit));
+        Ok(balance)
+    }
+
+    async fn get_transaction(
+        &self,
+        id: u32,
+        transaction_id: &str,
+    ) -> Result<Transaction, ServerError> {
+        let transaction = match transaction_id.parse::<i64>() {
+            Ok(transaction_id) => {
+                sqlx::query!(
+                    r#"SELECT
+                        id as "id!",
+                        amount,
+                        "type" as "type: TransactionType",
+                        "description",
+                        created_at,
+                        reversal_of,
+                        transfer_id
+                    FROM "transaction"
+                    WHERE id = ?1 AND wallet_id = ?2;"#,
+                    transaction_id,
+                    id
+                )
+                .fetch_optional(&self.0)
+                .await?
+            }
+            Err(_) => None,
+        };
+        let Some(t) = transaction else {
+            let wallet = sqlx::query!("SELECT id FROM wallet WHERE id = ?1;", id)
+                .fetch_optional(&self.0)
+                .await?;
+            return Err(match wallet {
+                Some(_) => ServerError::TransactionNotFound(transaction_id.to_string()),
+                None => ServerError::UserNotFound(id),
+            });
+        };
+        Ok(Transaction {
+            id: Some(t.id.to_string()),
+            value: t.amount as u32,
+            transaction_type: t.r#type,
+            description: t.description,
+            date: t.created_at.and_utc(),
+            reverses: t.reversal_of.map(|id| id.to_string()),
+            transfer_id: t.transfer_id.map(|id| id.to_string()),
+        })
+    }
+
+    async fn place_hold(&self, id: u32, hold: Hold) -> Result<(Balance, Hold), ServerError> {
+        let mut tx = self.0.begin().await?;
+        // closed wallets are refused by the `wallet_closed_hold` trigger
+        let balance = update_held(&mut tx, id, hold.value as i64).await?;
+        let (created_at, expires_at) = (hold.created_at.naive_utc(), hold.expires_at.naive_utc());
+        let hold_id = sqlx::query!(
+            r#"INSERT INTO hold (wallet_id, amount, "description", created_at, expires_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            RETURNING id;"#,
+            id,
+            hold.value,
+            hold.description,
+            created_at,
+            expires_at
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .id;
+        tx.commit().await?;
+        let hold = Hold {
+            id: Some(hold_id.to_string()),
+            ..hold
+        };
+        Ok((balance, hold))
+    }
+
+    async fn capture_hold(
+        &self,
+        id: u32,
+        hold_id: &str,
+        value: Option<u32>,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        let mut tx = self.0.begin().await?;
+        let hold = delete_hold(&mut tx, id, hold_id, Some(Utc::now().naive_utc())).await?;
+        let transaction = hold.capture(value)?;
+        let balance = sqlx::query!(
+            r#"UPDATE wallet SET total = total - ?2, held = held - ?3
+            WHERE id = ?1
+            RETURNING "limit", total, held;"#,
+            id,
+            transaction.value,
+            hold.value
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| map_wallet_error(e, ServerError::TransactionWouldExceedLimit))?;
+        let transaction = insert_transaction(&mut tx, id, &transaction, None, None).await?;
+        tx.commit().await?;
+        let balance = Balance {
+            total: balance.total as i32,
+            limit: balance.limit as u32,
+            statement_date: Some(Utc::now()),
+            held: Some(balance.held as u32),
+        };
+        let applied = (balance, transaction);
+        self.publish(id, &applied);
+        Ok(applied)
+    }
+
+    async fn release_hold(&self, id: u32, hold_id: &str) -> Result<(Balance, Hold), ServerError> {
+        let mut tx = self.0.begin().await?;
+        let hold = delete_hold(&mut tx, id, hold_id, None).await?;
+        let balance = update_held(&mut tx, id, -(hold.value as i64)).await?;
+        tx.commit().await?;
+        Ok((balance, hold))
+    }
+
+    /// Each wallet is released in its own transaction, so the sweep never locks
+    /// the database for long.
+    async fn expire_holds(&self) -> Result<Vec<u32>, ServerError> {
+        let now = Utc::now().naive_utc();
+        let wallets = sqlx::query_scalar!(
+            "SELECT DISTINCT wallet_id FROM hold WHERE expires_at <= ?1;",
+            now
+        )
+        .fetch_all(&self.0)
+        .await?;
+        for wallet_id in &wallets {
+            let mut tx = self.0.begin().await?;
+            sqlx::query!(
+                r#"UPDATE wallet
+                SET held = held - (
+                    SELECT COALESCE(SUM(amount), 0) FROM hold
+                    WHERE wallet_id = ?1 AND expires_at <= ?2
+                )
+                WHERE id = ?1;"#,
+                wallet_id,
+                now
+            )
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "DELETE FROM hold WHERE wallet_id = ?1 AND expires_at <= ?2;",
+                wallet_id,
+                now
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+        }
+        Ok(wallets.into_iter().map(|id| id as u32).collect())
+    }
+
+    async fn get_statement(&self, id: &u32) -> Result<Statement, ServerError> {
+        let id = *id as i64;
+        let balance = sqlx::query!(
+            r#"SELECT total, "limit", held FROM wallet WHERE id = ?1;"#,
+            id
+        )
+        .fetch_one(&self.0)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => ServerError::UserNotFound(id as u32),
+            _ => e.into(),
+        })?;
+        let balance = Balance {
+            total: balance.total as i32,
+            limit: balance.limit as u32,
+            statement_date: Some(Utc::now()),
+            held: Some(balance.held as u32),
+        };
+        let transactions = sqlx::query!(
+            r#"SELECT
+                id as "id!",
+                amount,
+                "type" as "type: TransactionType",
+                "description",
+                created_at,
+                reversal_of,
+                transfer_id
+            FROM "transaction"
+            WHERE
+                wallet_id = ?1
+            ORDER BY created_at DESC, id DESC
+            LIMIT ?2;"#,
+            id,
+            self.2
+        )
+        .fetch_all(&self.0)
+        .await?;
+
+        Ok(Statement {
+            balance,
+            last_transactions: transactions
+                .into_iter()
+                .map(|t| Transaction {
+                    id: Some(t.id.to_string()),
+                    value: t.amount as u32,
+                    transaction_type: t.r#type,
+                    description: t.description,
+                    date: t.created_at.and_utc(),
+                    reverses: t.reversal_of.map(|id| id.to_string()),
+                    transfer_id: t.transfer_id.map(|id| id.to_string()),
+                })
+                .collect(),
+        })
+    }
+
+    async fn get_history(&self, id: u32, query: HistoryQuery) -> Result<HistoryPage, ServerError> {
+        let cursor = match &query.cursor {
+            Some(cursor) => {
+                let cursor_id: i64 = cursor.id.parse().map_err(|_| ServerError::InvalidCursor)?;
+                Some((cursor.date.naive_utc(), cursor_id))
+            }
+            None => None,
+        };
+        let from = query.from.map(|date| date.naive_utc());
+        let to = query.to.map(|date| date.naive_utc());
+        let cursor_date = cursor.map(|(date, _)| date);
+        let cursor_id = cursor.map(|(_, id)| id);
+        // one extra row tells whether there is a next page
+        let limit = query.limit as i64 + 1;
+        let mut transactions = sqlx::query!(
+            r#"SELECT
+                id as "id!",
+                amount,
+                "type" as "type: TransactionType",
+                "description",
+                created_at,
+                reversal_of,
+                transfer_id
+            FROM "transaction"
+            WHERE
+                wallet_id = ?1
+                AND (?2 IS NULL OR created_at >= ?2)
+                AND (?3 IS NULL OR created_at < ?3)
+                AND (?4 IS NULL OR "type" = ?4)
+                AND (?5 IS NULL OR instr(lower("description"), lower(?5)) > 0)
+                AND (?6 IS NULL OR (created_at, id) < (?6, ?7))
+            ORDER BY created_at DESC, id DESC
+            LIMIT ?8;"#,
+            id,
+            from,
+            to,
+            query.transaction_type,
+            query.description,
+            cursor_date,
+            cursor_id,
+            limit
+        )
+        .fetch_all(&self.0)
+        .await?;
+
+        if transactions.is_empty() {
+            let wallet = sqlx::query!("SELECT id FROM wallet WHERE id = ?1;", id)
+                .fetch_optional(&self.0)
+                .await?;
+            if wallet.is_none() {
+                return Err(ServerError::UserNotFound(id));
+            }
+        }
+        let mut next_cursor = None;
+        if transactions.len() > query.limit as usize {
+            transactions.truncate(query.limit as usize);
+            next_cursor = transactions
+                .last()
+                .map(|last| HistoryCursor::new(last.created_at.and_utc(), last.id));
+        }
+        Ok(HistoryPage {
+            transactions: transactions
+                .into_iter()
+                .map(|t| Transaction {
+                    id: Some(t.id.to_string()),
+                    value: t.amount as u32,
+                    transaction_type: t.r#type,
+                    description: t.description,
+                    date: t.created_at.and_utc(),
+                    reverses: t.reversal_of.map(|id| id.to_string()),
+                    transfer_id: t.transfer_id.map(|id| id.to_string()),
+                })
+                .collect(),
+            next_cursor,
+        })
+    }
+
+    async fn reverse_transaction(
+        &self,
+        id: u32,
+        transaction_id: &str,
+    ) -> Result<(Balance, Transaction), ServerError> {
+        let not_found = || ServerError::TransactionNotFound(transaction_id.to_string());
+        let original_id: i64 = transaction_id.parse().map_err(|_| not_found())?;
+        let mut tx = self.0.begin().await?;
+        // writing first takes the write lock, a deferred transaction that read first
+        // could not upgrade it while another connection writes
+        let reversal = sqlx::query!(
+            r#"INSERT INTO "transaction" (wallet_id, amount, "type", description, reversal_of)
+            SELECT wallet_id, amount, CASE "type" WHEN 'c' THEN 'd' ELSE 'c' END, description, id
+            FROM "transaction"
+            WHERE id = ?1 AND wallet_id = ?2 AND reversal_of IS NULL AND transfer_id IS NULL
+            RETURNING
+                id,
+                amount,
+                "type" as "type: TransactionType",
+                "description",
+                created_at;"#,
+            original_id,
+            id
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+                ServerError::TransactionAlreadyReversed
+            }
+            _ => e.into(),
+        })?;
+        let Some(reversal) = reversal else {
+            let original = sqlx::query!(
+                r#"SELECT id FROM "transaction" WHERE id = ?1 AND wallet_id = ?2;"#,
+                original_id,
+                id
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+            if original.is_some() {
+                return Err(ServerError::TransactionNotReversible);
+            }
+            let wallet = sqlx::query!("SELECT id FROM wallet WHERE id = ?1;", id)
+                .fetch_optional(&mut *tx)
+                .await?;
+            return Err(match wallet {
+                Some(_) => not_found(),
+                None => ServerError::UserNotFound(id),
+            });
+        };
+        let reversal = Transaction {
+            id: Some(reversal.id.to_string()),
+            date: reversal.created_at.and_utc(),
+            reverses: Some(transaction_id.to_string()),
+            ..Transaction::new(
+                reversal.amount as u32,
+                reversal.r#type,
+                reversal.description,
+            )
+        };
+        let balance = update_balance(&mut tx, id, reversal.value()?).await?;
+        tx.commit().await?;
+        let applied = (balance, reversal);
+        self.publish(id, &applied);
+        Ok(applied)
+    }
+
+    async fn create_account(&self, limit: u32) -> Result<Account, ServerError> {
+        let wallet = sqlx::query_as!(
+            WalletRow,
+            r#"INSERT INTO wallet ("limit") VALUES (?1)
+            RETURNING id as "id!", "limit", total, closed_at as "closed_at: _";"#,
+            limit
+        )
+        .fetch_one(&self.0)
+        .await?;
+        Ok(wallet.into())
+    }
+
+    // wallet ids are rowids, new ones already start above the highest
+    async fn seed_account(&self, id: u32, limit: u32) -> Result<bool, ServerError> {
+        let seeded = sqlx::query!(
+            r#"INSERT INTO wallet (id, "limit") VALUES (?1, ?2) ON CONFLICT (id) DO NOTHING;"#,
+            id,
+            limit
+        )
+        .execute(&self.0)
+        .await?;
+        Ok(seeded.rows_affected() == 1)
+    }
+
+    async fn get_account(&self, id: u32) -> Result<Account, ServerError> {
+        let wallet = sqlx::query_as!(
+            WalletRow,
+            r#"SELECT id, "limit", total, closed_at as "closed_at: _" FROM wallet WHERE id = ?1;"#,
+            id
+        )
+        .fetch_optional(&self.0)
+        .await?
+        .ok_or(ServerError::UserNotFound(id))?;
+        Ok(wallet.into())
+    }
+
+    async fn update_limit(&self, id: u32, limit: u32) -> Result<Account, ServerError> {
+        let wallet = sqlx::query_as!(
+            WalletRow,
+            r#"UPDATE wallet SET "limit" = ?2 WHERE id = ?1
+            RETURNING id as "id!", "limit", total, closed_at as "closed_at: _";"#,
+            id,
+            limit
+        )
+        .fetch_optional(&self.0)
+        .await
+        .map_err(|e| map_wallet_error(e, ServerError::LimitBelowBalance))?
+        .ok_or(ServerError::UserNotFound(id))?;
+        Ok(wallet.into())
+    }
+
+    async fn close_account(&self, id: u32) -> Result<Account, ServerError> {
+        let wallet = sqlx::query_as!(
+            WalletRow,
+            r#"UPDATE wallet SET closed_at = COALESCE(closed_at, CURRENT_TIMESTAMP)
+            WHERE id = ?1
+            RETURNING id as "id!", "limit", total, closed_at as "closed_at: _";"#,
+            id
+        )
+        .fetch_optional(&self.0)
+        .await?
+        .ok_or(ServerError::UserNotFound(id))?;
+        Ok(wallet.into())
+    }
+
+    async fn ping(&self) -> Result<(), ServerError> {
+        let mut connection = self.0.acquire().await?;
+        sqlx::query("SELECT 1").execute(&mut *connection).await?;
+        Ok(())
+    }
+
+    async fn close(&self) {
+        self.0.close().await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<AccountEvent> {
+        self.1.subscribe()
+    }
+}
+
+impl SqliteDatabase {
+    pub fn new(pool: Pool<Sqlite>, statement_size: u32) -> Self {
+        Self(pool, events_channel(), statement_size)
+    }
+
+    /// Hands committed transactions to the subscribers. The database file is not
+    /// shared with other instances, so there is nothing else to listen to.
+    fn publish(&self, id: u32, (balance, transaction): &(Balance, Transaction)) {
+        // no subscribers is not an error
+        let _ = self.1.send(AccountEvent::new(id, balance, transaction));
+    }
+
+    /// Returns what the request that created the key returned. Keys created before
+    /// transactions were linked to them only return the balance.
+    async fn find_idempotency_key(
+        &self,
+        key: &str,
+        fingerprint: &str,
+        transaction: &Transaction,
+    ) -> Result<Option<(Balance, Transaction)>, ServerError> {
+        let stored = sqlx::query!(
+            r#"SELECT
+                k.fingerprint,
+                k.total,
+                k."limit",
+                t.id as "id?: i64",
+                t.created_at as "created_at?: NaiveDateTime"
+            FROM idempotency_key k
+            LEFT JOIN "transaction" t ON t.id = k.transaction_id
+            WHERE k."key" = ?1;"#,
+            key
+        )
+        .fetch_optional(&self.0)
+        .await?;
+        match stored {
+            Some(stored) if stored.fingerprint != fingerprint => {
+                Err(ServerError::IdempotencyKeyConflict)
+            }
+            Some(stored) => {
+                let balance = Balance {
+                    total: stored.total as i32,
+                    limit: stored.limit as u32,
+                    statement_date: Some(Utc::now()),
+                    held: None,
+                };
+                // same fingerprint, so the request describes the stored transaction
+                let transaction = Transaction {
+                    id: stored.id.map(|id| id.to_string()),
+                    date: stored
+                        .created_at
+                        .map_or(transaction.date, |date| date.and_utc()),
+                    ..transaction.clone()
+                };
+                Ok(Some((balance, transaction)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+async fn apply_transaction(
+    connection: &mut SqliteConnection,
+    id: u32,
+    transaction: &Transaction,
+) -> Result<(Balance, Transaction), ServerError> {
+    let balance = update_balance(&mut *connection, id, transaction.value()?).await?;
+    let transaction = insert_transaction(&mut *connection, id, transaction, None, None).await?;
+    Ok((balance, transaction))
+}
+
+/// Applies the transaction in a savepoint, so a failure leaves the surrounding
+/// transaction usable.
+async fn apply_in_savepoint(
+    connection: &mut SqliteConnection,
+    id: u32,
+    transaction: &Transaction,
+) -> Result<(Balance, Transaction), ServerError> {
+    let mut savepoint = connection.begin().await?;
+    match apply_transaction(&mut savepoint, id, transaction).await {
+        Ok(applied) => {
+            savepoint.commit().await?;
+            Ok(applied)
+        }
+        Err(e) => {
+            savepoint.rollback().await?;
+            Err(e)
+        }
+    }
+}
+
+async fn update_balance(
+    connection: &mut SqliteConnection,
+    id: u32,
+    value: i32,
+) -> Result<Balance, ServerError> {
+    let balance = sqlx::query!(
+        r#"UPDATE wallet SET total = total + ?2 WHERE id = ?1 RETURNING "limit", total;"#,
+        id,
+        value
+    )
+    .fetch_one(&mut *connection)
+    .await
+    .map_err(|e| match e {
+        sqlx::Error::RowNotFound => ServerError::UserNotFound(id),
+        _ => map_wallet_error(e, ServerError::TransactionWouldExceedLimit),
+    })?;
+    Ok(Balance {
+        total: balance.total as i32,
+        limit: balance.limit as u32,
+        statement_date: Some(Utc::now()),
+        held: None,
+    })
+}
+
+async fn update_held(
+    connection: &mut SqliteConnection,
+    id: u32,
+    value: i64,
+) -> Result<Balance, ServerError> {
+    let balance = sqlx::query!(
+        r#"UPDATE wallet SET held = held + ?2 WHERE id = ?1 RETURNING "limit", total, held;"#,
+        id,
+        value
+    )
+    .fetch_one(&mut *connection)
+    .await
+    .map_err(|e| match e {
+        sqlx::Error::RowNotFound => ServerError::UserNotFound(id),
+        _ => map_wallet_error(e, ServerError::TransactionWouldExceedLimit),
+    })?;
+    Ok(Balance {
+        total: balance.total as i32,
+        limit: balance.limit as u32,
+        statement_date: Some(Utc::now()),
+        held: Some(balance.held as u32),
+    })
+}
+
+/// Deletes the hold, only while it has not expired at `now` when given.
+async fn delete_hold(
+    connection: &mut SqliteConnection,
+    id: u32,
+    hold_id: &str,
+    now: Option<NaiveDateTime>,
+) -> Result<Hold, ServerError> {
+    let not_found = || ServerError::HoldNotFound(hold_id.to_string());
+    let hold_key: i64 = hold_id.parse().map_err(|_| not_found())?;
+    let hold = sqlx::query!(
+        r#"DELETE FROM hold
+        WHERE id = ?1 AND wallet_id = ?2 AND (?3 IS NULL OR expires_at > ?3)
+        RETURNING
+            amount as "amount!",
+            "description" as "description!",
+            created_at as "created_at!: NaiveDateTime",
+            expires_at as "expires_at!: NaiveDateTime";"#,
+        hold_key,
+        id,
+        now
+    )
+    .fetch_optional(&mut *connection)
+    .await?;
+    let Some(hold) = hold else {
+        let wallet = sqlx::query!("SELECT id FROM wallet WHERE id = ?1;", id)
+            .fetch_optional(&mut *connection)
+            .await?;
+        return Err(match wallet {
+            Some(_) => not_found(),
+            None => ServerError::UserNotFound(id),
+        });
+    };
+    Ok(Hold {
+        id: Some(hold_id.to_string()),
+        value: hold.amount as u32,
+        description: hold.description,
+        created_at: hold.created_at.and_utc(),
+        expires_at: hold.expires_at.and_utc(),
+    })
+}
+
+async fn insert_transaction(
+    connection: &mut SqliteConnection,
+    id: u32,
+    transaction: &Transaction,
+    transfer_id: Option<i64>,
+    reversal_of: Option<i64>,
+) -> Result<Transaction, ServerError> {
+    let inserted = sqlx::query!(
+        r#"INSERT INTO "transaction" (wallet_id, amount, "type", description, transfer_id, reversal_of)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+        RETURNING id, created_at;"#,
+        id,
+        transaction.value,
+        transaction.transaction_type,
+        transaction.description,
+        transfer_id,
+        reversal_of
+    )
+    .fetch_one(&mut *connection)
+    .await?;
+    Ok(Transaction {
+        id: Some(inserted.id.to_string()),
+        date: inserted.created_at.and_utc(),
+        reverses: reversal_of.map(|id| id.to_string()),
+        transfer_id: transfer_id.map(|id| id.to_string()),
+        ..transaction.clone()
+    })
+}
+
+/// Maps the errors raised when a wallet balance or limit is updated, `check_violation`
+/// being what a broken limit constraint means for the caller.
+fn map_wallet_error(e: sqlx::Error, check_violation: ServerError) -> ServerError {
+    match e {
+        sqlx::Error::Database(db_err)
+            if db_err.is_check_violation() || db_err.message() == WALLET_LIMIT_EXCEEDED =>
+        {
+            check_violation
+        }
+        sqlx::Error::Database(db_err) if db_err.message() == WALLET_CLOSED => {
+            ServerError::AccountClosed
+        }
+        _ => e.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use crate::commands::SQLITE_MIGRATOR;
+
+    use super::*;
+
+    const STATEMENT_SIZE: u32 = 10;
+
+    /// The accounts of the original challenge, in a database of their own.
+    async fn seeded() -> SqliteDatabase {
+        // every connection to `sqlite::memory:` opens a different database
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        SQLITE_MIGRATOR.run(&pool).await.unwrap();
+        let database = SqliteDatabase::new(pool, STATEMENT_SIZE);
+        for (id, limit) in (1..).zip([100000, 80000, 1000000, 10000000, 500000]) {
+            database.seed_account(id, limit).await.unwrap();
+        }
+        database
+    }
+
+    #[tokio::test]
+    async fn should_not_exceed_limit() {
+        let database = seeded().await;
+        let transaction = Transaction::new(100001, TransactionType::Withdraw, "debit".to_string());
+        let result = database.add_transaction(1, transaction).await;
+        assert!(matches!(
+            result,
+            Err(ServerError::TransactionWouldExceedLimit)
+        ));
+
+        let transaction = Transaction::new(100000, TransactionType::Withdraw, "debit".to_string());
+        let (balance, _) = database.add_transaction(1, transaction).await.unwrap();
+        assert_eq!(balance.total, -100000);
+    }
+
+    #[tokio::test]
+    async fn should_refuse_closed_accounts() {
+        let database = seeded().await;
+        let hold = Hold::new(1000, "hold".to_string(), chrono::Duration::days(7));
+        let (_, hold) = database.place_hold(2, hold).await.unwrap();
+        let account = database.close_account(2).await.unwrap();
+        assert!(account.closed_at.is_some());
+
+        let transaction = Transaction::new(100, TransactionType::Deposit, "credit".to_string());
+        let result = database.add_transaction(2, transaction).await;
+        assert!(matches!(result, Err(ServerError::AccountClosed)));
+        let result = database.update_limit(2, 1000).await;
+        assert!(matches!(result, Err(ServerError::AccountClosed)));
+        let another = Hold::new(100, "hold".to_string(), chrono::Duration::days(7));
+        let result = database.place_hold(2, another).await;
+        assert!(matches!(result, Err(ServerError::AccountClosed)));
+        // what was held can still be released
+        let (balance, _) = database
+            .release_hold(2, hold.id.as_deref().unwrap())
+            .await
+            .unwrap();
+        assert_eq!((balance.total, balance.held), (0, Some(0)));
+    }
+
+    #[tokio::test]
+    async fn should_reserve_limit_with_holds() {
+        let database = seeded().await;
+        let hold = Hold::new(60000, "hold".to_string(), chrono::Duration::days(7));
+        let (balance, hold) = database.place_hold(1, hold).await.unwrap();
+        assert_eq!((balance.total, balance.held), (0, Some(60000)));
+        // only what the hold left of the limit can be used
+        let transaction = Transaction::new(40001, TransactionType::Withdraw, "debit".to_string());
+        let result = database.add_transaction(1, transaction).await;
+        assert!(matches!(
+            result,
+            Err(ServerError::TransactionWouldExceedLimit)
+        ));
+        let another = Hold::new(40001, "hold".to_string(), chrono::Duration::days(7));
+        let result = database.place_hold(1, another).await;
+        assert!(matches!(
+            result,
+            Err(ServerError::TransactionWouldExceedLimit)
+        ));
+
+        let (balance, _) = database
+            .capture_hold(1, hold.id.as_deref().unwrap(), Some(50000))
+            .await
+            .unwrap();
+        assert_eq!((balance.total, balance.held), (-50000, Some(0)));
+    }
+}
@@ -1,19 +1,69 @@
-use axum::{extract::rejection::JsonRejection, http::StatusCode, response::IntoResponse};
+use std::collections::HashMap;
+
+use axum::{
+    extract::rejection::{JsonRejection, QueryRejection},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::Serialize;
+
+use crate::metrics;
 
 #[derive(thiserror::Error, Debug)]
 pub enum ServerError {
-    #[error("Invalid transaction")]
-    FailedToSerialize(#[from] serde_json::Error),
+    /// Stored or cached data that could not be (de)serialized, never a client payload.
+    #[error("Failed to serialize stored data: {0}")]
+    Serialization(#[from] serde_json::Error),
 
     #[error("User not found {0}")]
     UserNotFound(u32),
 
     #[error("Transaction would exceed limit")]
     TransactionWouldExceedLimit,
+
+    #[error("Transaction not found {0}")]
+    TransactionNotFound(String),
+
+    #[error("Transaction was already reversed")]
+    TransactionAlreadyReversed,
+
+    #[error("Transaction cannot be reversed")]
+    TransactionNotReversible,
+
+    #[error("Hold not found {0}")]
+    HoldNotFound(String),
+
+    #[error("Capture exceeds the held amount")]
+    CaptureExceedsHold,
+
+    #[error("Transaction {0} of the batch failed: {1}")]
+    BatchRejected(usize, Box<ServerError>),
+
+    #[error("Value {0} is out of range")]
+    ValueOutOfRange(u32),
+
+    #[error("Cannot transfer to the same client")]
+    SelfTransfer,
+
+    #[error("Account is closed")]
+    AccountClosed,
+
+    #[error("Limit is below the current negative balance")]
+    LimitBelowBalance,
+
+    #[error("Idempotency key was already used with a different request")]
+    IdempotencyKeyConflict,
+    #[error("Invalid idempotency key")]
+    InvalidIdempotencyKey,
+    #[error("Invalid cursor")]
+    InvalidCursor,
     #[error("Failed to validate: {0}")]
     ValidationError(#[from] validator::ValidationErrors),
     #[error(transparent)]
     AxumFormRejection(#[from] JsonRejection),
+    #[error(transparent)]
+    AxumQueryRejection(#[from] QueryRejection),
 
     #[error(transparent)]
     SqlxError(#[from] sqlx::Error),
@@ -26,21 +76,204 @@ pub enum ServerError {
 
     #[error("Bson error {0}")]
     BsonError(#[from] bson::de::Error),
+
+    #[error("{0}")]
+    SchemaMismatch(String),
+
+    #[error("Not supported: {0}")]
+    Unsupported(&'static str),
 }
 
-impl IntoResponse for ServerError {
-    fn into_response(self) -> axum::response::Response {
-        let status_code = match self {
-            ServerError::UserNotFound(_) => StatusCode::NOT_FOUND,
-            ServerError::RedisError(_) => StatusCode::INTERNAL_SERVER_ERROR,
-            ServerError::ValidationError(_)
-            | ServerError::FailedToSerialize(_)
-            | ServerError::AxumFormRejection(_)
-            | ServerError::SqlxError(_)
+impl ServerError {
+    /// Stable identifier of the error, safe for clients to match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServerError::Serialization(_) => "internal_error",
+            ServerError::UserNotFound(_) => "client_not_found",
+            ServerError::TransactionWouldExceedLimit => "limit_exceeded",
+            ServerError::TransactionNotFound(_) => "transaction_not_found",
+            ServerError::TransactionAlreadyReversed => "transaction_already_reversed",
+            ServerError::TransactionNotReversible => "transaction_not_reversible",
+            ServerError::HoldNotFound(_) => "hold_not_found",
+            ServerError::CaptureExceedsHold => "capture_exceeds_hold",
+            ServerError::BatchRejected(_, e) => e.code(),
+            ServerError::ValueOutOfRange(_) => "value_out_of_range",
+            ServerError::SelfTransfer => "self_transfer",
+            ServerError::AccountClosed => "account_closed",
+            ServerError::LimitBelowBalance => "limit_below_balance",
+            ServerError::IdempotencyKeyConflict => "idempotency_key_conflict",
+            ServerError::InvalidIdempotencyKey => "invalid_idempotency_key",
+            ServerError::InvalidCursor => "invalid_cursor",
+            ServerError::ValidationError(_) => "validation_failed",
+            ServerError::AxumFormRejection(_) => "invalid_payload",
+            ServerError::AxumQueryRejection(_) => "invalid_query",
+            ServerError::Unsupported(_) => "unsupported",
+            ServerError::SqlxError(_)
+            | ServerError::RedisError(_)
             | ServerError::MongoError(_)
             | ServerError::BsonError(_)
+            | ServerError::SchemaMismatch(_) => {
+                if self.is_unavailable() {
+                    "database_unavailable"
+                } else {
+                    "database_error"
+                }
+            }
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ServerError::BatchRejected(_, e) => e.status_code(),
+            ServerError::UserNotFound(_)
+            | ServerError::TransactionNotFound(_)
+            | ServerError::HoldNotFound(_) => StatusCode::NOT_FOUND,
+            ServerError::IdempotencyKeyConflict | ServerError::TransactionAlreadyReversed => {
+                StatusCode::CONFLICT
+            }
+            ServerError::InvalidIdempotencyKey
+            | ServerError::ValidationError(_)
+            | ServerError::AxumFormRejection(_)
+            | ServerError::AxumQueryRejection(_)
+            | ServerError::InvalidCursor
+            | ServerError::ValueOutOfRange(_)
+            | ServerError::SelfTransfer
+            | ServerError::TransactionNotReversible
+            | ServerError::CaptureExceedsHold
+            | ServerError::AccountClosed
+            | ServerError::LimitBelowBalance
             | ServerError::TransactionWouldExceedLimit => StatusCode::UNPROCESSABLE_ENTITY,
+            ServerError::SqlxError(_)
+            | ServerError::RedisError(_)
+            | ServerError::MongoError(_)
+            | ServerError::BsonError(_)
+            | ServerError::SchemaMismatch(_) => {
+                if self.is_unavailable() {
+                    StatusCode::SERVICE_UNAVAILABLE
+                } else {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                }
+            }
+            ServerError::Serialization(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ServerError::Unsupported(_) => StatusCode::NOT_IMPLEMENTED,
+        }
+    }
+
+    /// Whether the backend could not be reached or has no free connections.
+    fn is_unavailable(&self) -> bool {
+        match self {
+            ServerError::SqlxError(e) => matches!(
+                e,
+                sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_)
+            ),
+            ServerError::RedisError(e) => e.is_io_error() || e.is_connection_refusal(),
+            ServerError::MongoError(e) => matches!(
+                e.kind.as_ref(),
+                mongodb::error::ErrorKind::ServerSelection { .. }
+                    | mongodb::error::ErrorKind::ConnectionPoolCleared { .. }
+                    | mongodb::error::ErrorKind::Io(_)
+            ),
+            _ => false,
+        }
+    }
+
+    /// Whether a debit was refused for not fitting in the limit, alone or in a batch.
+    fn is_limit_rejection(&self) -> bool {
+        match self {
+            ServerError::TransactionWouldExceedLimit => true,
+            ServerError::BatchRejected(_, e) => e.is_limit_rejection(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<HashMap<&'static str, Vec<String>>>,
+}
+
+impl IntoResponse for ServerError {
+    fn into_response(self) -> axum::response::Response {
+        if self.is_limit_rejection() {
+            metrics::LIMIT_REJECTIONS.inc();
+        }
+        let details = match &self {
+            ServerError::ValidationError(errors) => Some(
+                errors
+                    .field_errors()
+                    .into_iter()
+                    .map(|(field, errors)| {
+                        let errors = errors.iter().map(|e| e.code.to_string()).collect();
+                        (field, errors)
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        };
+        let status = self.status_code();
+        // backend errors may carry queries, hosts or stored data, keep them in the logs
+        let message = if status.is_server_error() && !matches!(self, ServerError::Unsupported(_)) {
+            tracing::error!(code = self.code(), "{self}");
+            status
+                .canonical_reason()
+                .unwrap_or("Internal Server Error")
+                .to_string()
+        } else {
+            self.to_string()
         };
-        (status_code, self.to_string()).into_response()
+        let body = ErrorBody {
+            code: self.code(),
+            message,
+            details,
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_map_business_errors() {
+        let error = ServerError::TransactionWouldExceedLimit;
+        assert_eq!(error.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error.code(), "limit_exceeded");
+        assert!(ServerError::BatchRejected(1, Box::new(error)).is_limit_rejection());
+        assert!(!ServerError::CaptureExceedsHold.is_limit_rejection());
+
+        let error = ServerError::UserNotFound(6);
+        assert_eq!(error.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(error.code(), "client_not_found");
+    }
+
+    #[test]
+    fn should_map_backend_errors() {
+        let error = ServerError::SqlxError(sqlx::Error::PoolTimedOut);
+        assert_eq!(error.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(error.code(), "database_unavailable");
+
+        let error = ServerError::SqlxError(sqlx::Error::Protocol("unexpected".to_string()));
+        assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(error.code(), "database_error");
+
+        let error: ServerError = serde_json::from_str::<u32>("{").unwrap_err().into();
+        assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(error.code(), "internal_error");
+    }
+
+    #[tokio::test]
+    async fn should_hide_backend_details() {
+        let error = ServerError::SqlxError(sqlx::Error::Protocol("password=secret".to_string()));
+        let response = error.into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(!body.contains("secret"));
+        assert!(body.contains("Internal Server Error"));
     }
 }
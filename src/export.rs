@@ -0,0 +1,210 @@
+use chrono::{DateTime, Utc};
+
+use crate::database::{Balance, Transaction, TransactionType};
+
+/// Formats the full history of a wallet can be exported in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Ofx,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Ofx => "application/x-ofx",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ofx => "ofx",
+        }
+    }
+
+    /// Matches the media types of an `Accept` header.
+    pub fn from_accept(accept: &str) -> Option<Self> {
+        accept
+            .split(',')
+            .map(|media_type| media_type.split(';').next().unwrap_or_default().trim())
+            .find_map(|media_type| match media_type {
+                "text/csv" => Some(ExportFormat::Csv),
+                "application/x-ofx" | "application/ofx" => Some(ExportFormat::Ofx),
+                _ => None,
+            })
+    }
+}
+
+/// Renders a wallet history, fed page by page from the newest transaction.
+pub struct Exporter {
+    format: ExportFormat,
+    id: u32,
+    balance: Balance,
+    // (date, position among the transactions at that date) of the last row,
+    // used to build unique OFX transaction ids
+    last: (i64, usize),
+}
+
+impl Exporter {
+    pub fn new(format: ExportFormat, id: u32, balance: Balance) -> Self {
+        Self {
+            format,
+            id,
+            balance,
+            last: (i64::MIN, 0),
+        }
+    }
+
+    pub fn header(&self) -> String {
+        match self.format {
+            ExportFormat::Csv => "realizada_em,tipo,valor,descricao\n".to_string(),
+            // the history is walked from the newest transaction, so the
+            // oldest date is unknown when the transaction list starts
+            ExportFormat::Ofx => format!(
+                concat!(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                    "<?OFX OFXHEADER=\"200\" VERSION=\"220\" SECURITY=\"NONE\" OLDFILEUID=\"NONE\" NEWFILEUID=\"NONE\"?>\n",
+                    "<OFX>\n",
+                    "<BANKMSGSRSV1><STMTTRNRS>\n",
+                    "<TRNUID>0</TRNUID>\n",
+                    "<STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>\n",
+                    "<STMTRS>\n",
+                    "<CURDEF>BRL</CURDEF>\n",
+                    "<BANKACCTFROM><BANKID>rinha</BANKID><ACCTID>{}</ACCTID><ACCTTYPE>CHECKING</ACCTTYPE></BANKACCTFROM>\n",
+                    "<BANKTRANLIST>\n",
+                    "<DTSTART>{}</DTSTART><DTEND>{}</DTEND>\n",
+                ),
+                self.id,
+                ofx_date(DateTime::UNIX_EPOCH),
+                ofx_date(self.statement_date()),
+            ),
+        }
+    }
+
+    pub fn rows(&mut self, transactions: &[Transaction]) -> String {
+        let mut rows = String::new();
+        for transaction in transactions {
+            match self.format {
+                ExportFormat::Csv => {
+                    rows.push_str(&format!(
+                        "{},{},{},{}\n",
+                        transaction.date.to_rfc3339(),
+                        transaction.transaction_type.as_str(),
+                        transaction.value,
+                        csv_field(&transaction.description)
+                    ));
+                }
+                ExportFormat::Ofx => {
+                    let date = transaction.date.timestamp_micros();
+                    self.last = match self.last.0 == date {
+                        true => (date, self.last.1 + 1),
+                        false => (date, 0),
+                    };
+                    let value = transaction.value as i64;
+                    let (transaction_type, amount) = match transaction.transaction_type {
+                        TransactionType::Deposit => ("CREDIT", value),
+                        TransactionType::Withdraw => ("DEBIT", -value),
+                    };
+                    rows.push_str(&format!(
+                        "<STMTTRN><TRNTYPE>{}</TRNTYPE><DTPOSTED>{}</DTPOSTED><TRNAMT>{}</TRNAMT><FITID>{}-{}-{}</FITID><MEMO>{}</MEMO></STMTTRN>\n",
+                        transaction_type,
+                        ofx_date(transaction.date),
+                        ofx_amount(amount),
+                        self.id,
+                        date,
+                        self.last.1,
+                        xml_text(&transaction.description)
+                    ));
+                }
+            }
+        }
+        rows
+    }
+
+    pub fn footer(&self) -> String {
+        match self.format {
+            ExportFormat::Csv => String::new(),
+            ExportFormat::Ofx => format!(
+                concat!(
+                    "</BANKTRANLIST>\n",
+                    "<LEDGERBAL><BALAMT>{}</BALAMT><DTASOF>{}</DTASOF></LEDGERBAL>\n",
+                    "</STMTRS>\n",
+                    "</STMTTRNRS></BANKMSGSRSV1>\n",
+                    "</OFX>\n",
+                ),
+                ofx_amount(self.balance.total as i64),
+                ofx_date(self.statement_date()),
+            ),
+        }
+    }
+
+    fn statement_date(&self) -> DateTime<Utc> {
+        self.balance.statement_date.unwrap_or_else(Utc::now)
+    }
+}
+
+/// Quotes the field when it contains a separator, a quote or a line break.
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+fn xml_text(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn ofx_date(date: DateTime<Utc>) -> String {
+    date.format("%Y%m%d%H%M%S%.3f[0:UTC]").to_string()
+}
+
+/// Values are stored in cents.
+fn ofx_amount(cents: i64) -> String {
+    let sign = if cents < 0 { "-" } else { "" };
+    format!("{sign}{}.{:02}", cents.abs() / 100, cents.abs() % 100)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_export_csv() {
+        let mut exporter = Exporter::new(ExportFormat::Csv, 1, Balance::new(1000));
+        let transaction = Transaction::new(150, TransactionType::Withdraw, "a, \"b\"".to_string());
+        let rows = exporter.rows(std::slice::from_ref(&transaction));
+        assert_eq!(exporter.header(), "realizada_em,tipo,valor,descricao\n");
+        assert_eq!(
+            rows,
+            format!("{},d,150,\"a, \"\"b\"\"\"\n", transaction.date.to_rfc3339())
+        );
+    }
+
+    #[test]
+    fn should_export_ofx() {
+        let balance = Balance {
+            total: -150,
+            ..Balance::new(1000)
+        };
+        let mut exporter = Exporter::new(ExportFormat::Ofx, 1, balance);
+        let transaction = Transaction::new(150, TransactionType::Withdraw, "a<b".to_string());
+        let rows = exporter.rows(&[transaction.clone(), transaction]);
+        assert!(rows.contains("<TRNTYPE>DEBIT</TRNTYPE>"));
+        assert!(rows.contains("<TRNAMT>-1.50</TRNAMT>"));
+        assert!(rows.contains("<MEMO>a&lt;b</MEMO>"));
+        assert!(rows.contains("-0</FITID>") && rows.contains("-1</FITID>"));
+        assert!(exporter.footer().contains("<BALAMT>-1.50</BALAMT>"));
+    }
+
+    #[test]
+    fn should_negotiate_format() {
+        let accept = "application/json;q=0.5, text/csv";
+        assert_eq!(ExportFormat::from_accept(accept), Some(ExportFormat::Csv));
+        assert_eq!(ExportFormat::from_accept("application/json"), None);
+    }
+}
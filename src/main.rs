@@ -1,67 +1,151 @@
-use axum::Router;
-use redis::Client;
-use sqlx::postgres::PgPoolOptions;
-use tokio::net::TcpListener;
+use std::future::IntoFuture;
+use std::time::Duration;
 
-use database::CachedDatabase;
-use database::PostgresDatabase;
-use router::client_router;
+use axum::{middleware, routing::get, Router};
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use tokio::net::TcpListener;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
 
-use crate::database::MongoDatabase;
+use config::{Cli, Command, Config, DatabaseType};
+use database::TransactionRepository;
+use router::{client_router, health_router};
+use shutdown::InFlight;
 
+mod commands;
+mod config;
 mod database;
 mod error;
+mod export;
+mod metrics;
 mod router;
+mod shutdown;
+mod telemetry;
 mod validator;
 
 type Database = database::Database;
 
+/// How often expired holds are released.
+const HOLD_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenvy::dotenv().ok();
-    let database_type = std::env::var("DATABASE_TYPE").unwrap_or("postgres".to_string());
+    let mut cli = Cli::parse();
+    let command = cli.command.take().unwrap_or(Command::Serve);
+    let config = match Config::load(cli) {
+        Ok(config) => config,
+        Err(e) => Cli::command().error(ErrorKind::ValueValidation, e).exit(),
+    };
+    let tracer_provider = telemetry::init()?;
+
+    let result = match command {
+        Command::Serve => serve(config).await,
+        Command::Migrate => commands::migrate(&config).await,
+        Command::Seed { file } => commands::seed(&config, &file).await,
+        Command::Check => commands::check(&config).await,
+    };
 
-    let database = match database_type.as_str() {
-        "postgres" => {
-            let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-            let database_connections = std::env::var("DATABASE_CONNECTIONS")
-                .unwrap_or("10".to_string())
-                .parse()
-                .expect("DATABASE_CONNECTIONS must be a number");
+    if let Some(tracer_provider) = tracer_provider {
+        tracer_provider.shutdown()?;
+    }
+    if let Err(e) = result {
+        tracing::error!("{e}");
+        std::process::exit(1);
+    }
+    Ok(())
+}
 
-            let pool = PgPoolOptions::new()
-                .max_connections(database_connections)
-                .connect(&database_url)
-                .await?;
-            let postgres_db = PostgresDatabase::new(pool);
+async fn serve(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    // nothing else shares the sqlite file, so it is kept migrated without a separate step
+    if config.database.database_type() == DatabaseType::Sqlite {
+        commands::migrate(&config).await?;
+    }
+    let database = commands::connect(&config).await?;
+    if let Some(file) = &config.seed_file {
+        commands::seed_database(&database, file).await?;
+    }
 
-            let redis_url = std::env::var("REDIS_URL").ok();
-            match redis_url {
-                Some(redis_url) => {
-                    let redis = Client::open(redis_url)?;
-                    let cached_database = CachedDatabase::new(redis, postgres_db);
-                    Database::Cached(cached_database)
-                }
-                None => Database::Postgres(postgres_db),
+    let (start_shutdown, shutdown) = shutdown::channel();
+
+    // holds are checked on capture, releasing them only frees the limit they reserve
+    let sweeper = database.clone();
+    let stop_sweeper = shutdown.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(HOLD_SWEEP_INTERVAL);
+        let stop = stop_sweeper.wait();
+        tokio::pin!(stop);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                () = &mut stop => break,
+            }
+            if let Err(e) = sweeper.expire_holds().await {
+                tracing::error!("Failed to expire holds: {e}");
             }
         }
-        "mongo" => {
-            let database_url = std::env::var("MONGO_URL").expect("MONGO_URL must be set");
-            let client = mongodb::Client::with_uri_str(&database_url).await?;
-            let mongo = MongoDatabase::new(client).await;
-            Database::Mongo(mongo)
-        }
-        _ => panic!("DATABASE_TYPE must be a postgres or mongo"),
-    };
+    });
 
-    let clients = client_router();
+    let clients = client_router(config.hold_ttl, config.account_events, shutdown.clone());
+    let in_flight = InFlight::default();
     let app = Router::new()
         .nest("/clientes", clients)
-        .with_state(database);
+        .route_layer(middleware::from_fn_with_state(
+            in_flight.clone(),
+            shutdown::track,
+        ))
+        .route_layer(middleware::from_fn(metrics::track))
+        .route_layer(telemetry::trace_layer())
+        .route("/metrics", get(metrics::get_metrics))
+        .nest("/health", health_router())
+        .layer(PropagateRequestIdLayer::new(telemetry::REQUEST_ID))
+        .layer(SetRequestIdLayer::new(
+            telemetry::REQUEST_ID,
+            MakeRequestUuid,
+        ))
+        .with_state(database.clone());
 
-    let port = std::env::var("PORT").unwrap_or("9999".to_string());
+    let listener = TcpListener::bind(config.bind).await?;
+    tracing::info!(
+        address = %config.bind,
+        database_type = %config.database.database_type(),
+        "Listening"
+    );
+    let server = axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.wait())
+        .into_future();
+    tokio::pin!(server);
+
+    let drained = tokio::select! {
+        result = &mut server => {
+            result?;
+            true
+        }
+        () = shutdown::terminate() => {
+            let timeout = config.shutdown_timeout;
+            tracing::info!(in_flight = in_flight.len(), "Shutting down, draining the requests");
+            start_shutdown.send_replace(true);
+            match tokio::time::timeout(timeout, &mut server).await {
+                Ok(result) => {
+                    result?;
+                    true
+                }
+                Err(_) => {
+                    tracing::warn!(
+                        in_flight = in_flight.len(),
+                        "Requests did not finish in {timeout:?}, interrupting them"
+                    );
+                    in_flight.report_interrupted();
+                    false
+                }
+            }
+        }
+    };
 
-    let listener = TcpListener::bind(format!("0.0.0.0:{port}")).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    // interrupted requests still hold their connections, the database rolls back
+    // their transactions once the process exits and drops them
+    if drained {
+        database.close().await;
+        tracing::info!("Database connections closed");
+    }
     Ok(())
 }
@@ -0,0 +1,127 @@
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    register_histogram_vec, register_int_counter, register_int_counter_vec, Encoder, HistogramVec,
+    IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, TextEncoder,
+};
+use sqlx::Pool;
+
+/// Latency of the handled requests, its `_count` is the request count.
+pub static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "http_request_duration_seconds",
+        "Latency of the HTTP requests by method, route and status",
+        &["method", "route", "status"],
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]
+    )
+    .expect("http_request_duration_seconds is registered once")
+});
+
+pub static LIMIT_REJECTIONS: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "transactions_limit_exceeded_total",
+        "Transactions rejected because they would exceed the client limit"
+    )
+    .expect("transactions_limit_exceeded_total is registered once")
+});
+
+pub static CACHE_LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "cache_lookups_total",
+        "Redis cache lookups by cached entry and result",
+        &["entry", "result"]
+    )
+    .expect("cache_lookups_total is registered once")
+});
+
+pub static CACHE_OUT_OF_SYNC: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!(
+        "cache_out_of_sync_total",
+        "Cached balances that diverged from the database or failed to update after a write"
+    )
+    .expect("cache_out_of_sync_total is registered once")
+});
+
+/// Samples the connections of a sqlx pool when the metrics are scraped.
+struct PoolCollector<DB: sqlx::Database> {
+    pool: Pool<DB>,
+    connections: IntGaugeVec,
+    max_connections: IntGauge,
+}
+
+impl<DB: sqlx::Database> Collector for PoolCollector<DB> {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut desc = self.connections.desc();
+        desc.extend(self.max_connections.desc());
+        desc
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let size = self.pool.size() as i64;
+        let idle = self.pool.num_idle() as i64;
+        self.connections.with_label_values(&["idle"]).set(idle);
+        self.connections
+            .with_label_values(&["in_use"])
+            .set(size - idle);
+        self.max_connections
+            .set(self.pool.options().get_max_connections() as i64);
+
+        let mut metrics = self.connections.collect();
+        metrics.extend(self.max_connections.collect());
+        metrics
+    }
+}
+
+pub fn register_pool<DB: sqlx::Database>(pool: Pool<DB>) -> prometheus::Result<()> {
+    let collector = PoolCollector {
+        pool,
+        connections: IntGaugeVec::new(
+            Opts::new("db_pool_connections", "Open database connections by state"),
+            &["state"],
+        )?,
+        max_connections: IntGauge::new(
+            "db_pool_max_connections",
+            "Maximum connections of the database pool",
+        )?,
+    };
+    prometheus::register(Box::new(collector))
+}
+
+/// Records the latency of the request, must be added as a route layer so the
+/// route is already matched.
+pub async fn track(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_default();
+    let method = request.method().to_string();
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .observe(start.elapsed().as_secs_f64());
+    response
+}
+
+pub async fn get_metrics() -> Response {
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    match encoder.encode(&prometheus::gather(), &mut body) {
+        Ok(()) => ([(header::CONTENT_TYPE, encoder.format_type())], body).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to encode metrics: {e}");
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
@@ -1,14 +1,29 @@
 use axum::{
-    extract::{Path, State},
-    routing::{get, post},
-    Json, Router,
+    body::Body,
+    extract::{rejection::QueryRejection, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{delete, get, post},
+    Extension, Json, Router,
 };
+use chrono::{DateTime, Duration, Utc};
+use futures_util::{stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
 use validator::Validate;
 
 use crate::{
-    database::{Balance, Statement, Transaction, TransactionRepository, TransactionType},
+    database::{
+        Account, AccountEvent, Balance, BatchMode, BatchOutcome, HistoryCursor, HistoryPage,
+        HistoryQuery, Hold, Transaction, TransactionRepository, TransactionType, Transfer,
+    },
     error::ServerError,
+    export::{ExportFormat, Exporter},
+    metrics,
+    shutdown::Shutdown,
     validator::ValidatedJson,
     Database,
 };
@@ -16,6 +31,7 @@ use crate::{
 #[derive(Debug, Serialize, Deserialize, Validate)]
 pub(crate) struct NewTransaction {
     #[serde(rename = "valor")]
+    #[validate(range(min = 1, max = 2147483647))]
     value: u32,
     #[serde(rename = "tipo")]
     transaction_type: TransactionType,
@@ -34,12 +50,77 @@ impl From<NewTransaction> for Transaction {
     }
 }
 
+#[derive(Debug, Deserialize, Validate)]
+pub(crate) struct NewBatch {
+    #[serde(rename = "modo", default)]
+    mode: BatchMode,
+    #[serde(rename = "transacoes")]
+    #[validate(length(min = 1, max = 1000))]
+    #[validate]
+    transactions: Vec<NewTransaction>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub(crate) struct NewTransfer {
+    #[serde(rename = "valor")]
+    #[validate(range(min = 1, max = 2147483647))]
+    value: u32,
+    #[serde(rename = "destinatario")]
+    to: u32,
+    #[serde(rename = "descricao")]
+    #[validate(length(min = 1, max = 10))]
+    description: String,
+}
+
+impl From<NewTransfer> for Transfer {
+    fn from(transfer: NewTransfer) -> Self {
+        Self::new(transfer.to, transfer.value, transfer.description)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub(crate) struct NewHold {
+    #[serde(rename = "valor")]
+    #[validate(range(min = 1, max = 2147483647))]
+    value: u32,
+    #[serde(rename = "descricao")]
+    #[validate(length(min = 1, max = 10))]
+    description: String,
+}
+
+/// Captures the whole hold when no value is given.
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub(crate) struct HoldCapture {
+    #[serde(rename = "valor", default)]
+    #[validate(range(min = 1, max = 2147483647))]
+    value: Option<u32>,
+}
+
+/// How long a hold reserves the limit before it is released.
+#[derive(Debug, Clone, Copy)]
+struct HoldTtl(Duration);
+
+/// Whether the account activity is streamed, postgres only announcing it when it is.
+#[derive(Debug, Clone, Copy)]
+struct AccountEvents(bool);
+
+/// Body of both account creation and limit updates.
+#[derive(Debug, Serialize, Deserialize, Validate)]
+pub(crate) struct AccountLimit {
+    #[serde(rename = "limite")]
+    #[validate(range(max = 2147483647))]
+    limit: u32,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct NewTransactionResponse {
     #[serde(rename = "limite")]
     limit: u32,
     #[serde(rename = "saldo")]
     balance: i32,
+    /// Id of the created transaction, absent for transfers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
 }
 
 impl From<Balance> for NewTransactionResponse {
@@ -47,32 +128,574 @@ impl From<Balance> for NewTransactionResponse {
         Self {
             limit: balance.limit,
             balance: balance.total,
+            id: None,
+        }
+    }
+}
+
+impl From<(Balance, Transaction)> for NewTransactionResponse {
+    fn from((balance, transaction): (Balance, Transaction)) -> Self {
+        Self {
+            id: transaction.id,
+            ..balance.into()
         }
     }
 }
 
+/// A balance along with the transaction that changed it.
+#[derive(Debug, Serialize)]
+struct TransactionResponse {
+    #[serde(rename = "limite")]
+    limit: u32,
+    #[serde(rename = "saldo")]
+    balance: i32,
+    #[serde(rename = "transacao")]
+    transaction: Transaction,
+}
+
+impl From<(Balance, Transaction)> for TransactionResponse {
+    fn from((balance, transaction): (Balance, Transaction)) -> Self {
+        Self {
+            limit: balance.limit,
+            balance: balance.total,
+            transaction,
+        }
+    }
+}
+
+/// Data of the `transacao` events streamed by `/eventos`.
+#[derive(Debug, Serialize)]
+struct EventResponse {
+    #[serde(rename = "limite")]
+    limit: u32,
+    #[serde(rename = "saldo")]
+    balance: i32,
+    #[serde(rename = "reservado", skip_serializing_if = "Option::is_none")]
+    held: Option<u32>,
+    #[serde(rename = "transacao")]
+    transaction: Transaction,
+}
+
+impl From<AccountEvent> for EventResponse {
+    fn from(event: AccountEvent) -> Self {
+        Self {
+            limit: event.balance.limit,
+            balance: event.balance.total,
+            held: event.balance.held,
+            transaction: event.transaction,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BatchError {
+    code: &'static str,
+    message: String,
+}
+
+/// Either the applied transaction or why it was skipped.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum BatchResult {
+    Applied {
+        #[serde(rename = "transacao")]
+        transaction: Transaction,
+    },
+    Skipped {
+        #[serde(rename = "erro")]
+        error: BatchError,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct BatchResponse {
+    #[serde(rename = "limite")]
+    limit: u32,
+    #[serde(rename = "saldo")]
+    balance: i32,
+    #[serde(rename = "resultados")]
+    results: Vec<BatchResult>,
+}
+
+impl From<BatchOutcome> for BatchResponse {
+    fn from(outcome: BatchOutcome) -> Self {
+        Self {
+            limit: outcome.balance.limit,
+            balance: outcome.balance.total,
+            results: outcome
+                .results
+                .into_iter()
+                .map(|result| match result {
+                    Ok(transaction) => BatchResult::Applied { transaction },
+                    Err(e) => BatchResult::Skipped {
+                        error: BatchError {
+                            code: e.code(),
+                            message: e.to_string(),
+                        },
+                    },
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct HoldResponse {
+    #[serde(rename = "limite")]
+    limit: u32,
+    #[serde(rename = "saldo")]
+    balance: i32,
+    #[serde(rename = "reservado")]
+    held: u32,
+    #[serde(rename = "disponivel")]
+    available: i64,
+    #[serde(rename = "reserva")]
+    hold: Hold,
+}
+
+impl From<(Balance, Hold)> for HoldResponse {
+    fn from((balance, hold): (Balance, Hold)) -> Self {
+        Self {
+            limit: balance.limit,
+            balance: balance.total,
+            held: balance.held.unwrap_or_default(),
+            available: balance.available(),
+            hold,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum StatementFormat {
+    Json,
+    Csv,
+    Ofx,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct StatementParams {
+    format: Option<StatementFormat>,
+}
+
+/// Number of transactions fetched at a time while exporting the history.
+const EXPORT_PAGE_SIZE: u32 = 100;
+
+/// Where the export stream is between chunks.
+enum ExportStep {
+    Page(HistoryPage),
+    Next(HistoryCursor),
+    Footer,
+    Done,
+}
+
 async fn get_statement(
     Path(id): Path<u32>,
     database: State<Database>,
-) -> Result<Json<Statement>, ServerError> {
+    headers: HeaderMap,
+    params: Result<Query<StatementParams>, QueryRejection>,
+) -> Result<Response, ServerError> {
+    let Query(params) = params?;
+    // an explicit format wins over the Accept header
+    let format = match params.format {
+        Some(StatementFormat::Json) => None,
+        Some(StatementFormat::Csv) => Some(ExportFormat::Csv),
+        Some(StatementFormat::Ofx) => Some(ExportFormat::Ofx),
+        None => headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .and_then(ExportFormat::from_accept),
+    };
     let stmt = database.get_statement(&id).await?;
-    Ok(Json(stmt))
+    match format {
+        Some(format) => export_history(id, database.0, format, stmt.balance).await,
+        None => Ok(Json(stmt).into_response()),
+    }
+}
+
+/// Streams the whole wallet history, newest first, fetching it page by page.
+async fn export_history(
+    id: u32,
+    database: Database,
+    format: ExportFormat,
+    balance: Balance,
+) -> Result<Response, ServerError> {
+    let query = HistoryQuery {
+        from: None,
+        to: None,
+        transaction_type: None,
+        description: None,
+        cursor: None,
+        limit: EXPORT_PAGE_SIZE,
+    };
+    // fetch the first page up front so a failure is still reported with a status code
+    let first = database.get_history(id, query.clone()).await?;
+    let exporter = Exporter::new(format, id, balance);
+    let header = exporter.header();
+    let pages = stream::unfold(
+        (ExportStep::Page(first), exporter),
+        move |(step, mut exporter)| {
+            let database = database.clone();
+            let query = query.clone();
+            async move {
+                let page = match step {
+                    ExportStep::Page(page) => page,
+                    ExportStep::Next(cursor) => {
+                        let query = HistoryQuery {
+                            cursor: Some(cursor),
+                            ..query
+                        };
+                        match database.get_history(id, query).await {
+                            Ok(page) => page,
+                            Err(e) => return Some((Err(e), (ExportStep::Done, exporter))),
+                        }
+                    }
+                    ExportStep::Footer => {
+                        let footer = exporter.footer();
+                        return Some((Ok(footer), (ExportStep::Done, exporter)));
+                    }
+                    ExportStep::Done => return None,
+                };
+                let rows = exporter.rows(&page.transactions);
+                let next = match page.next_cursor {
+                    Some(cursor) => ExportStep::Next(cursor),
+                    None => ExportStep::Footer,
+                };
+                Some((Ok(rows), (next, exporter)))
+            }
+        },
+    );
+    let body = stream::once(async move { Ok::<_, ServerError>(header) }).chain(pages);
+    let disposition = format!(
+        "attachment; filename=\"extrato-{id}.{}\"",
+        format.extension()
+    );
+    let headers = [
+        (header::CONTENT_TYPE, format.content_type().to_string()),
+        (header::CONTENT_DISPOSITION, disposition),
+    ];
+    Ok((headers, Body::from_stream(body)).into_response())
+}
+
+/// Default number of transactions in a history page.
+const HISTORY_PAGE_SIZE: u32 = 10;
+
+#[derive(Debug, Deserialize, Validate)]
+pub(crate) struct HistoryParams {
+    cursor: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    #[serde(rename = "tipo")]
+    transaction_type: Option<TransactionType>,
+    #[serde(rename = "descricao")]
+    #[validate(length(min = 1, max = 10))]
+    description: Option<String>,
+    #[validate(range(min = 1, max = 100))]
+    limit: Option<u32>,
+}
+
+impl TryFrom<HistoryParams> for HistoryQuery {
+    type Error = ServerError;
+
+    fn try_from(params: HistoryParams) -> Result<Self, Self::Error> {
+        Ok(Self {
+            from: params.from,
+            to: params.to,
+            transaction_type: params.transaction_type,
+            description: params.description,
+            cursor: params
+                .cursor
+                .as_deref()
+                .map(HistoryCursor::decode)
+                .transpose()?,
+            limit: params.limit.unwrap_or(HISTORY_PAGE_SIZE),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct HistoryResponse {
+    #[serde(rename = "transacoes")]
+    transactions: Vec<Transaction>,
+    #[serde(rename = "proximo_cursor")]
+    next_cursor: Option<String>,
+}
+
+impl From<HistoryPage> for HistoryResponse {
+    fn from(page: HistoryPage) -> Self {
+        Self {
+            transactions: page.transactions,
+            next_cursor: page.next_cursor.map(|cursor| cursor.encode()),
+        }
+    }
+}
+
+async fn get_history(
+    Path(id): Path<u32>,
+    State(database): State<Database>,
+    params: Result<Query<HistoryParams>, QueryRejection>,
+) -> Result<Json<HistoryResponse>, ServerError> {
+    let Query(params) = params?;
+    params.validate()?;
+    let page = database.get_history(id, params.try_into()?).await?;
+    Ok(Json(page.into()))
+}
+
+/// Streams the transactions of the wallet as they are applied, by any instance.
+async fn get_events(
+    Path(id): Path<u32>,
+    State(database): State<Database>,
+    Extension(shutdown): Extension<Shutdown>,
+    Extension(AccountEvents(enabled)): Extension<AccountEvents>,
+) -> Result<impl IntoResponse, ServerError> {
+    if !enabled {
+        return Err(ServerError::Unsupported(
+            "account events are disabled, see ACCOUNT_EVENTS",
+        ));
+    }
+    // subscribe first, so nothing applied after the wallet is found is missed
+    let events = database.subscribe();
+    database.get_account(id).await?;
+    let stream = stream::unfold(events, move |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) if event.wallet_id == id => {
+                    let event = Event::default()
+                        .event("transacao")
+                        .json_data(EventResponse::from(event));
+                    return Some((event, events));
+                }
+                Ok(_) => continue,
+                // a slow client misses the oldest events rather than holding the others
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    // end the stream on shutdown, so it does not hold the server until the deadline
+    let stream = stream.take_until(shutdown.wait());
+    let sse: Sse<_> = Sse::new(stream).keep_alive(KeepAlive::default());
+    // nginx would otherwise buffer the stream
+    Ok(([("x-accel-buffering", "no")], sse))
+}
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<&str>, ServerError> {
+    let Some(key) = headers.get(IDEMPOTENCY_KEY) else {
+        return Ok(None);
+    };
+    match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= 255 => Ok(Some(key)),
+        _ => Err(ServerError::InvalidIdempotencyKey),
+    }
 }
 
 async fn post_transaction(
     Path(id): Path<u32>,
     State(database): State<Database>,
+    headers: HeaderMap,
     ValidatedJson(transaction): ValidatedJson<NewTransaction>,
 ) -> Result<Json<NewTransactionResponse>, ServerError> {
     transaction.validate()?;
-    let balance = database.add_transaction(id, transaction.into()).await?;
+    let applied = match idempotency_key(&headers)? {
+        Some(key) => {
+            database
+                .add_idempotent_transaction(id, transaction.into(), key)
+                .await?
+        }
+        None => database.add_transaction(id, transaction.into()).await?,
+    };
+    Ok(Json(applied.into()))
+}
+
+async fn post_batch(
+    Path(id): Path<u32>,
+    State(database): State<Database>,
+    ValidatedJson(batch): ValidatedJson<NewBatch>,
+) -> Result<Json<BatchResponse>, ServerError> {
+    let transactions = batch.transactions.into_iter().map(Into::into).collect();
+    let outcome = database
+        .add_transactions(id, transactions, batch.mode)
+        .await?;
+    let skipped = outcome
+        .results
+        .iter()
+        .filter(|result| matches!(result, Err(ServerError::TransactionWouldExceedLimit)))
+        .count();
+    metrics::LIMIT_REJECTIONS.inc_by(skipped as u64);
+    Ok(Json(outcome.into()))
+}
+
+async fn post_transfer(
+    Path(id): Path<u32>,
+    State(database): State<Database>,
+    ValidatedJson(transfer): ValidatedJson<NewTransfer>,
+) -> Result<Json<NewTransactionResponse>, ServerError> {
+    if transfer.to == id {
+        return Err(ServerError::SelfTransfer);
+    }
+    let balance = database.transfer(id, transfer.into()).await?;
     Ok(Json(balance.into()))
 }
 
-pub fn client_router() -> Router<Database> {
+async fn get_transaction(
+    Path((id, transaction_id)): Path<(u32, String)>,
+    State(database): State<Database>,
+) -> Result<Json<Transaction>, ServerError> {
+    let transaction = database.get_transaction(id, &transaction_id).await?;
+    Ok(Json(transaction))
+}
+
+async fn post_reversal(
+    Path((id, transaction_id)): Path<(u32, String)>,
+    State(database): State<Database>,
+) -> Result<Json<TransactionResponse>, ServerError> {
+    let reversal = database.reverse_transaction(id, &transaction_id).await?;
+    Ok(Json(reversal.into()))
+}
+
+async fn post_hold(
+    Path(id): Path<u32>,
+    State(database): State<Database>,
+    Extension(HoldTtl(ttl)): Extension<HoldTtl>,
+    ValidatedJson(hold): ValidatedJson<NewHold>,
+) -> Result<(StatusCode, Json<HoldResponse>), ServerError> {
+    let hold = Hold::new(hold.value, hold.description, ttl);
+    let placed = database.place_hold(id, hold).await?;
+    Ok((StatusCode::CREATED, Json(placed.into())))
+}
+
+async fn post_capture(
+    Path((id, hold_id)): Path<(u32, String)>,
+    State(database): State<Database>,
+    ValidatedJson(capture): ValidatedJson<HoldCapture>,
+) -> Result<Json<TransactionResponse>, ServerError> {
+    let captured = database.capture_hold(id, &hold_id, capture.value).await?;
+    Ok(Json(captured.into()))
+}
+
+async fn release_hold(
+    Path((id, hold_id)): Path<(u32, String)>,
+    State(database): State<Database>,
+) -> Result<Json<HoldResponse>, ServerError> {
+    let released = database.release_hold(id, &hold_id).await?;
+    Ok(Json(released.into()))
+}
+
+async fn post_account(
+    State(database): State<Database>,
+    ValidatedJson(account): ValidatedJson<AccountLimit>,
+) -> Result<(StatusCode, Json<Account>), ServerError> {
+    let account = database.create_account(account.limit).await?;
+    Ok((StatusCode::CREATED, Json(account)))
+}
+
+async fn get_account(
+    Path(id): Path<u32>,
+    State(database): State<Database>,
+) -> Result<Json<Account>, ServerError> {
+    let account = database.get_account(id).await?;
+    Ok(Json(account))
+}
+
+async fn patch_account(
+    Path(id): Path<u32>,
+    State(database): State<Database>,
+    ValidatedJson(account): ValidatedJson<AccountLimit>,
+) -> Result<Json<Account>, ServerError> {
+    let account = database.update_limit(id, account.limit).await?;
+    Ok(Json(account))
+}
+
+async fn close_account(
+    Path(id): Path<u32>,
+    State(database): State<Database>,
+) -> Result<Json<Account>, ServerError> {
+    let account = database.close_account(id).await?;
+    Ok(Json(account))
+}
+
+/// How long readiness waits for the database before reporting it unavailable.
+const READINESS_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+#[derive(Debug, Serialize)]
+struct HealthResponse {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+}
+
+impl HealthResponse {
+    fn up() -> Json<Self> {
+        Json(Self {
+            status: "up",
+            error: None,
+        })
+    }
+
+    fn down(error: &'static str) -> (StatusCode, Json<Self>) {
+        let body = Self {
+            status: "down",
+            error: Some(error),
+        };
+        (StatusCode::SERVICE_UNAVAILABLE, Json(body))
+    }
+}
+
+/// The process is running, whether or not its dependencies are.
+async fn get_live() -> Json<HealthResponse> {
+    HealthResponse::up()
+}
+
+/// The database, and the cache when there is one, can serve requests.
+async fn get_ready(State(database): State<Database>) -> Response {
+    match tokio::time::timeout(READINESS_TIMEOUT, database.ping()).await {
+        Ok(Ok(())) => HealthResponse::up().into_response(),
+        Ok(Err(e)) => {
+            tracing::warn!("Database is not ready: {e}");
+            HealthResponse::down(e.code()).into_response()
+        }
+        Err(_) => {
+            tracing::warn!("Database did not answer in {READINESS_TIMEOUT:?}");
+            HealthResponse::down("timeout").into_response()
+        }
+    }
+}
+
+pub fn health_router() -> Router<Database> {
+    Router::new()
+        .route("/live", get(get_live))
+        .route("/ready", get(get_ready))
+}
+
+pub fn client_router(
+    hold_ttl: Duration,
+    account_events: bool,
+    shutdown: Shutdown,
+) -> Router<Database> {
     Router::new()
+        .route("/", post(post_account))
+        .route(
+            "/:id",
+            get(get_account).patch(patch_account).delete(close_account),
+        )
         .route("/:id/extrato", get(get_statement))
-        .route("/:id/transacoes", post(post_transaction))
+        .route("/:id/eventos", get(get_events))
+        .route("/:id/transacoes", get(get_history).post(post_transaction))
+        .route("/:id/transacoes/lote", post(post_batch))
+        .route("/:id/transacoes/:transaction_id", get(get_transaction))
+        .route(
+            "/:id/transacoes/:transaction_id/estorno",
+            post(post_reversal),
+        )
+        .route("/:id/transferencias", post(post_transfer))
+        .route("/:id/reservas", post(post_hold))
+        .route("/:id/reservas/:hold_id", delete(release_hold))
+        .route("/:id/reservas/:hold_id/captura", post(post_capture))
+        .layer(Extension(HoldTtl(hold_ttl)))
+        .layer(Extension(AccountEvents(account_events)))
+        .layer(Extension(shutdown))
 }
 
 #[cfg(test)]
@@ -94,6 +717,99 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_transfer() {
+        let transfer = r#"
+            {
+                "valor": 1000,
+                "destinatario": 2,
+                "descricao" : "descricao"
+            }
+        "#;
+
+        let transfer: Result<NewTransfer> = serde_json::from_str(transfer);
+        assert!(transfer.unwrap().validate().is_ok());
+
+        for value in [0, 4294967295u32] {
+            let transfer = format!(r#"{{"valor": {value}, "destinatario": 2, "descricao": "d"}}"#);
+            let transfer: NewTransfer = serde_json::from_str(&transfer).unwrap();
+            assert!(transfer.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_account_limit() {
+        let account: AccountLimit = serde_json::from_str(r#"{"limite": 1000}"#).unwrap();
+        assert!(account.validate().is_ok());
+
+        let account: AccountLimit = serde_json::from_str(r#"{"limite": 3000000000}"#).unwrap();
+        assert!(account.validate().is_err());
+    }
+
+    #[test]
+    fn test_history_params() {
+        let uri = "/?tipo=d&from=2024-03-01T00:00:00Z&limit=20"
+            .parse()
+            .unwrap();
+        let Query(params) = Query::<HistoryParams>::try_from_uri(&uri).unwrap();
+        let query = HistoryQuery::try_from(params).unwrap();
+        assert!(matches!(
+            query.transaction_type,
+            Some(TransactionType::Withdraw)
+        ));
+        assert_eq!(query.limit, 20);
+
+        let uri = "/?cursor=invalid".parse().unwrap();
+        let Query(params) = Query::<HistoryParams>::try_from_uri(&uri).unwrap();
+        let query = HistoryQuery::try_from(params);
+        assert!(matches!(query, Err(ServerError::InvalidCursor)));
+    }
+
+    #[test]
+    fn test_hold_capture() {
+        let capture: HoldCapture = serde_json::from_str("{}").unwrap();
+        assert_eq!(capture.value, None);
+        let capture: HoldCapture = serde_json::from_str(r#"{"valor": 500}"#).unwrap();
+        assert_eq!(capture.value, Some(500));
+        assert!(capture.validate().is_ok());
+
+        for value in [0, 4294967295u32] {
+            let capture = format!(r#"{{"valor": {value}}}"#);
+            let capture: HoldCapture = serde_json::from_str(&capture).unwrap();
+            assert!(capture.validate().is_err());
+
+            let hold = format!(r#"{{"valor": {value}, "descricao": "d"}}"#);
+            let hold: NewHold = serde_json::from_str(&hold).unwrap();
+            assert!(hold.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_batch() {
+        let batch: NewBatch = serde_json::from_str(
+            r#"{"transacoes": [{"valor": 1000, "tipo": "c", "descricao": "descricao"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(batch.mode, BatchMode::AllOrNothing);
+        assert!(batch.validate().is_ok());
+
+        let batch: NewBatch = serde_json::from_str(
+            r#"{"modo": "melhor_esforco", "transacoes": [{"valor": 1, "tipo": "d", "descricao": ""}]}"#,
+        )
+        .unwrap();
+        assert_eq!(batch.mode, BatchMode::BestEffort);
+        assert!(batch.validate().is_err());
+
+        let batch: NewBatch = serde_json::from_str(r#"{"transacoes": []}"#).unwrap();
+        assert!(batch.validate().is_err());
+
+        let batch: NewBatch = serde_json::from_str(
+            r#"{"transacoes": [{"valor": 4294967295, "tipo": "d", "descricao": "descricao"}]}"#,
+        )
+        .unwrap();
+        assert!(batch.validate().is_err());
+    }
+
     #[test]
     fn test_transaction() {
         let transaction = r#"
@@ -105,6 +821,13 @@ mod tests {
         "#;
 
         let transaction: Result<NewTransaction> = serde_json::from_str(transaction);
-        assert!(transaction.is_ok());
+        assert!(transaction.unwrap().validate().is_ok());
+
+        // a withdraw of 4294967295 would credit 1, one of 2147483648 would overflow
+        for value in [0, 2147483648, 4294967295u32] {
+            let transaction = format!(r#"{{"valor": {value}, "tipo": "d", "descricao": "d"}}"#);
+            let transaction: NewTransaction = serde_json::from_str(&transaction).unwrap();
+            assert!(transaction.validate().is_err());
+        }
     }
 }
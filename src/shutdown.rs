@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use tokio::sync::watch;
+
+use crate::telemetry::REQUEST_ID;
+
+/// Resolves once the shutdown started, so long-lived tasks and streams can stop.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    pub async fn wait(mut self) {
+        // the sender is only dropped when the process exits
+        let _ = self.0.wait_for(|&started| started).await;
+    }
+}
+
+pub fn channel() -> (watch::Sender<bool>, Shutdown) {
+    let (sender, receiver) = watch::channel(false);
+    (sender, Shutdown(receiver))
+}
+
+/// Waits for SIGINT or SIGTERM.
+pub async fn terminate() {
+    let interrupt = tokio::signal::ctrl_c();
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("SIGTERM handler can be installed")
+            .recv()
+            .await
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<Option<()>>();
+
+    tokio::select! {
+        _ = interrupt => tracing::info!("Received SIGINT"),
+        _ = terminate => tracing::info!("Received SIGTERM"),
+    }
+}
+
+struct Pending {
+    method: String,
+    route: String,
+    request_id: String,
+    started: Instant,
+}
+
+/// Requests being handled, to report the ones a shutdown interrupts.
+#[derive(Clone, Default)]
+pub struct InFlight {
+    next: Arc<AtomicU64>,
+    requests: Arc<Mutex<HashMap<u64, Pending>>>,
+}
+
+/// Removes the request when it finishes, or when its handler is dropped.
+struct InFlightGuard {
+    key: u64,
+    requests: Arc<Mutex<HashMap<u64, Pending>>>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.requests.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl InFlight {
+    fn start(&self, request: &Request) -> InFlightGuard {
+        let pending = Pending {
+            method: request.method().to_string(),
+            route: request
+                .extensions()
+                .get::<MatchedPath>()
+                .map(|path| path.as_str().to_owned())
+                .unwrap_or_default(),
+            request_id: request
+                .headers()
+                .get(REQUEST_ID)
+                .and_then(|id| id.to_str().ok())
+                .unwrap_or_default()
+                .to_owned(),
+            started: Instant::now(),
+        };
+        let key = self.next.fetch_add(1, Ordering::Relaxed);
+        self.requests.lock().unwrap().insert(key, pending);
+        InFlightGuard {
+            key,
+            requests: self.requests.clone(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+
+    /// Logs the requests still being handled, which are about to be dropped.
+    pub fn report_interrupted(&self) {
+        for pending in self.requests.lock().unwrap().values() {
+            tracing::warn!(
+                method = pending.method,
+                route = pending.route,
+                request_id = pending.request_id,
+                elapsed_ms = pending.started.elapsed().as_secs_f64() * 1000.0,
+                "Request interrupted by the shutdown"
+            );
+        }
+    }
+}
+
+/// Must be added as a route layer so the route is already matched.
+pub async fn track(State(in_flight): State<InFlight>, request: Request, next: Next) -> Response {
+    let _guard = in_flight.start(&request);
+    next.run(request).await
+}
@@ -0,0 +1,99 @@
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{HeaderName, Response},
+};
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+use tower_http::{
+    classify::{ServerErrorsAsFailures, SharedClassifier},
+    trace::{MakeSpan, OnResponse, TraceLayer},
+};
+use tracing::{field::Empty, Span};
+use tracing_subscriber::{
+    fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
+};
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+const SERVICE_NAME: &str = "rinha-de-backend";
+
+/// Logs spans and events as JSON lines to stdout, and exports the spans to an
+/// OTLP collector when OTEL_EXPORTER_OTLP_ENDPOINT is set.
+///
+/// The returned provider must be shut down to flush the pending spans.
+pub fn init() -> Result<Option<TracerProvider>, Box<dyn std::error::Error>> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let logs = tracing_subscriber::fmt::layer()
+        .json()
+        .with_span_events(FmtSpan::CLOSE);
+
+    let provider = match std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(_) => {
+            let exporter = SpanExporter::builder().with_tonic().build()?;
+            let service = std::env::var("OTEL_SERVICE_NAME").unwrap_or(SERVICE_NAME.to_string());
+            Some(
+                TracerProvider::builder()
+                    .with_batch_exporter(exporter, runtime::Tokio)
+                    .with_resource(Resource::new([KeyValue::new("service.name", service)]))
+                    .build(),
+            )
+        }
+        Err(_) => None,
+    };
+    let traces = provider
+        .as_ref()
+        .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer(SERVICE_NAME)));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(logs)
+        .with(traces)
+        .try_init()?;
+    Ok(provider)
+}
+
+/// Opens a span per request, tagged with the id set by the proxy or generated
+/// by `SetRequestIdLayer`.
+#[derive(Clone)]
+pub struct RequestSpan;
+
+impl<B> MakeSpan<B> for RequestSpan {
+    fn make_span(&mut self, request: &Request<B>) -> Span {
+        let route = request
+            .extensions()
+            .get::<MatchedPath>()
+            .map(MatchedPath::as_str)
+            .unwrap_or_default();
+        let request_id = request
+            .headers()
+            .get(REQUEST_ID)
+            .and_then(|id| id.to_str().ok())
+            .unwrap_or_default();
+        tracing::info_span!(
+            "request",
+            method = %request.method(),
+            route,
+            request_id,
+            status = Empty,
+            duration_ms = Empty,
+        )
+    }
+}
+
+#[derive(Clone)]
+pub struct RecordResponse;
+
+impl<B> OnResponse<B> for RecordResponse {
+    fn on_response(self, response: &Response<B>, latency: std::time::Duration, span: &Span) {
+        span.record("status", response.status().as_u16());
+        span.record("duration_ms", latency.as_secs_f64() * 1000.0);
+    }
+}
+
+pub fn trace_layer(
+) -> TraceLayer<SharedClassifier<ServerErrorsAsFailures>, RequestSpan, (), RecordResponse> {
+    TraceLayer::new_for_http()
+        .make_span_with(RequestSpan)
+        .on_request(())
+        .on_response(RecordResponse)
+}